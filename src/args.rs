@@ -3,7 +3,7 @@
 //! This module contains the Args struct and its implementation for parsing command line arguments.
 //!
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -12,10 +12,167 @@ pub struct Args {
     #[clap(short, long, default_value = "loopy.yaml")]
     pub config: Option<String>,
 
+    /// The profile from the configuration file to apply.
+    #[clap(short, long)]
+    pub profile: Option<String>,
+
     /// The action to perform.
     /// Can be either --install or --uninstall.
     #[clap(short, long)]
     pub action: Option<String>,
+
+    /// Continue the last interrupted install or uninstall, skipping the steps that completed.
+    #[clap(long)]
+    pub resume: bool,
+
+    /// Print the execution plan of the action without changing anything.
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// Upgrade charts and apply manifests even when their inputs haven't changed.
+    #[clap(long)]
+    pub force: bool,
+
+    /// Only use cached remote manifests and existing git checkouts, without any network access.
+    #[clap(long)]
+    pub offline: bool,
+
+    /// The command to run instead of an action.
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Commands that can be run instead of an install or uninstall action.
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Forward local ports to the Services and Pods defined in the configuration.
+    Forward {
+        /// The names of the port-forwards to start. Starts all when empty.
+        names: Vec<String>,
+    },
+
+    /// Create the directory layout and a starter configuration file.
+    Init {
+        /// A Helm repository to add, as <name>=<url>. Can be repeated.
+        #[clap(long = "repo")]
+        repos: Vec<String>,
+
+        /// A Helm chart to add, as <repo>/<chart>. Can be repeated.
+        #[clap(long = "chart")]
+        charts: Vec<String>,
+
+        /// Also write the bundled kind cluster config to config/capi/kind.
+        #[clap(long)]
+        kind: bool,
+
+        /// Overwrite an existing configuration file.
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Inspect the configuration.
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+
+    /// Save or restore the state of the configured charts and manifests.
+    Snapshot {
+        #[clap(subcommand)]
+        command: SnapshotCommand,
+    },
+
+    /// Show what is installed for every repository, chart and manifest set, and whether it is healthy.
+    Status {
+        /// Print JSON instead of a table.
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Roll back the release of a chart to an earlier revision.
+    Rollback {
+        /// The name of the chart in the configuration.
+        chart: String,
+
+        /// The revision to roll back to. Defaults to the last deployed revision before the current one.
+        revision: Option<u64>,
+    },
+
+    /// Compare the stored default values of charts with their pinned versions.
+    Values {
+        #[clap(subcommand)]
+        command: ValuesCommand,
+    },
+
+    /// Browse the command transcripts of past runs.
+    Runs {
+        #[clap(subcommand)]
+        command: RunsCommand,
+    },
+}
+
+/// Commands for inspecting the configuration.
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Show the effective configuration, with includes and the profile applied.
+    Show,
+
+    /// Check the configuration and report every problem that was found.
+    Validate,
+
+    /// Rewrite the configuration, and the files it includes, to the current version.
+    Migrate,
+
+    /// Print the JSON Schema of the configuration file, for editor autocompletion.
+    Schema,
+}
+
+/// Commands for saving and restoring the state of the cluster.
+#[derive(Subcommand, Debug)]
+pub enum SnapshotCommand {
+    /// Record the release revisions, manifest resources and values file hashes.
+    Save {
+        /// The name of the snapshot.
+        name: String,
+    },
+
+    /// Roll back or remove everything that changed since the snapshot.
+    Restore {
+        /// The name of the snapshot.
+        name: String,
+    },
+
+    /// List the saved snapshots.
+    List,
+}
+
+/// Commands for managing the default values of charts.
+#[derive(Subcommand, Debug)]
+pub enum ValuesCommand {
+    /// Show the keys added, removed or renamed upstream, and stale keys in override files.
+    Diff {
+        /// The names of the charts to compare. Compares all when empty.
+        charts: Vec<String>,
+    },
+
+    /// Replace the stored default values with those of the pinned chart versions.
+    Refresh {
+        /// The names of the charts to refresh. Refreshes all when empty.
+        charts: Vec<String>,
+    },
+}
+
+/// Commands for browsing past runs.
+#[derive(Subcommand, Debug)]
+pub enum RunsCommand {
+    /// List all recorded runs.
+    List,
+
+    /// Show the command transcripts of a run.
+    Show {
+        /// The run identifier, or "last" for the most recent run.
+        id: String,
+    },
 }
 
 impl Args {
@@ -30,9 +187,30 @@ impl Args {
 
         let config = args.config;
 
+        let profile = args.profile;
+
         let action = args.action;
 
-        Self { config, action }
+        let resume = args.resume;
+
+        let dry_run = args.dry_run;
+
+        let force = args.force;
+
+        let offline = args.offline;
+
+        let command = args.command;
+
+        Self {
+            config,
+            profile,
+            action,
+            resume,
+            dry_run,
+            force,
+            offline,
+            command,
+        }
     }
 }
 
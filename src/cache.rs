@@ -0,0 +1,189 @@
+//! Cache
+//!
+//! This module caches remote manifests, so they are only downloaded again when
+//! they change and can be used without a network connection.
+//!
+//! Each URL is stored in `.loopy/cache/http` under the SHA-256 of the URL, next
+//! to a metadata file with its ETag and Last-Modified headers. A cached URL is
+//! revalidated on every run, and the cached copy is used when the server reports
+//! it hasn't changed, can't be reached or returns an error. With `--offline`, only cached content and git checkouts
+//! are used.
+//!
+
+use crate::utils::hash_content;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local};
+use log::{debug, error, info, warn};
+use reqwest::header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The directory where remote content is cached.
+const HTTP_CACHE_DIR: &str = ".loopy/cache/http";
+
+/// Whether only cached content may be used.
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// The metadata of a cached URL.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheEntry {
+    /// The URL that was fetched.
+    url: String,
+    /// The ETag header of the last response.
+    etag: Option<String>,
+    /// The Last-Modified header of the last response.
+    last_modified: Option<String>,
+    /// When the content was last downloaded.
+    fetched: Option<DateTime<Local>>,
+}
+
+/// Set offline mode.
+///
+/// # Arguments
+///
+/// * `offline` - If true, nothing is downloaded and only cached content is used
+///
+pub fn set_offline(offline: bool) {
+    OFFLINE.store(offline, Ordering::SeqCst);
+}
+
+/// Check if offline mode is enabled.
+pub fn is_offline() -> bool {
+    OFFLINE.load(Ordering::SeqCst)
+}
+
+/// Fetch a URL through the cache.
+///
+/// Downloads the URL if it isn't cached or has changed since it was cached.
+/// If the server can't be reached or returns an error, the cached copy is used
+/// with a warning.
+///
+/// # Arguments
+///
+/// * `url` - The URL to fetch
+///
+/// # Returns
+///
+/// The path of the cached content.
+///
+pub async fn fetch(url: &str) -> Result<PathBuf> {
+    let name = hash_content(url.as_bytes());
+    let path = Path::new(HTTP_CACHE_DIR).join(&name);
+    let entry_path = Path::new(HTTP_CACHE_DIR).join(format!("{}.json", name));
+    let cached = path.is_file();
+
+    if is_offline() {
+        if cached {
+            info!("Using cached copy of {}", url);
+            return Ok(path);
+        }
+        return Err(anyhow!(
+            "{} is not cached, run once without --offline to download it",
+            url
+        ));
+    }
+
+    let mut request = reqwest::Client::new().get(url);
+    if cached {
+        let entry = read_entry(&entry_path);
+        if let Some(etag) = &entry.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &entry.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(e) if cached => {
+            warn!("Failed to revalidate {}, using the cached copy: {}", url, e);
+            return Ok(path);
+        }
+        Err(e) => return Err(e).context(format!("Failed to get URL: {}", url)),
+    };
+
+    if response.status() == StatusCode::NOT_MODIFIED && cached {
+        debug!("{} has not changed, using the cached copy", url);
+        return Ok(path);
+    }
+
+    if !response.status().is_success() && cached {
+        warn!(
+            "Failed to revalidate {}, using the cached copy: status code {}",
+            url,
+            response.status()
+        );
+        return Ok(path);
+    }
+    if !response.status().is_success() {
+        error!("Failed to get URL: {}", url);
+        error!("Status code: {}", response.status());
+        return Err(anyhow!(
+            "Failed to get URL: {}, status code {}",
+            url,
+            response.status()
+        ));
+    }
+
+    let entry = CacheEntry {
+        url: url.to_string(),
+        etag: header(response.headers(), ETAG),
+        last_modified: header(response.headers(), LAST_MODIFIED),
+        fetched: Some(Local::now()),
+    };
+    let err_msg = format!("Failed to download URL: {}", url);
+    let content = response.bytes().await.context(err_msg)?;
+
+    let err_msg = format!("Failed to create cache directory {}", HTTP_CACHE_DIR);
+    fs::create_dir_all(HTTP_CACHE_DIR).context(err_msg)?;
+    write_file(&path, &content)?;
+    write_file(
+        &entry_path,
+        serde_json::to_string_pretty(&entry)?.as_bytes(),
+    )?;
+
+    info!("Downloaded {} to {}", url, path.display());
+
+    Ok(path)
+}
+
+/// Write a file of the cache.
+///
+/// The content is written to a temporary file that is moved into place, so a
+/// component never reads a file that another one is still writing.
+///
+/// # Arguments
+///
+/// * `path` - The path of the file in the cache
+/// * `content` - The content of the file
+///
+fn write_file(path: &Path, content: &[u8]) -> Result<()> {
+    let err_msg = format!("Failed to write {}", path.display());
+    let mut file = tempfile::NamedTempFile::new_in(HTTP_CACHE_DIR).context(err_msg.clone())?;
+    file.write_all(content).context(err_msg.clone())?;
+    file.persist(path).context(err_msg)?;
+
+    Ok(())
+}
+
+/// Read the metadata of a cached URL, or an empty entry if it can't be read.
+fn read_entry(path: &Path) -> CacheEntry {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Read a header as a string.
+fn header(headers: &HeaderMap, name: reqwest::header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
@@ -0,0 +1,196 @@
+//! Changes
+//!
+//! This module detects charts and manifest sets whose inputs haven't changed
+//! since they were last installed, so an install can skip them.
+//!
+//! The inputs of a chart are its source, version, values and options, and the
+//! inputs of a manifest set are its rendered content. Their HMAC-SHA256 is
+//! recorded with the component in the state store of the cluster. A component
+//! is only skipped when its hash matches and it is still deployed, and never
+//! with `--force`.
+//!
+//! The inputs include decrypted secrets and rendered environment variables, so
+//! the hash is keyed with a random key in `.loopy/state.key` that never leaves
+//! the machine. A plain hash stored in the cluster would let short secrets be
+//! guessed. Another machine, or a new key, installs every component once again.
+//!
+
+use crate::store;
+use crate::utils::hash_content;
+
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use log::{debug, info};
+use rand::RngCore;
+use sha2::Sha256;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// The file that holds the key of the input hashes.
+const KEY_FILE: &str = ".loopy/state.key";
+
+/// Whether unchanged components are installed anyway.
+static FORCE: AtomicBool = AtomicBool::new(false);
+
+/// The components that were skipped in this run.
+static UNCHANGED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Set force mode.
+///
+/// # Arguments
+///
+/// * `force` - If true, components are installed even when their inputs are unchanged
+///
+pub fn set_force(force: bool) {
+    FORCE.store(force, Ordering::SeqCst);
+}
+
+/// Check if the inputs of a component are unchanged since it was last installed.
+///
+/// The caller checks that the component is still deployed before skipping it.
+///
+/// # Arguments
+///
+/// * `kind` - The kind of component, "chart" or "manifest"
+/// * `name` - The name of the component
+/// * `hash` - The hash of the current inputs
+///
+pub fn is_unchanged(kind: &str, name: &str, hash: &str) -> bool {
+    if FORCE.load(Ordering::SeqCst) {
+        return false;
+    }
+
+    let unchanged = store::hash(kind, name).is_some_and(|stored| stored == hash);
+    debug!("Inputs of {} {} unchanged: {}", kind, name, unchanged);
+
+    unchanged
+}
+
+/// Hash the inputs of a component, keyed with the local key.
+///
+/// The key is created the first time it is needed.
+///
+/// # Arguments
+///
+/// * `content` - The inputs of the component
+///
+pub fn hash_inputs(content: &[u8]) -> Result<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&hash_key()?)
+        .map_err(|e| anyhow::anyhow!("Invalid key in {}: {}", KEY_FILE, e))?;
+    mac.update(content);
+
+    Ok(format!("{:x}", mac.finalize().into_bytes()))
+}
+
+/// Read the key of the input hashes, creating it if it doesn't exist.
+fn hash_key() -> Result<Vec<u8>> {
+    let path = Path::new(KEY_FILE);
+    if path.is_file() {
+        let err_msg = format!("Failed to read {}", KEY_FILE);
+        return fs::read(path).context(err_msg);
+    }
+
+    let mut key = vec![0u8; 32];
+    rand::thread_rng().fill_bytes(&mut key);
+
+    let err_msg = format!("Failed to create {}", KEY_FILE);
+    fs::create_dir_all(path.parent().unwrap_or(Path::new("."))).context(err_msg.clone())?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    match options.open(path) {
+        Ok(mut file) => file.write_all(&key).context(err_msg)?,
+        // Another component created it first.
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            let err_msg = format!("Failed to read {}", KEY_FILE);
+            return fs::read(path).context(err_msg);
+        }
+        Err(e) => return Err(e).context(err_msg),
+    }
+    info!("Created the key of the input hashes in {}", KEY_FILE);
+
+    Ok(key)
+}
+
+/// Record that a component was skipped, for the summary.
+///
+/// # Arguments
+///
+/// * `kind` - The kind of component, for example "Helm chart"
+/// * `name` - The name of the component
+///
+pub fn skip(kind: &str, name: &str) {
+    println!("The {} {} is unchanged, skipping", kind, name);
+    UNCHANGED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(format!("{} {}", kind, name));
+}
+
+/// Print the components that were skipped in this run, if there were any.
+pub fn summary() {
+    let unchanged = UNCHANGED.lock().unwrap_or_else(|e| e.into_inner());
+    if unchanged.is_empty() {
+        return;
+    }
+
+    println!(
+        "{} unchanged, use --force to install them anyway: {}",
+        unchanged.len(),
+        unchanged.join(", ")
+    );
+}
+
+/// Hash the files of a directory, in a stable order.
+///
+/// The `charts` directory and `Chart.lock` are left out, as Helm builds them
+/// again with new timestamps whenever the chart dependencies are built.
+///
+/// # Arguments
+///
+/// * `dir` - The directory to hash
+///
+pub fn hash_dir(dir: &Path) -> Result<String> {
+    let mut files = Vec::new();
+    collect_files(dir, dir, &mut files)?;
+    files.sort();
+
+    let mut content = Vec::new();
+    for file in files {
+        let err_msg = format!("Failed to read {}", dir.join(&file).display());
+        let data = fs::read(dir.join(&file)).context(err_msg)?;
+        content.extend(file.as_bytes());
+        content.push(0);
+        content.extend(hash_content(&data).as_bytes());
+        content.push(b'\n');
+    }
+
+    Ok(hash_content(&content))
+}
+
+/// Collect the files below a directory, relative to the root.
+fn collect_files(root: &Path, dir: &Path, files: &mut Vec<String>) -> Result<()> {
+    let err_msg = format!("Failed to read directory {}", dir.display());
+    for entry in fs::read_dir(dir).context(err_msg)? {
+        let path = entry?.path();
+        let relative = path
+            .strip_prefix(root)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .to_string();
+        if relative == "charts" || relative == "Chart.lock" {
+            continue;
+        }
+        if path.is_dir() {
+            collect_files(root, &path, files)?;
+        } else {
+            files.push(relative);
+        }
+    }
+
+    Ok(())
+}
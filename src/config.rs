@@ -4,21 +4,74 @@
 //! for loading and validating the configuration file.
 //!
 
+use crate::validate::{self, Locator};
+
 use anyhow::{Context, Result};
+use schemars::JsonSchema;
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+/// The current version of the configuration file format.
+pub const CONFIG_VERSION: u64 = 1;
 
 /// Main configuration structure.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 pub struct Config {
     pub log: Option<Log>,
+    #[serde(default)]
     pub dependencies: Dependencies,
+    #[serde(default)]
     pub application: Application,
+    pub port_forwards: Option<Vec<PortForward>>,
+    /// The maximum number of manifests and charts processed at the same time.
+    /// Defaults to 4.
+    pub parallelism: Option<usize>,
+    /// Optional OCI registries to log in to before installing charts.
+    pub registries: Option<Vec<Registry>>,
+    /// Optional variables for values files and manifests, used as `{{ vars.<name> }}`.
+    #[schemars(with = "Option<BTreeMap<String, serde_json::Value>>")]
+    pub vars: Option<BTreeMap<String, serde_yaml::Value>>,
+}
+
+impl Config {
+    /// The template variables as strings.
+    pub fn template_vars(&self) -> BTreeMap<String, String> {
+        self.vars
+            .iter()
+            .flatten()
+            .filter_map(|(name, value)| {
+                let value = match value {
+                    serde_yaml::Value::String(value) => value.clone(),
+                    serde_yaml::Value::Number(value) => value.to_string(),
+                    serde_yaml::Value::Bool(value) => value.to_string(),
+                    _ => return None,
+                };
+                Some((name.clone(), value))
+            })
+            .collect()
+    }
+}
+
+/// OCI registry configuration structure.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Registry {
+    /// The registry host, for example "ghcr.io" or "localhost:5000".
+    pub host: String,
+    /// Optional user name to log in with.
+    pub username: Option<String>,
+    /// The environment variable holding the password or token.
+    /// Required when a user name is set.
+    pub password_env: Option<String>,
+    /// Skip verification of the registry TLS certificate, defaults to false.
+    pub insecure: Option<bool>,
+    /// Use plain HTTP instead of HTTPS, for example for a local registry, defaults to false.
+    pub plain_http: Option<bool>,
 }
 
 /// Log configuration structure.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 pub struct Log {
     /// Logging level (e.g., "info", "warn", "error").
     pub level: Option<String>,
@@ -27,29 +80,61 @@ pub struct Log {
     /// Boolean to enable or disable showing a fortune cookie.
     /// Defaults to false.
     pub fortune: Option<bool>,
+    /// Log record format, either "text" or "json".
+    /// Defaults to "text".
+    pub format: Option<String>,
+    /// Log level overrides per module (e.g., "loopy::helm": "debug").
+    pub modules: Option<BTreeMap<String, String>>,
 }
 
 /// Dependencies configuration structure.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(default)]
 pub struct Dependencies {
     //pub carvel: Carvel,
     pub helm: Helm,
     pub manifests: Vec<Manifests>,
     pub tools: Vec<Tool>,
     pub tests: Vec<Test>,
+    pub secrets: Vec<Secret>,
 }
 
 /// Application configuration structure.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(default)]
 pub struct Application {
     //pub carvel: Carvel,
     pub helm: Helm,
     pub manifests: Vec<Manifests>,
     pub tests: Vec<Test>,
+    pub secrets: Vec<Secret>,
+}
+
+/// Kubernetes Secret configuration structure.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Secret {
+    /// The name of the Secret.
+    pub name: String,
+    /// The namespace of the Secret, defaults to "default".
+    pub namespace: Option<String>,
+    /// The type of the Secret, defaults to "Opaque".
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    /// The keys of the Secret and where to read each value from.
+    pub data: BTreeMap<String, SecretSource>,
+}
+
+/// The source of a single Secret value.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct SecretSource {
+    /// Read the value from this environment variable.
+    pub env: Option<String>,
+    /// Read the value from this local file.
+    pub file: Option<String>,
 }
 
 /// Tool configuration structure.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 pub struct Tool {
     /// Tool name.
     pub name: String,
@@ -60,11 +145,19 @@ pub struct Tool {
 }
 
 /// Test configuration structure.
-#[derive(Debug, Deserialize)]
+///
+/// A test is one of a command test, which runs a local command,
+/// an HTTP test, which sends a request to a deployed service,
+/// a Pod test, which runs a Pod or Job in the cluster, or
+/// a Helm test, which runs the test hooks of a Helm release.
+#[derive(Debug, Deserialize, JsonSchema)]
 pub struct Test {
+    /// Optional test name, used in output.
+    pub name: Option<String>,
     /// Test command.
-    pub command: String,
+    pub command: Option<String>,
     /// Test arguments.
+    #[allow(dead_code)]
     pub args: Option<Vec<String>>,
     /// stdout
     pub stdout: Option<String>,
@@ -72,27 +165,134 @@ pub struct Test {
     pub stderr: Option<String>,
     /// status
     pub status: Option<i32>,
+    /// Optional HTTP test.
+    pub http: Option<HttpTest>,
+    /// Optional Pod test.
+    pub pod: Option<PodTest>,
+    /// Optional Helm test.
+    pub helm: Option<HelmTest>,
+}
+
+impl Test {
+    /// A short label for the test, used in output.
+    ///
+    /// Defaults to the command, URL, manifest, image or release when no name is set.
+    pub fn label(&self) -> &str {
+        self.name
+            .as_deref()
+            .or(self.command.as_deref())
+            .or(self.http.as_ref().map(|http| http.url.as_str()))
+            .or(self
+                .pod
+                .as_ref()
+                .and_then(|pod| pod.manifest.as_deref().or(pod.image.as_deref())))
+            .or(self.helm.as_ref().map(|helm| helm.release.as_str()))
+            .unwrap_or_default()
+    }
+}
+
+/// HTTP test configuration structure.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct HttpTest {
+    /// The URL to request.
+    /// When a service is set, the scheme, host and port are replaced
+    /// with the local end of a temporary port-forward.
+    pub url: String,
+    /// HTTP method, defaults to GET.
+    pub method: Option<String>,
+    /// Expected status code, defaults to any 2xx status.
+    pub status: Option<u16>,
+    /// Expected response headers and their values.
+    pub headers: Option<BTreeMap<String, String>>,
+    /// A regular expression the response body must match.
+    pub body: Option<String>,
+    /// Optional Service to port-forward to when there is no ingress.
+    pub service: Option<String>,
+    /// The namespace of the Service, defaults to "default".
+    pub namespace: Option<String>,
+    /// The Service port to forward to, defaults to 80.
+    pub port: Option<u16>,
+    /// Number of retries until the endpoint comes up, defaults to 10.
+    pub retries: Option<u32>,
+    /// Seconds to wait between retries, defaults to 5.
+    pub interval: Option<u64>,
+    /// Request timeout in seconds, defaults to 10.
+    pub timeout: Option<u64>,
+}
+
+/// Pod test configuration structure.
+///
+/// Runs either the first Pod or Job in a manifest file, or a Pod
+/// created from an inline image and command. A Job passes with status 0 when
+/// it succeeds, and has status 1 when it fails.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct PodTest {
+    /// Optional manifest file, relative to the manifests directory.
+    pub manifest: Option<String>,
+    /// Container image, used when no manifest is provided.
+    pub image: Option<String>,
+    /// Optional container command, used with the image.
+    pub command: Option<Vec<String>>,
+    /// The namespace to run in, defaults to the manifest namespace or "default".
+    pub namespace: Option<String>,
+    /// Seconds to wait for completion, defaults to 300.
+    pub timeout: Option<u64>,
+}
+
+/// Helm test configuration structure.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct HelmTest {
+    /// The name of the Helm release to test.
+    pub release: String,
+    /// The namespace of the release, defaults to the release name.
+    pub namespace: Option<String>,
+    /// The time to wait for each test hook, defaults to "5m0s".
+    pub timeout: Option<String>,
 }
 
 /// Manifests configuration structure.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
 pub struct Manifests {
     /// Manifest name.
     pub name: String,
     /// Optional manifest URL.
     pub url: Option<String>,
-    /// Optional manifest directory.
+    /// Optional manifest URLs, applied after `url` in order.
+    #[serde(default)]
+    pub urls: Option<Vec<String>>,
+    /// Optional git repository containing a manifest file or directory.
+    pub git: Option<GitSource>,
+    /// Optional manifest directory, nested directories are applied too.
     pub dir: Option<String>,
+    /// Optional kustomization, a directory relative to the manifests directory or a remote URL.
+    pub kustomize: Option<String>,
+    /// Optional manifests and charts that must be processed before these manifests.
+    /// Use "manifest/<name>" or "chart/<name>" when a manifest and chart share a name.
+    #[serde(default)]
+    pub depends_on: Option<Vec<String>>,
+}
+
+impl Manifests {
+    /// The URLs to apply, `url` first.
+    pub fn urls(&self) -> Vec<&str> {
+        self.url
+            .iter()
+            .chain(self.urls.iter().flatten())
+            .map(|url| url.as_str())
+            .collect()
+    }
 }
 
 /// Carvel configuration structure.
-#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+#[derive(Debug, Deserialize, JsonSchema)]
 pub struct Carvel {
     pub packages: Vec<Package>,
 }
 
 /// Package configuration structure for Carvel.
-#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+#[derive(Debug, Deserialize, JsonSchema)]
 pub struct Package {
     /// Package name.
     pub name: String,
@@ -103,14 +303,15 @@ pub struct Package {
 }
 
 /// Helm configuration structure.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(default)]
 pub struct Helm {
     pub repositories: Vec<Repository>,
     pub charts: Vec<Chart>,
 }
 
 /// Repository configuration structure for Helm.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 pub struct Repository {
     /// Repository name.
     pub name: String,
@@ -119,27 +320,267 @@ pub struct Repository {
 }
 
 /// Chart configuration structure for Helm.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
 pub struct Chart {
-    /// The name of the Helm release.
+    /// The name of the chart entry, used for its directory under config/helm.
     pub name: String,
-    /// The Helm repository where the chart is located.
-    pub repo: String,
+    /// The Helm repository where the chart is located, either the name
+    /// of a configured repository or an OCI registry URL "oci://registry/path".
+    #[serde(default)]
+    pub repo: Option<String>,
+    /// Optional local chart directory, instead of a repository.
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Optional git repository containing the chart, instead of a repository.
+    #[serde(default)]
+    pub git: Option<GitSource>,
+    /// The name of the chart in the repository.
+    /// If not provided, defaults to the name.
+    #[serde(default)]
+    pub chart: Option<String>,
+    /// The name of the Helm release.
+    /// If not provided, defaults to the name.
+    #[serde(default)]
+    pub release: Option<String>,
     /// The Kubernetes namespace in which to deploy the Helm release.
     /// If not provided, defaults to the Helm release name.
     #[serde(default)]
     pub namespace: Option<String>,
-    /// The optional name of the values file to use.
+    /// The optional chart version constraint, for example "1.2.3" or "~1.2".
+    /// If not provided, the latest version is used.
+    #[serde(default)]
+    pub version: Option<String>,
+    /// The optional name of the values file, or a list of values files layered in order.
     /// If not provided, the default "values.yaml" file is used.
     #[serde(default)]
-    pub values: Option<String>,
+    pub values: Option<Values>,
+    /// Optional values set on the command line, which override the values files.
+    #[serde(default)]
+    pub set: Option<BTreeMap<String, String>>,
+    /// Optional values set on the command line that are always treated as strings.
+    #[serde(default)]
+    pub set_string: Option<BTreeMap<String, String>>,
+    /// The time to wait for the release, defaults to "10m0s".
+    #[serde(default)]
+    pub timeout: Option<String>,
+    /// Wait until the resources of the release are ready, defaults to true.
+    #[serde(default)]
+    pub wait: Option<bool>,
+    /// Roll back the release if the install or upgrade fails, defaults to false.
+    #[serde(default)]
+    pub atomic: Option<bool>,
+    /// Roll back to the last deployed revision if an upgrade fails, or uninstall
+    /// the release if the first install fails, defaults to false.
+    /// Unlike atomic, the failed revision is kept in the history for inspection.
+    #[serde(default)]
+    pub rollback: Option<bool>,
+    /// Don't install the CRDs of the chart, defaults to false.
+    #[serde(default)]
+    pub skip_crds: Option<bool>,
+    /// Optional extra arguments passed to helm install and upgrade as-is.
+    #[serde(default)]
+    pub extra_args: Option<Vec<String>>,
+    /// Optional port-forwards to the Services or Pods of this release.
+    /// If a namespace is not provided, the release namespace is used.
+    #[serde(default)]
+    pub port_forwards: Option<Vec<PortForward>>,
+    /// Optional manifests and charts that must be processed before this chart.
+    /// Use "manifest/<name>" or "chart/<name>" when a manifest and chart share a name.
+    #[serde(default)]
+    pub depends_on: Option<Vec<String>>,
+}
+
+impl Chart {
+    /// The name of the chart in the repository.
+    pub fn chart(&self) -> &str {
+        self.chart.as_deref().unwrap_or(&self.name)
+    }
+
+    /// The name of the Helm release.
+    pub fn release(&self) -> &str {
+        self.release.as_deref().unwrap_or(&self.name)
+    }
+
+    /// The namespace of the Helm release.
+    pub fn namespace(&self) -> &str {
+        self.namespace.as_deref().unwrap_or(self.release())
+    }
+
+    /// The values files, in the order they are layered.
+    pub fn values_files(&self) -> Vec<&str> {
+        match &self.values {
+            Some(Values::File(file)) => vec![file.as_str()],
+            Some(Values::Files(files)) => files.iter().map(|file| file.as_str()).collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Git source configuration structure.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct GitSource {
+    /// The URL of the git repository.
+    pub url: String,
+    /// Optional branch, tag or commit to check out, defaults to the default branch.
+    #[serde(rename = "ref")]
+    pub reference: Option<String>,
+    /// Optional directory within the repository, defaults to the repository root.
+    pub path: Option<String>,
+}
+
+/// Values files of a chart, either a single file or a list.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Values {
+    File(String),
+    Files(Vec<String>),
+}
+
+/// Port-forward configuration structure.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct PortForward {
+    /// Port-forward name.
+    pub name: String,
+    /// The namespace of the Service or Pod.
+    pub namespace: Option<String>,
+    /// Optional Service to forward to.
+    pub service: Option<String>,
+    /// Optional Pod to forward to.
+    pub pod: Option<String>,
+    /// The local port to listen on.
+    pub local_port: u16,
+    /// The Service or Pod port to forward to.
+    pub remote_port: u16,
 }
 
 /// Load config.
 ///
-/// Loads the configuration file and perform validation on the file.
+/// Loads the configuration file and its includes, applies the selected
+/// profile and performs validation on the result.
 ///
-pub fn load_config(config_file: &str) -> Result<Config> {
+/// # Arguments
+///
+/// * `config_file` - The path of the configuration file
+/// * `profile` - Optional: The name of the profile to apply
+///
+pub fn load_config(config_file: &str, profile: Option<&str>) -> Result<Config> {
+    let resolved = resolve_config(config_file, profile)?;
+    let locator = Locator::new(&resolved);
+
+    // Process the merged configuration to ensure it has the expected structure.
+    let config: Config = serde_path_to_error::deserialize(resolved.value).map_err(|e| {
+        let path = e.path().to_string();
+        anyhow::anyhow!(
+            "{}: {}: {}",
+            locator.describe(&path, config_file),
+            path,
+            e.inner()
+        )
+    })?;
+
+    // Perform validation on the config for custom rules.
+    let problems = validate::validate_config(&config, &locator);
+    if !problems.is_empty() {
+        return Err(validate::report(config_file, &problems));
+    }
+
+    Ok(config)
+}
+
+/// Config hash.
+///
+/// Hashes the effective configuration, so a change to any included file or
+/// the selected profile is noticed. The hash is keyed like the inputs of the
+/// components, as the configuration may hold secrets.
+///
+/// # Arguments
+///
+/// * `config_file` - The path of the configuration file
+/// * `profile` - Optional: The name of the profile to apply
+///
+pub fn config_hash(config_file: &str, profile: Option<&str>) -> Result<String> {
+    let resolved = resolve_config(config_file, profile)?;
+    let content = serde_yaml::to_string(&resolved.value)?;
+
+    crate::changes::hash_inputs(content.as_bytes())
+}
+
+/// The JSON Schema of the configuration file.
+///
+/// Describes the `include` and `profiles` fields and the `enabled` field of
+/// list entries as well, which are handled before the configuration is loaded.
+///
+pub fn schema() -> serde_json::Value {
+    let mut schema = serde_json::to_value(schemars::schema_for!(Config)).unwrap_or_default();
+
+    schema["properties"]["version"] = serde_json::json!({
+        "description": "The version of the configuration file format. Older files are migrated with `loopy config migrate`.",
+        "type": "integer",
+        "minimum": 0,
+        "maximum": CONFIG_VERSION
+    });
+    schema["properties"]["include"] = serde_json::json!({
+        "description": "Configuration files to merge this file on top of, relative to this file.",
+        "type": "array",
+        "items": { "type": "string" }
+    });
+    schema["properties"]["profiles"] = serde_json::json!({
+        "description": "Named overlays that are merged on top of the configuration when selected with --profile.",
+        "type": "object",
+        "additionalProperties": { "type": "object" }
+    });
+
+    let entries = [
+        "Chart",
+        "Manifests",
+        "PortForward",
+        "Registry",
+        "Repository",
+        "Secret",
+        "Test",
+        "Tool",
+    ];
+    for entry in entries {
+        let properties = &mut schema["definitions"][entry]["properties"];
+        if properties.is_object() {
+            properties["enabled"] = serde_json::json!({
+                "description": "Set to false to leave this entry out, for example in a profile.",
+                "type": "boolean"
+            });
+        }
+    }
+
+    schema
+}
+
+/// A configuration with its includes and profile applied.
+#[derive(Debug)]
+pub struct ResolvedConfig {
+    /// The effective configuration.
+    pub value: serde_yaml::Value,
+    /// The files the configuration was loaded from and their contents, in merge order.
+    pub sources: Vec<(PathBuf, String)>,
+    /// The profile that was applied.
+    pub profile: Option<String>,
+}
+
+/// Resolve config.
+///
+/// Builds the effective configuration as YAML, before it is deserialized.
+///
+/// Files listed in `include` are loaded first, relative to the file that
+/// includes them, and the including file is merged on top. Mappings are merged
+/// key by key, and list entries with the same `name` are merged into one entry.
+/// Other list entries are appended. The selected profile from `profiles` is
+/// merged on top in the same way. Finally, list entries with `enabled: false`
+/// are removed, together with any `depends_on` references to them.
+///
+/// # Arguments
+///
+/// * `config_file` - The path of the configuration file
+/// * `profile` - Optional: The name of the profile to apply
+///
+pub fn resolve_config(config_file: &str, profile: Option<&str>) -> Result<ResolvedConfig> {
     if !Path::new(config_file).exists() {
         let err_msg = format!(
             "The configuration file {} was not found in the current directory.
@@ -149,166 +590,482 @@ pub fn load_config(config_file: &str) -> Result<Config> {
         anyhow::bail!(err_msg);
     }
 
-    // Read and deserialize the config file.
-    let err_msg = format!("Failed to read {}", config_file);
-    let content = fs::read_to_string(config_file).context(err_msg)?;
+    let mut sources = Vec::new();
+    let mut value = read_config_file(Path::new(config_file), &mut Vec::new(), &mut sources)?;
+
+    let profiles = match &mut value {
+        serde_yaml::Value::Mapping(mapping) => mapping.remove("profiles"),
+        _ => None,
+    };
+    if let Some(name) = profile {
+        let overlay = profiles
+            .as_ref()
+            .and_then(|profiles| profiles.get(name))
+            .cloned();
+        let Some(overlay) = overlay else {
+            let available: Vec<&str> = profiles
+                .as_ref()
+                .and_then(|profiles| profiles.as_mapping())
+                .into_iter()
+                .flat_map(|profiles| profiles.keys())
+                .filter_map(|name| name.as_str())
+                .collect();
+            anyhow::bail!(
+                "The profile '{}' is not defined in {}. Available profiles: {}",
+                name,
+                config_file,
+                if available.is_empty() {
+                    "none".to_string()
+                } else {
+                    available.join(", ")
+                }
+            );
+        };
+        merge_values(&mut value, overlay);
+    }
+
+    let mut disabled = Vec::new();
+    let mut enabled = Vec::new();
+    remove_disabled(&mut value, "", &mut disabled, &mut enabled);
+    if !disabled.is_empty() {
+        remove_references(&mut value, &disabled, &enabled);
+    }
+
+    Ok(ResolvedConfig {
+        value,
+        sources,
+        profile: profile.map(|profile| profile.to_string()),
+    })
+}
+
+/// Read a configuration file and the files it includes.
+fn read_config_file(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    sources: &mut Vec<(PathBuf, String)>,
+) -> Result<serde_yaml::Value> {
+    let err_msg = format!("Failed to read {}", path.display());
+    let canonical = path.canonicalize().context(err_msg.clone())?;
+    if stack.contains(&canonical) {
+        anyhow::bail!(
+            "The configuration file {} includes itself through its includes.",
+            path.display()
+        );
+    }
+
+    let content = fs::read_to_string(path).context(err_msg)?;
 
     // Process the file to ensure it is valid YAML.
-    let err_msg = format!(
-        "Failed to parse YAML configuration file. Please ensure the file {} has a valid YAML syntax.",
-        config_file,
-    );
-    let config: Config = serde_yaml::from_str(&content).context(err_msg)?;
+    let mut value: serde_yaml::Value =
+        serde_yaml::from_str(&content).map_err(|e| match e.location() {
+            Some(location) => anyhow::anyhow!(
+                "{}:{}:{}: Invalid YAML syntax: {}",
+                path.display(),
+                location.line(),
+                location.column(),
+                e
+            ),
+            None => anyhow::anyhow!("{}: Invalid YAML syntax: {}", path.display(), e),
+        })?;
 
-    // Perform validation on the config for custom rules.
-    validate_config(&config)?;
+    // Older files are migrated in memory, and a null field is the same as a missing one.
+    migrate_value(&mut value, path)?;
+    if let serde_yaml::Value::Mapping(mapping) = &mut value {
+        mapping.remove("version");
+    }
+    remove_nulls(&mut value);
 
-    Ok(config)
+    let includes = match &mut value {
+        serde_yaml::Value::Mapping(mapping) => mapping.remove("include"),
+        _ => None,
+    };
+    let includes: Vec<String> = match includes {
+        Some(includes) => serde_yaml::from_value(includes).with_context(|| {
+            format!(
+                "The 'include' field of {} must be a list of files.",
+                path.display()
+            )
+        })?,
+        None => Vec::new(),
+    };
+
+    stack.push(canonical);
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+    let mut merged = serde_yaml::Value::Mapping(serde_yaml::Mapping::new());
+    for include in includes {
+        let included = read_config_file(&base_dir.join(&include), stack, sources)
+            .with_context(|| format!("Failed to include {} from {}", include, path.display()))?;
+        merge_values(&mut merged, included);
+    }
+    stack.pop();
+
+    sources.push((path.to_path_buf(), content));
+    merge_values(&mut merged, value);
+
+    Ok(merged)
 }
 
-/// Validate config.
+/// Migrate a configuration to the current version.
 ///
-/// Performs validation on the config file.
+/// Applies each migration from the version of the configuration, or version 0
+/// when it has none, and sets the current version.
 ///
-fn validate_config(config: &Config) -> Result<()> {
-    // Validate dependencies.tools
-    for tool in &config.dependencies.tools {
-        // Ensure that the name field of each tool is not empty.
-        let err_msg = "The 'name' field of all defined tools cannot be empty.".to_string();
-        if tool.name.trim().is_empty() {
-            anyhow::bail!(err_msg);
-        }
+/// # Arguments
+///
+/// * `value` - The configuration of a single file
+/// * `path` - The path of the file, used in errors
+///
+/// # Returns
+///
+/// The version the configuration had before it was migrated.
+///
+fn migrate_value(value: &mut serde_yaml::Value, path: &Path) -> Result<u64> {
+    let Some(mapping) = value.as_mapping_mut() else {
+        return Ok(CONFIG_VERSION);
+    };
 
-        // Ensure that the bin field of each tool is not empty.
-        let err_msg = format!("The 'bin' field of {} cannot be empty.", tool.name).to_string();
-        if tool.bin.trim().is_empty() {
-            anyhow::bail!(err_msg);
-        }
+    let version = match mapping.get("version") {
+        Some(version) => version.as_u64().with_context(|| {
+            format!(
+                "The 'version' field of {} must be a whole number.",
+                path.display()
+            )
+        })?,
+        None => 0,
+    };
+    if version > CONFIG_VERSION {
+        anyhow::bail!(
+            "{} is version {} of the configuration format, but this loopy only supports up to version {}. Please upgrade loopy.",
+            path.display(),
+            version,
+            CONFIG_VERSION
+        );
     }
 
-    // Validate dependencies.tests
-    for test in &config.dependencies.tests {
-        // Ensure that the command field of each test is not empty.
-        let err_msg = "The 'command' field of all defined tests cannot be empty.".to_string();
-        if test.command.trim().is_empty() {
-            anyhow::bail!(err_msg);
-        }
+    // Each migration upgrades a configuration from its index to the next version.
+    let migrations: [fn(&mut serde_yaml::Value); CONFIG_VERSION as usize] = [migrate_v0];
+    for migration in &migrations[version as usize..] {
+        migration(value);
     }
 
-    // Validate dependencies.manifests
-    for manifest in &config.dependencies.manifests {
-        // Ensure that the name field of each manifest is not empty.
-        let err_msg = "The 'name' field of all defined manifests cannot be empty.".to_string();
-        if manifest.name.trim().is_empty() {
-            anyhow::bail!(err_msg);
-        }
+    if let Some(mapping) = value.as_mapping_mut() {
+        mapping.insert("version".into(), CONFIG_VERSION.into());
+    }
 
-        // Ensure that either the url or dir field of each manifest is not empty.
-        let err_msg = format!(
-            "The 'url' and 'dir' field of {} cannot both be empty, at least one must be defined.",
-            manifest.name
-        );
-        if manifest.url.is_none() && manifest.dir.is_none() {
-            anyhow::bail!(err_msg);
+    Ok(version)
+}
+
+/// Migrate a configuration from version 0.
+///
+/// Every section became optional, so the empty sections and lists that were
+/// required before are removed. Only the sections of the version 0 schema are
+/// touched, as empty values elsewhere, such as in `set`, can be meaningful.
+///
+fn migrate_v0(value: &mut serde_yaml::Value) {
+    let Some(config) = value.as_mapping_mut() else {
+        return;
+    };
+
+    for phase in ["dependencies", "application"] {
+        if let Some(section) = config.get_mut(phase).and_then(|v| v.as_mapping_mut()) {
+            if let Some(helm) = section.get_mut("helm").and_then(|v| v.as_mapping_mut()) {
+                remove_empty(helm, &["repositories", "charts"]);
+            }
+            remove_empty(section, &["helm", "manifests", "tools", "tests"]);
         }
     }
+    remove_empty(config, &["log", "dependencies", "application"]);
+}
 
-    // Validate dependencies.helm.repositories
-    for repo in &config.dependencies.helm.repositories {
-        // Ensure that the name field of each repository is not empty.
-        let err_msg = "The 'name' field of all defined repositories cannot be empty.".to_string();
-        if repo.name.trim().is_empty() {
-            anyhow::bail!(err_msg);
+/// Remove the given keys of a mapping when they are null or empty.
+fn remove_empty(mapping: &mut serde_yaml::Mapping, keys: &[&str]) {
+    for key in keys {
+        let empty = match mapping.get(*key) {
+            Some(serde_yaml::Value::Null) => true,
+            Some(serde_yaml::Value::Sequence(items)) => items.is_empty(),
+            Some(serde_yaml::Value::Mapping(entries)) => entries.is_empty(),
+            _ => false,
+        };
+        if empty {
+            mapping.remove(*key);
         }
+    }
+}
 
-        // Ensure that the url field of each repository is not empty.
-        let err_msg = format!("The 'url' field of {} cannot be empty", repo.name).to_string();
-        for repo in &config.dependencies.helm.repositories {
-            if repo.url.trim().is_empty() {
-                anyhow::bail!(err_msg);
+/// Remove the fields that are null, so their defaults are used.
+fn remove_nulls(value: &mut serde_yaml::Value) {
+    match value {
+        serde_yaml::Value::Mapping(mapping) => {
+            mapping.retain(|_, child| !child.is_null());
+            for (_, child) in mapping.iter_mut() {
+                remove_nulls(child);
+            }
+        }
+        serde_yaml::Value::Sequence(items) => {
+            for item in items {
+                remove_nulls(item);
             }
         }
+        _ => {}
     }
+}
 
-    // Validate dependencies.helm.charts
-    for chart in &config.dependencies.helm.charts {
-        // Ensure that the name field of each chart is not empty.
-        let err_msg = "The 'name' field of all defined charts cannot be empty.".to_string();
-        if chart.name.trim().is_empty() {
-            anyhow::bail!(err_msg);
-        }
+/// Migrate config.
+///
+/// Rewrites a configuration file and the files it includes to the current
+/// version. The original of each rewritten file is kept with a `.bak` suffix.
+/// Comments are not kept in the rewritten files.
+///
+/// # Arguments
+///
+/// * `config_file` - The path of the configuration file
+///
+pub fn migrate_config(config_file: &str) -> Result<()> {
+    // Fail on missing files and include cycles before anything is rewritten.
+    resolve_config(config_file, None)?;
 
-        // Ensure that the repo field of each chart is not empty.
-        let err_msg =
-            format!("The 'repo' field of chart {} cannot be empty.", chart.name).to_string();
-        if chart.repo.trim().is_empty() {
-            anyhow::bail!(err_msg);
+    let path = Path::new(config_file);
+    let err_msg = format!("Failed to read {}", path.display());
+    let content = fs::read_to_string(path).context(err_msg)?;
+    let err_msg = format!("{}: Invalid YAML syntax", path.display());
+    let mut value: serde_yaml::Value = serde_yaml::from_str(&content).context(err_msg)?;
+
+    let version = migrate_value(&mut value, path)?;
+    if version == CONFIG_VERSION {
+        println!(
+            "{} is already at version {} of the configuration format.",
+            path.display(),
+            CONFIG_VERSION
+        );
+    } else {
+        // Keep the version at the top of the file.
+        let mut migrated = serde_yaml::Mapping::new();
+        if let serde_yaml::Value::Mapping(mapping) = value.clone() {
+            migrated.insert("version".into(), CONFIG_VERSION.into());
+            migrated.extend(mapping.into_iter().filter(|(key, _)| key != "version"));
         }
 
-        // The values field is optional.
+        let backup = format!("{}.bak", path.display());
+        let err_msg = format!("Failed to write {}", backup);
+        fs::write(&backup, &content).context(err_msg)?;
+        let err_msg = format!("Failed to write {}", path.display());
+        let migrated = serde_yaml::to_string(&migrated)?;
+        fs::write(path, format!("---\n{}", migrated)).context(err_msg)?;
+
+        println!(
+            "Migrated {} from version {} to {}, the original was saved to {}",
+            path.display(),
+            version,
+            CONFIG_VERSION,
+            backup
+        );
     }
 
-    // Validate application.tests
-    for test in &config.application.tests {
-        // Ensure that the command field of each test is not empty.
-        let err_msg = "The 'command' field of all defined tests cannot be empty.".to_string();
-        if test.command.trim().is_empty() {
-            anyhow::bail!(err_msg);
-        }
+    // Migrate the included files as well.
+    let includes: Vec<String> = value
+        .get("include")
+        .and_then(|includes| serde_yaml::from_value(includes.clone()).ok())
+        .unwrap_or_default();
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+    for include in includes {
+        migrate_config(&base_dir.join(include).to_string_lossy())?;
     }
 
-    // Validate application.manifests
-    for manifest in &config.application.manifests {
-        // Ensure that the name field of each manifest is not empty.
-        let err_msg = "The 'name' field of all defined manifests cannot be empty.".to_string();
-        if manifest.name.trim().is_empty() {
-            anyhow::bail!(err_msg);
-        }
+    Ok(())
+}
 
-        // Ensure that either the url or dir field of each manifest is not empty.
-        let err_msg = format!(
-            "The 'url' and 'dir' field of {} cannot both be empty, at least one must be defined.",
-            manifest.name
-        );
-        if manifest.url.is_none() && manifest.dir.is_none() {
-            anyhow::bail!(err_msg);
+/// Merge an overlay into a base value.
+///
+/// Mappings are merged key by key. Lists of named entries, such as charts and
+/// manifests, are merged entry by entry on their `name`. Any other list, such
+/// as values files, args or depends_on, is replaced, as are scalars.
+///
+fn merge_values(base: &mut serde_yaml::Value, overlay: serde_yaml::Value) {
+    match (base, overlay) {
+        (serde_yaml::Value::Mapping(base), serde_yaml::Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_values(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
         }
+        (serde_yaml::Value::Sequence(base), serde_yaml::Value::Sequence(overlay))
+            if !overlay.is_empty() && base.iter().chain(overlay.iter()).all(is_named) =>
+        {
+            for item in overlay {
+                let existing = item.get("name").and_then(|name| {
+                    base.iter_mut()
+                        .find(|other| other.get("name") == Some(name))
+                });
+                match existing {
+                    Some(existing) => merge_values(existing, item),
+                    None => base.push(item),
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
     }
+}
 
-    // Validate application.helm.repositories
-    for repo in &config.application.helm.repositories {
-        // Ensure that the name field of each repository is not empty.
-        let err_msg = "The 'name' field of all defined repositories cannot be empty.".to_string();
-        if repo.name.trim().is_empty() {
-            anyhow::bail!(err_msg);
-        }
+/// Whether a list entry is a mapping with a `name`, so it can be merged by name.
+fn is_named(item: &serde_yaml::Value) -> bool {
+    item.as_mapping()
+        .is_some_and(|mapping| mapping.get("name").is_some_and(|name| name.is_string()))
+}
 
-        // Ensure that the url field of each repository is not empty.
-        let err_msg = format!("The 'url' field of {} cannot be empty", repo.name).to_string();
-        for repo in &config.application.helm.repositories {
-            if repo.url.trim().is_empty() {
-                anyhow::bail!(err_msg);
+/// Remove the list entries with `enabled: false`.
+///
+/// The manifests and charts that were removed and kept are recorded as "<kind>/<name>".
+fn remove_disabled(
+    value: &mut serde_yaml::Value,
+    key: &str,
+    disabled: &mut Vec<String>,
+    enabled: &mut Vec<String>,
+) {
+    match value {
+        serde_yaml::Value::Mapping(mapping) => {
+            for (child_key, child) in mapping.iter_mut() {
+                let child_key = child_key.as_str().unwrap_or_default();
+                remove_disabled(child, child_key, disabled, enabled);
             }
         }
+        serde_yaml::Value::Sequence(items) => {
+            let kind = match key {
+                "manifests" => "manifest",
+                "charts" => "chart",
+                _ => key,
+            };
+            items.retain(|item| {
+                let is_enabled = item
+                    .get("enabled")
+                    .and_then(|enabled| enabled.as_bool())
+                    .unwrap_or(true);
+                let name = item.get("name").and_then(|name| name.as_str());
+                let component = format!("{}/{}", kind, name.unwrap_or_default());
+                if is_enabled {
+                    enabled.push(component);
+                } else {
+                    disabled.push(component);
+                }
+                is_enabled
+            });
+            for item in items.iter_mut() {
+                if let serde_yaml::Value::Mapping(mapping) = item {
+                    mapping.remove("enabled");
+                }
+                remove_disabled(item, key, disabled, enabled);
+            }
+        }
+        _ => {}
     }
+}
 
-    // Validate application.helm.charts
-    for chart in &config.application.helm.charts {
-        // Ensure that the name field of each chart is not empty.
-        let err_msg = "The 'name' field of all defined charts cannot be empty.".to_string();
-        if chart.name.trim().is_empty() {
-            anyhow::bail!(err_msg);
-        }
+/// Remove the `depends_on` references to disabled manifests and charts.
+///
+/// A bare name is only removed when no enabled component has that name.
+fn remove_references(value: &mut serde_yaml::Value, disabled: &[String], enabled: &[String]) {
+    let refers_to = |component: &String, reference: &str| {
+        component == reference || component.split_once('/').map(|(_, name)| name) == Some(reference)
+    };
 
-        // Ensure that the repo field of each chart is not empty.
-        let err_msg =
-            format!("The 'repo' field of chart {} cannot be empty.", chart.name).to_string();
-        if chart.repo.trim().is_empty() {
-            anyhow::bail!(err_msg);
+    match value {
+        serde_yaml::Value::Mapping(mapping) => {
+            if let Some(serde_yaml::Value::Sequence(depends_on)) = mapping.get_mut("depends_on") {
+                depends_on.retain(|reference| {
+                    let reference = reference.as_str().unwrap_or_default();
+                    !disabled
+                        .iter()
+                        .any(|component| refers_to(component, reference))
+                        || enabled
+                            .iter()
+                            .any(|component| refers_to(component, reference))
+                });
+            }
+            for (_, child) in mapping.iter_mut() {
+                remove_references(child, disabled, enabled);
+            }
         }
+        serde_yaml::Value::Sequence(items) => {
+            for item in items {
+                remove_references(item, disabled, enabled);
+            }
+        }
+        _ => {}
+    }
+}
 
-        // The values field is optional.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(content: &str) -> serde_yaml::Value {
+        serde_yaml::from_str(content).unwrap()
     }
 
-    Ok(())
+    fn merged(base: &str, overlay: &str) -> serde_yaml::Value {
+        let mut base = yaml(base);
+        merge_values(&mut base, yaml(overlay));
+        base
+    }
+
+    #[test]
+    fn merge_mappings_key_by_key() {
+        assert_eq!(
+            merged("log: {level: info, file: a.log}", "log: {level: debug}"),
+            yaml("log: {level: debug, file: a.log}")
+        );
+    }
+
+    #[test]
+    fn merge_named_entries_by_name() {
+        assert_eq!(
+            merged(
+                "charts: [{name: a, repo: r}, {name: b, repo: r}]",
+                "charts: [{name: b, version: 1.0.0}, {name: c, repo: r}]"
+            ),
+            yaml(
+                "charts: [{name: a, repo: r}, {name: b, repo: r, version: 1.0.0}, {name: c, repo: r}]"
+            )
+        );
+    }
+
+    #[test]
+    fn merge_replaces_unnamed_lists() {
+        assert_eq!(
+            merged("values: [values.yaml]", "values: [values-kind.yaml]"),
+            yaml("values: [values-kind.yaml]")
+        );
+        assert_eq!(
+            merged(
+                "charts: [{name: a, args: [--x], depends_on: [b]}]",
+                "charts: [{name: a, args: [--y], depends_on: [c]}]"
+            ),
+            yaml("charts: [{name: a, args: [--y], depends_on: [c]}]")
+        );
+    }
+
+    #[test]
+    fn merge_replaces_mixed_lists() {
+        assert_eq!(
+            merged("urls: [{name: a}, b]", "urls: [{name: c}]"),
+            yaml("urls: [{name: c}]")
+        );
+    }
+
+    #[test]
+    fn merge_empty_list_clears() {
+        assert_eq!(
+            merged("charts: [{name: a}]", "charts: []"),
+            yaml("charts: []")
+        );
+    }
+
+    #[test]
+    fn merge_replaces_scalars_and_types() {
+        assert_eq!(merged("a: 1", "a: 2"), yaml("a: 2"));
+        assert_eq!(merged("a: {b: 1}", "a: [1]"), yaml("a: [1]"));
+    }
 }
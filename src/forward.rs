@@ -0,0 +1,345 @@
+//! Forward
+//!
+//! This module contains functions for forwarding local ports to Kubernetes Services and Pods.
+//!
+
+use crate::config::{Config, PortForward};
+
+use anyhow::{anyhow, Context, Result};
+use k8s_openapi::api::core::v1::{Pod, Service};
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+use kube::api::{Api, ListParams};
+use kube::Client;
+use log::{debug, error, info, warn};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// The number of times to look for a ready Pod before giving up on a connection.
+const POD_READY_ATTEMPTS: u32 = 30;
+
+/// The target of a port-forward.
+#[derive(Debug, Clone)]
+pub struct ForwardTarget {
+    /// The namespace of the target.
+    pub namespace: String,
+    /// Optional Service to forward to.
+    pub service: Option<String>,
+    /// Optional Pod to forward to.
+    pub pod: Option<String>,
+    /// The Service or Pod port to forward to.
+    pub port: u16,
+}
+
+impl ForwardTarget {
+    /// Create a target from a configured port-forward.
+    ///
+    /// The namespace defaults to the provided namespace when it is not set.
+    pub fn from_config(port_forward: &PortForward, default_namespace: &str) -> Self {
+        Self {
+            namespace: port_forward
+                .namespace
+                .clone()
+                .unwrap_or_else(|| default_namespace.to_string()),
+            service: port_forward.service.clone(),
+            pod: port_forward.pod.clone(),
+            port: port_forward.remote_port,
+        }
+    }
+}
+
+impl std::fmt::Display for ForwardTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.service, &self.pod) {
+            (Some(service), _) => write!(f, "svc/{}:{} in {}", service, self.port, self.namespace),
+            (None, Some(pod)) => write!(f, "pod/{}:{} in {}", pod, self.port, self.namespace),
+            (None, None) => write!(f, "<none>:{} in {}", self.port, self.namespace),
+        }
+    }
+}
+
+/// A scoped temporary port-forward.
+///
+/// The port-forward is stopped when this value is dropped.
+pub struct ForwardHandle {
+    /// The local port that is being forwarded.
+    pub local_port: u16,
+    /// The task accepting connections on the local port.
+    task: JoinHandle<()>,
+}
+
+impl Drop for ForwardHandle {
+    fn drop(&mut self) {
+        debug!(
+            "Stopping temporary port-forward on port {}",
+            self.local_port
+        );
+        self.task.abort();
+    }
+}
+
+/// Forward temporary.
+///
+/// Starts a temporary port-forward to the target on a random local port.
+///
+/// # Arguments
+///
+/// * `target` - The Service or Pod to forward to
+///
+/// # Returns
+///
+/// A `ForwardHandle` that stops the port-forward when dropped.
+///
+pub async fn forward_temporary(target: ForwardTarget) -> Result<ForwardHandle> {
+    let client = Client::try_default().await?;
+
+    let err_msg = "Failed to bind a local port for the port-forward".to_string();
+    let listener = TcpListener::bind("127.0.0.1:0").await.context(err_msg)?;
+    let local_port = listener.local_addr()?.port();
+
+    info!("Forwarding 127.0.0.1:{} to {}", local_port, target);
+
+    let task = tokio::spawn(async move {
+        if let Err(e) = forward_listener(client, listener, target).await {
+            error!("Temporary port-forward failed: {}", e);
+        }
+    });
+
+    Ok(ForwardHandle { local_port, task })
+}
+
+/// Forward.
+///
+/// Forwards a local port to the target until the task is cancelled.
+///
+/// # Arguments
+///
+/// * `target` - The Service or Pod to forward to
+/// * `local_port` - The local port to listen on
+///
+pub async fn forward(target: ForwardTarget, local_port: u16) -> Result<()> {
+    let client = Client::try_default().await?;
+
+    let address = SocketAddr::from(([127, 0, 0, 1], local_port));
+    let err_msg = format!("Failed to listen on {}", address);
+    let listener = TcpListener::bind(address).await.context(err_msg)?;
+
+    println!("Forwarding {} -> {}", address, target);
+    info!("Forwarding {} to {}", address, target);
+
+    forward_listener(client, listener, target).await
+}
+
+/// Forward listener.
+///
+/// Accepts connections on the listener and forwards each to the target.
+/// The target Pod is resolved for every connection, so that forwarding
+/// reconnects to the replacement when a Pod restarts.
+///
+async fn forward_listener(
+    client: Client,
+    listener: TcpListener,
+    target: ForwardTarget,
+) -> Result<()> {
+    loop {
+        let (connection, peer) = listener.accept().await?;
+        debug!("Accepted connection from {} for {}", peer, target);
+
+        let client = client.clone();
+        let target = target.clone();
+        tokio::spawn(async move {
+            if let Err(e) = forward_connection(client, connection, &target).await {
+                warn!("Port-forward connection to {} failed: {}", target, e);
+            }
+        });
+    }
+}
+
+/// Forward a single connection.
+///
+/// A helper function for forward_listener.
+///
+async fn forward_connection(
+    client: Client,
+    mut connection: TcpStream,
+    target: &ForwardTarget,
+) -> Result<()> {
+    let (pod_name, pod_port) = forward_resolve(&client, target).await?;
+    debug!("Forwarding connection to pod/{}:{}", pod_name, pod_port);
+
+    let pods: Api<Pod> = Api::namespaced(client, &target.namespace);
+    let mut forwarder = pods.portforward(&pod_name, &[pod_port]).await?;
+    let mut upstream = forwarder
+        .take_stream(pod_port)
+        .ok_or_else(|| anyhow!("Failed to open a stream to pod/{}", pod_name))?;
+
+    tokio::io::copy_bidirectional(&mut connection, &mut upstream).await?;
+
+    drop(upstream);
+    forwarder.join().await?;
+
+    Ok(())
+}
+
+/// Resolve the Pod and port to forward to.
+///
+/// For a Service, a ready Pod matching its selector is chosen and the
+/// Service port is translated to the target container port. Waits for a
+/// ready Pod to allow for restarts.
+///
+async fn forward_resolve(client: &Client, target: &ForwardTarget) -> Result<(String, u16)> {
+    let pods: Api<Pod> = Api::namespaced(client.clone(), &target.namespace);
+
+    if let Some(pod) = &target.pod {
+        return Ok((pod.to_owned(), target.port));
+    }
+
+    let service_name = target
+        .service
+        .as_deref()
+        .ok_or_else(|| anyhow!("A Service or Pod is required to port-forward"))?;
+
+    let services: Api<Service> = Api::namespaced(client.clone(), &target.namespace);
+    let err_msg = format!("Failed to get Service {}", service_name);
+    let service = services.get(service_name).await.context(err_msg)?;
+    let spec = service
+        .spec
+        .ok_or_else(|| anyhow!("Service {} has no spec", service_name))?;
+
+    let selector = spec
+        .selector
+        .unwrap_or_default()
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(",");
+    if selector.is_empty() {
+        return Err(anyhow!("Service {} has no selector", service_name));
+    }
+
+    let target_port = spec
+        .ports
+        .unwrap_or_default()
+        .into_iter()
+        .find(|port| port.port == i32::from(target.port))
+        .ok_or_else(|| anyhow!("Service {} has no port {}", service_name, target.port))?
+        .target_port;
+
+    for attempt in 1..=POD_READY_ATTEMPTS {
+        let pod_list = pods.list(&ListParams::default().labels(&selector)).await?;
+
+        if let Some(pod) = pod_list.items.into_iter().find(pod_is_ready) {
+            let pod_name = pod.metadata.name.clone().unwrap_or_default();
+            let pod_port = match &target_port {
+                Some(IntOrString::Int(port)) => u16::try_from(*port)?,
+                Some(IntOrString::String(name)) => pod_named_port(&pod, name)
+                    .ok_or_else(|| anyhow!("Pod {} has no port named {}", pod_name, name))?,
+                None => target.port,
+            };
+            return Ok((pod_name, pod_port));
+        }
+
+        debug!(
+            "No ready Pod for Service {} yet, attempt {} of {}",
+            service_name, attempt, POD_READY_ATTEMPTS
+        );
+        tokio::time::sleep(Duration::from_secs(2)).await;
+    }
+
+    Err(anyhow!(
+        "No ready Pod was found for Service {}",
+        service_name
+    ))
+}
+
+/// Check if a Pod is running, ready and not terminating.
+fn pod_is_ready(pod: &Pod) -> bool {
+    if pod.metadata.deletion_timestamp.is_some() {
+        return false;
+    }
+
+    pod.status
+        .as_ref()
+        .and_then(|status| status.conditions.as_ref())
+        .map(|conditions| {
+            conditions
+                .iter()
+                .any(|condition| condition.type_ == "Ready" && condition.status == "True")
+        })
+        .unwrap_or(false)
+}
+
+/// Find the container port number for a named port on a Pod.
+fn pod_named_port(pod: &Pod, name: &str) -> Option<u16> {
+    pod.spec
+        .as_ref()?
+        .containers
+        .iter()
+        .flat_map(|container| container.ports.iter().flatten())
+        .find(|port| port.name.as_deref() == Some(name))
+        .and_then(|port| u16::try_from(port.container_port).ok())
+}
+
+/// Forward configured.
+///
+/// Forwards all port-forwards defined in the configuration, both the
+/// top-level `port_forwards` section and those declared per chart,
+/// until the user presses Ctrl-C.
+///
+/// # Arguments
+///
+/// * `config` - The loaded configuration
+/// * `names` - Only forward the port-forwards with these names, or all if empty
+///
+pub async fn forward_configured(config: &Config, names: &[String]) -> Result<()> {
+    let mut forwards: Vec<(String, ForwardTarget, u16)> = Vec::new();
+
+    for port_forward in config.port_forwards.iter().flatten() {
+        let target = ForwardTarget::from_config(port_forward, "default");
+        forwards.push((port_forward.name.clone(), target, port_forward.local_port));
+    }
+
+    let charts = config
+        .dependencies
+        .helm
+        .charts
+        .iter()
+        .chain(config.application.helm.charts.iter());
+    for chart in charts {
+        let namespace = chart.namespace();
+        for port_forward in chart.port_forwards.iter().flatten() {
+            let target = ForwardTarget::from_config(port_forward, namespace);
+            forwards.push((port_forward.name.clone(), target, port_forward.local_port));
+        }
+    }
+
+    if !names.is_empty() {
+        forwards.retain(|(name, _, _)| names.contains(name));
+    }
+
+    if forwards.is_empty() {
+        println!("No port-forwards were found in the configuration file. Skipping...");
+        return Ok(());
+    }
+
+    let mut tasks = Vec::new();
+    for (name, target, local_port) in forwards {
+        tasks.push(tokio::spawn(async move {
+            if let Err(e) = forward(target, local_port).await {
+                error!("Port-forward {} failed: {}", name, e);
+                eprintln!("Port-forward {} failed: {}", name, e);
+            }
+        }));
+    }
+
+    println!("Press Ctrl-C to stop forwarding.");
+    tokio::signal::ctrl_c().await?;
+
+    for task in tasks {
+        task.abort();
+    }
+    println!("Stopped all port-forwards.");
+
+    Ok(())
+}
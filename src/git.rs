@@ -0,0 +1,98 @@
+//! Git
+//!
+//! This module contains functions for checking out git repositories
+//! that charts and manifests are installed from.
+//!
+
+use crate::cache;
+use crate::utils::run_command;
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// The directory where git repositories are checked out.
+const GIT_CACHE_DIR: &str = ".loopy/cache/git";
+
+/// Serializes checkouts, as concurrent components may use the same repository.
+static CHECKOUT: OnceLock<tokio::sync::Mutex<()>> = OnceLock::new();
+
+/// Git checkout.
+///
+/// Fetches a branch, tag or commit of a git repository into the cache
+/// and checks it out. Only the requested revision is fetched.
+///
+/// # Arguments
+///
+/// * `url` - The URL of the git repository
+/// * `reference` - The branch, tag or commit to check out, or the default branch if None
+///
+/// # Returns
+///
+/// The path to the checked out repository.
+///
+pub async fn git_checkout(url: &str, reference: Option<&str>) -> Result<PathBuf> {
+    let _guard = CHECKOUT
+        .get_or_init(|| tokio::sync::Mutex::new(()))
+        .lock()
+        .await;
+
+    let reference = reference.unwrap_or("HEAD");
+    let dir_name: String = format!("{}-{}", url, reference)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    let dir = Path::new(GIT_CACHE_DIR).join(dir_name);
+    let dir_str = dir.to_string_lossy().to_string();
+
+    // Offline, an earlier checkout is used as it is.
+    if cache::is_offline() {
+        if dir.join(".git").exists() {
+            info!("Using cached checkout of {} at {}", url, reference);
+            return Ok(dir);
+        }
+        return Err(anyhow!(
+            "{} at {} is not checked out, run once without --offline to fetch it",
+            url,
+            reference
+        ));
+    }
+
+    if !dir.join(".git").exists() {
+        debug!("Initialising git checkout of {} in {}", url, dir_str);
+        let err_msg = format!("Failed to create git checkout directory '{}'", dir_str);
+        std::fs::create_dir_all(&dir).context(err_msg)?;
+        git(&["init", "--quiet", &dir_str]).await?;
+        git(&["-C", &dir_str, "remote", "add", "origin", url]).await?;
+    }
+
+    info!("Fetching {} at {}", url, reference);
+    git(&["-C", &dir_str, "fetch", "--depth", "1", "origin", reference])
+        .await
+        .with_context(|| format!("Failed to fetch '{}' from {}", reference, url))?;
+    git(&[
+        "-C",
+        &dir_str,
+        "checkout",
+        "--quiet",
+        "--force",
+        "FETCH_HEAD",
+    ])
+    .await?;
+
+    Ok(dir)
+}
+
+/// Run a git command, returning an error if it fails.
+async fn git(args: &[&str]) -> Result<()> {
+    let (stdout, stderr, status) = run_command("git", args).await?;
+    debug!("stdout: {}", stdout);
+    debug!("stderr: {}", stderr);
+
+    if status.code() != Some(0) {
+        return Err(anyhow!("git {} failed: {}", args.join(" "), stderr.trim()));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,528 @@
+//! Graph
+//!
+//! This module builds the dependency graph of the manifests and charts of each
+//! phase from their `depends_on` fields, and processes independent components
+//! concurrently.
+//!
+//! Each running component is a tokio task that owns a copy of its manifest set
+//! or chart, and commands run on the blocking thread pool, so a slow command
+//! never holds up the other components.
+//!
+//! A component may depend on components of its own phase or of an earlier phase.
+//! Earlier phases are always complete before a later phase starts, so those
+//! dependencies are already satisfied.
+//!
+
+use crate::config::{Chart, Config, Manifests};
+use crate::logger;
+use crate::signal;
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, error};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// The default number of components processed at the same time.
+pub const DEFAULT_PARALLELISM: usize = 4;
+
+/// A manifest or chart in the dependency graph.
+#[derive(Debug, Clone, Copy)]
+pub enum Component<'a> {
+    Manifest(&'a Manifests),
+    Chart(&'a Chart),
+}
+
+impl<'a> Component<'a> {
+    /// The kind of component, "manifest" or "chart".
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Component::Manifest(_) => "manifest",
+            Component::Chart(_) => "chart",
+        }
+    }
+
+    /// The name of the component.
+    pub fn name(&self) -> &'a str {
+        match self {
+            Component::Manifest(manifest) => &manifest.name,
+            Component::Chart(chart) => &chart.name,
+        }
+    }
+
+    /// The components this component depends on.
+    fn depends_on(&self) -> &'a [String] {
+        let depends_on = match self {
+            Component::Manifest(manifest) => &manifest.depends_on,
+            Component::Chart(chart) => &chart.depends_on,
+        };
+        depends_on.as_deref().unwrap_or_default()
+    }
+
+    /// Check if a `depends_on` entry refers to this component.
+    ///
+    /// An entry is either "<kind>/<name>" or a bare name, which refers to
+    /// every other component with that name.
+    fn is_referred_by(&self, reference: &str, referrer: Option<&Component>) -> bool {
+        if reference == format!("{}/{}", self.kind(), self.name()) {
+            return true;
+        }
+        let is_referrer = referrer.is_some_and(|referrer| {
+            referrer.kind() == self.kind() && referrer.name() == self.name()
+        });
+        reference == self.name() && !is_referrer
+    }
+}
+
+impl Component<'_> {
+    /// A copy of the component that a task can own.
+    pub fn owned(&self) -> OwnedComponent {
+        match self {
+            Component::Manifest(manifest) => {
+                OwnedComponent::Manifest(Box::new((*manifest).clone()))
+            }
+            Component::Chart(chart) => OwnedComponent::Chart(Box::new((*chart).clone())),
+        }
+    }
+}
+
+impl std::fmt::Display for Component<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.kind(), self.name())
+    }
+}
+
+/// A manifest or chart owned by the task that processes it.
+#[derive(Debug, Clone)]
+pub enum OwnedComponent {
+    Manifest(Box<Manifests>),
+    Chart(Box<Chart>),
+}
+
+/// The dependency graph of the manifests and charts of a phase.
+#[derive(Debug)]
+pub struct Graph<'a> {
+    /// The components of the phase, manifests first, in configuration order.
+    pub components: Vec<Component<'a>>,
+    /// The indices of the components each component depends on.
+    dependencies: Vec<Vec<usize>>,
+    /// The indices of the components that depend on each component.
+    dependents: Vec<Vec<usize>>,
+}
+
+impl<'a> Graph<'a> {
+    /// Build the graph of a phase.
+    ///
+    /// # Arguments
+    ///
+    /// * `manifests` - The manifests of the phase
+    /// * `charts` - The charts of the phase
+    /// * `earlier` - The graph of the earlier phase, if any
+    ///
+    /// # Returns
+    ///
+    /// An error if a `depends_on` entry doesn't refer to any component, or the graph has a cycle.
+    ///
+    pub fn new(
+        manifests: &'a [Manifests],
+        charts: &'a [Chart],
+        earlier: Option<&Graph<'a>>,
+    ) -> Result<Self> {
+        let components: Vec<Component<'a>> = manifests
+            .iter()
+            .map(Component::Manifest)
+            .chain(charts.iter().map(Component::Chart))
+            .collect();
+
+        let mut dependencies = vec![Vec::new(); components.len()];
+        let mut dependents = vec![Vec::new(); components.len()];
+
+        for (index, component) in components.iter().enumerate() {
+            for reference in component.depends_on() {
+                let matches: Vec<usize> = components
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, other)| other.is_referred_by(reference, Some(component)))
+                    .map(|(other, _)| other)
+                    .collect();
+
+                let in_earlier = earlier.is_some_and(|earlier| {
+                    earlier
+                        .components
+                        .iter()
+                        .any(|other| other.is_referred_by(reference, None))
+                });
+
+                if matches.is_empty() && !in_earlier {
+                    return Err(anyhow!(
+                        "The {} depends on '{}', which is not a manifest or chart of this phase or an earlier one.",
+                        component,
+                        reference
+                    ));
+                }
+
+                for other in matches {
+                    if !dependencies[index].contains(&other) {
+                        dependencies[index].push(other);
+                        dependents[other].push(index);
+                    }
+                }
+            }
+        }
+
+        let graph = Self {
+            components,
+            dependencies,
+            dependents,
+        };
+
+        // Fail early if the graph can't be processed.
+        graph.stages(false)?;
+
+        Ok(graph)
+    }
+
+    /// Check if the graph has no components.
+    pub fn is_empty(&self) -> bool {
+        self.components.is_empty()
+    }
+
+    /// The edges to follow, reversed when removing components.
+    fn edges(&self, reverse: bool) -> (&[Vec<usize>], &[Vec<usize>]) {
+        if reverse {
+            (&self.dependents, &self.dependencies)
+        } else {
+            (&self.dependencies, &self.dependents)
+        }
+    }
+
+    /// Group the components into stages.
+    ///
+    /// Every component of a stage only depends on components of earlier stages.
+    ///
+    /// # Arguments
+    ///
+    /// * `reverse` - Order dependents before their dependencies, as for uninstall
+    ///
+    pub fn stages(&self, reverse: bool) -> Result<Vec<Vec<Component<'a>>>> {
+        let (waits_on, unblocks) = self.edges(reverse);
+        let mut pending: Vec<usize> = waits_on.iter().map(Vec::len).collect();
+        let mut stage: Vec<usize> = (0..self.components.len())
+            .filter(|&index| pending[index] == 0)
+            .collect();
+
+        let mut stages = Vec::new();
+        let mut processed = 0;
+        while !stage.is_empty() {
+            processed += stage.len();
+            let mut next = Vec::new();
+            for &index in &stage {
+                for &other in &unblocks[index] {
+                    pending[other] -= 1;
+                    if pending[other] == 0 {
+                        next.push(other);
+                    }
+                }
+            }
+            next.sort_unstable();
+            stages.push(stage.iter().map(|&index| self.components[index]).collect());
+            stage = next;
+        }
+
+        if processed < self.components.len() {
+            let cycle: Vec<String> = (0..self.components.len())
+                .filter(|&index| pending[index] > 0)
+                .map(|index| self.components[index].to_string())
+                .collect();
+            return Err(anyhow!(
+                "The depends_on fields form a cycle between: {}",
+                cycle.join(", ")
+            ));
+        }
+
+        Ok(stages)
+    }
+
+    /// Process the components of the graph.
+    ///
+    /// Each component starts once everything it depends on has completed, with at
+    /// most `parallelism` components running at the same time. When a component
+    /// fails or the user presses Ctrl-C, no new components are started and the
+    /// running ones are allowed to finish.
+    ///
+    /// # Arguments
+    ///
+    /// * `reverse` - Process dependents before their dependencies, as for uninstall
+    /// * `parallelism` - The maximum number of components processed at the same time
+    /// * `process` - The function that processes a single component
+    ///
+    pub async fn process<F, Fut>(&self, reverse: bool, parallelism: usize, process: F) -> Result<()>
+    where
+        F: Fn(OwnedComponent) -> Fut,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let (waits_on, unblocks) = self.edges(reverse);
+        let mut pending: Vec<usize> = waits_on.iter().map(Vec::len).collect();
+        let mut ready: VecDeque<usize> = (0..self.components.len())
+            .filter(|&index| pending[index] == 0)
+            .collect();
+
+        let permits = Arc::new(Semaphore::new(parallelism.max(1)));
+        let context = logger::current();
+        let mut running = JoinSet::new();
+        let mut first_error: Option<anyhow::Error> = None;
+
+        loop {
+            while first_error.is_none() && !signal::interrupted() {
+                let Some(index) = ready.pop_front() else {
+                    break;
+                };
+                let permit = permits
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .context("Failed to wait for a running component")?;
+
+                let component = self.components[index];
+                debug!("Starting {}", component);
+                let future = process(component.owned());
+                running.spawn(logger::scope(context.clone(), async move {
+                    let result = future.await;
+                    drop(permit);
+                    (index, result)
+                }));
+            }
+
+            let Some(joined) = running.join_next().await else {
+                break;
+            };
+            let (index, result) = match joined {
+                Ok(joined) => joined,
+                Err(e) => {
+                    let e = anyhow!("A component failed to run: {}", e);
+                    if first_error.is_none() {
+                        first_error = Some(e);
+                    } else {
+                        error!("{:#}", e);
+                    }
+                    continue;
+                }
+            };
+
+            match result {
+                Ok(()) => {
+                    for &other in &unblocks[index] {
+                        pending[other] -= 1;
+                        if pending[other] == 0 {
+                            ready.push_back(other);
+                        }
+                    }
+                }
+                Err(e) if first_error.is_none() => first_error = Some(e),
+                Err(e) => error!("{:#}", e),
+            }
+        }
+
+        if let Some(e) = first_error {
+            return Err(e);
+        }
+
+        signal::check()
+    }
+}
+
+/// Build the graphs of the dependencies and application phases.
+///
+/// # Arguments
+///
+/// * `config` - The loaded configuration
+///
+pub fn phase_graphs(config: &Config) -> Result<(Graph<'_>, Graph<'_>)> {
+    let dependencies = Graph::new(
+        &config.dependencies.manifests,
+        &config.dependencies.helm.charts,
+        None,
+    )?;
+    let application = Graph::new(
+        &config.application.manifests,
+        &config.application.helm.charts,
+        Some(&dependencies),
+    )?;
+
+    Ok((dependencies, application))
+}
+
+/// Print the execution plan.
+///
+/// Shows the order in which each phase processes its repositories,
+/// manifests, charts and tests, without changing anything.
+///
+/// # Arguments
+///
+/// * `action` - The action to plan (install or uninstall)
+/// * `config` - The loaded configuration
+///
+pub fn print_plan(action: &str, config: &Config) -> Result<()> {
+    let (dependencies, application) = phase_graphs(config)?;
+    let reverse = action == "uninstall";
+    let parallelism = config.parallelism.unwrap_or(DEFAULT_PARALLELISM);
+
+    println!(
+        "Execution plan for {}, up to {} components at a time:",
+        action, parallelism
+    );
+
+    let phases = [
+        (
+            "dependencies",
+            &config.dependencies.helm,
+            &config.dependencies.tests,
+            dependencies,
+        ),
+        (
+            "application",
+            &config.application.helm,
+            &config.application.tests,
+            application,
+        ),
+    ];
+    for (phase, helm, tests, graph) in phases {
+        println!();
+        println!("{}:", phase);
+
+        if !helm.repositories.is_empty() {
+            let names: Vec<&str> = helm
+                .repositories
+                .iter()
+                .map(|repo| repo.name.as_str())
+                .collect();
+            println!("  repositories: {}", names.join(", "));
+        }
+
+        for (number, stage) in graph.stages(reverse)?.iter().enumerate() {
+            let names: Vec<String> = stage
+                .iter()
+                .map(|component| component.to_string())
+                .collect();
+            println!("  stage {}: {}", number + 1, names.join(", "));
+        }
+
+        if !tests.is_empty() {
+            let names: Vec<&str> = tests.iter().map(|test| test.label()).collect();
+            println!("  tests: {}", names.join(", "));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifests(content: &str) -> Vec<Manifests> {
+        serde_yaml::from_str(content).unwrap()
+    }
+
+    fn charts(content: &str) -> Vec<Chart> {
+        serde_yaml::from_str(content).unwrap()
+    }
+
+    fn names(stages: Vec<Vec<Component>>) -> Vec<Vec<String>> {
+        stages
+            .iter()
+            .map(|stage| {
+                stage
+                    .iter()
+                    .map(|component| component.to_string())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stages_follow_dependencies() {
+        let manifests = manifests("[{name: crds}]");
+        let charts = charts(
+            "[{name: app, depends_on: [db]}, {name: db, depends_on: [crds]}, {name: other}]",
+        );
+        let graph = Graph::new(&manifests, &charts, None).unwrap();
+
+        assert_eq!(
+            names(graph.stages(false).unwrap()),
+            vec![
+                vec!["manifest/crds", "chart/other"],
+                vec!["chart/db"],
+                vec!["chart/app"],
+            ]
+        );
+    }
+
+    #[test]
+    fn reverse_stages_remove_dependents_first() {
+        let manifests = manifests("[{name: crds}]");
+        let charts = charts("[{name: app, depends_on: [db]}, {name: db, depends_on: [crds]}]");
+        let graph = Graph::new(&manifests, &charts, None).unwrap();
+
+        assert_eq!(
+            names(graph.stages(true).unwrap()),
+            vec![vec!["chart/app"], vec!["chart/db"], vec!["manifest/crds"]]
+        );
+    }
+
+    #[test]
+    fn cycles_are_an_error() {
+        let charts = charts("[{name: a, depends_on: [b]}, {name: b, depends_on: [a]}, {name: c}]");
+        let e = Graph::new(&[], &charts, None).unwrap_err();
+
+        assert_eq!(
+            e.to_string(),
+            "The depends_on fields form a cycle between: chart/a, chart/b"
+        );
+    }
+
+    #[test]
+    fn unknown_references_are_an_error() {
+        let charts = charts("[{name: app, depends_on: [missing]}]");
+        let e = Graph::new(&[], &charts, None).unwrap_err();
+
+        assert!(e.to_string().contains("The chart/app depends on 'missing'"));
+    }
+
+    #[test]
+    fn bare_names_refer_to_the_other_component_with_that_name() {
+        let manifests = manifests("[{name: db}]");
+        let charts = charts("[{name: db, depends_on: [db]}]");
+        let graph = Graph::new(&manifests, &charts, None).unwrap();
+
+        assert_eq!(
+            names(graph.stages(false).unwrap()),
+            vec![vec!["manifest/db"], vec!["chart/db"]]
+        );
+    }
+
+    #[test]
+    fn kind_prefixed_names_refer_to_one_component() {
+        let manifests = manifests("[{name: db, depends_on: [chart/db]}]");
+        let charts = charts("[{name: db}, {name: app, depends_on: [manifest/db]}]");
+        let graph = Graph::new(&manifests, &charts, None).unwrap();
+
+        assert_eq!(
+            names(graph.stages(false).unwrap()),
+            vec![vec!["chart/db"], vec!["manifest/db"], vec!["chart/app"]]
+        );
+    }
+
+    #[test]
+    fn earlier_phases_satisfy_references() {
+        let dependencies = charts("[{name: db}]");
+        let earlier = Graph::new(&[], &dependencies, None).unwrap();
+        let application = charts("[{name: app, depends_on: [chart/db]}]");
+
+        let graph = Graph::new(&[], &application, Some(&earlier)).unwrap();
+        assert_eq!(names(graph.stages(false).unwrap()), vec![vec!["chart/app"]]);
+        assert!(Graph::new(&[], &application, None).is_err());
+    }
+}
@@ -3,15 +3,38 @@
 //! This module contains functions for installing and uninstalling Helm charts and repositories.
 //!
 
-use crate::config::{Chart, Repository};
-use crate::utils::run_command;
+use crate::cache;
+use crate::changes;
+use crate::config::{Chart, HelmTest, Registry, Repository};
+use crate::git::git_checkout;
+use crate::logger;
+use crate::secrets;
+use crate::signal;
+use crate::state;
+use crate::store;
+use crate::template;
+use crate::utils::{
+    hash_content, run_command, run_command_streaming, run_command_streaming_with_input,
+    run_command_with_input,
+};
+use crate::values;
+use crate::PACKAGE_NAME;
 
 use anyhow::{Context, Result};
-use log::{debug, error, info};
+use chrono::Local;
+use log::{debug, error, info, warn};
 use reqwest::StatusCode;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Write;
 use std::path::Path;
+use std::sync::Mutex;
+
+/// The directory where pulled charts are cached.
+const CHART_CACHE_DIR: &str = ".loopy/cache/charts";
+
+/// Extra helm flags for charts pulled from each configured OCI registry host.
+static REGISTRY_FLAGS: Mutex<BTreeMap<String, Vec<&'static str>>> = Mutex::new(BTreeMap::new());
 
 /// Manage helm repositories.
 ///
@@ -41,7 +64,7 @@ pub async fn helm_repo(action: &str, name: Option<&str>, url: Option<&str>) -> R
 
         "uninstall" => {
             if let Some(repo_name) = name {
-                helm_uninstall_repo(repo_name)
+                helm_uninstall_repo(repo_name).await
             } else {
                 Err(anyhow::anyhow!(
                     "Name is required for uninstalling a Helm repo"
@@ -49,7 +72,7 @@ pub async fn helm_repo(action: &str, name: Option<&str>, url: Option<&str>) -> R
             }
         }
 
-        "update" => helm_update_repos(),
+        "update" => helm_update_repos().await,
 
         _ => Err(anyhow::anyhow!(
             "Invalid action, only 'install', 'uninstall', or 'update' are allowed"
@@ -75,9 +98,17 @@ pub async fn helm_repo(action: &str, name: Option<&str>, url: Option<&str>) -> R
 async fn helm_install_repo(name: &str, url: &str) -> Result<()> {
     info!("Installing Helm repo: {}", name);
 
+    // Offline, the repository added by an earlier run is used as it is.
+    if cache::is_offline() {
+        info!("Offline, skipping adding Helm repo '{}'", name);
+        return Ok(());
+    }
+
     // Check if the helm repo already exists
     let err_msg = "Failed to list Helm repositories".to_string();
-    let (stdout, stderr, status) = run_command("helm", &["repo", "list"]).expect(&err_msg);
+    let (stdout, stderr, status) = run_command("helm", &["repo", "list"])
+        .await
+        .expect(&err_msg);
 
     if stderr.contains("Error: no repositories to show") && status.code() == Some(1) {
         // If the command failed with 'Error: no repositories to show',
@@ -120,8 +151,9 @@ async fn helm_install_repo(name: &str, url: &str) -> Result<()> {
 
     // Add the Helm repo
     let err_msg = format!("Failed to add Helm repository '{}'", name);
-    let (stdout, stderr, status) =
-        run_command("helm", &["repo", "add", name, url]).context(err_msg)?;
+    let (stdout, stderr, status) = run_command("helm", &["repo", "add", name, url])
+        .await
+        .context(err_msg)?;
 
     // Check the status code of the command.
     if status.code() == Some(0) {
@@ -141,6 +173,28 @@ async fn helm_install_repo(name: &str, url: &str) -> Result<()> {
     Ok(())
 }
 
+/// Merge a layer of Helm values into the values below it
+///
+/// Maps are merged key by key, any other value replaces the value below it,
+/// which matches how helm layers values files.
+///
+pub fn helm_merge_values(base: &mut serde_yaml::Value, layer: serde_yaml::Value) {
+    match (base, layer) {
+        (serde_yaml::Value::Mapping(base), serde_yaml::Value::Mapping(layer)) => {
+            for (key, value) in layer {
+                match base.get_mut(&key) {
+                    Some(existing) => helm_merge_values(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (_, serde_yaml::Value::Null) => {}
+        (base, layer) => *base = layer,
+    }
+}
+
 /// Uninstall the given Helm chart release
 ///
 /// # Arguments
@@ -155,11 +209,13 @@ async fn helm_install_repo(name: &str, url: &str) -> Result<()> {
 /// assert!(result.is_ok());
 /// ```
 ///
-fn helm_uninstall_repo(name: &str) -> Result<()> {
+async fn helm_uninstall_repo(name: &str) -> Result<()> {
     info!("Uninstalling Helm repo: {}", name);
 
     let err_msg = "Failed to list Helm repositories".to_string();
-    let (_stdout, stderr, status) = run_command("helm", &["repo", "list"]).expect(&err_msg);
+    let (_stdout, stderr, status) = run_command("helm", &["repo", "list"])
+        .await
+        .expect(&err_msg);
 
     if stderr.contains("Error: no repositories to show") && status.code() == Some(1) {
         // If the command failed with 'Error: no repositories to show',
@@ -178,12 +234,16 @@ fn helm_uninstall_repo(name: &str) -> Result<()> {
 
     // Check if the helm repo exists.
     let err_msg = "Failed to list Helm repositories".to_string();
-    let (stdout, _stderr, status) = run_command("helm", &["repo", "list"]).expect(&err_msg);
+    let (stdout, _stderr, status) = run_command("helm", &["repo", "list"])
+        .await
+        .expect(&err_msg);
     if stdout.contains(name) && status.code() == Some(0) {
         // If the helm repo exists, remove it.
         debug!("Removing Helm repo: {}", name);
         let err_msg = format!("Failed to remove Helm repository '{}'", name);
-        run_command("helm", &["repo", "remove", name]).context(err_msg)?;
+        run_command("helm", &["repo", "remove", name])
+            .await
+            .context(err_msg)?;
     } else {
         debug!("Helm repo {} is not installed, skipping", name);
     }
@@ -201,12 +261,14 @@ fn helm_uninstall_repo(name: &str) -> Result<()> {
 /// assert!(result.is_ok());
 ///```
 ///
-fn helm_update_repos() -> Result<()> {
+async fn helm_update_repos() -> Result<()> {
     info!("Updating Helm repos");
 
     // Run the helm repo update command
     let err_msg = "Failed to update Helm repositories".to_string();
-    let (stdout, stderr, status) = run_command("helm", &["repo", "update"]).context(err_msg)?;
+    let (stdout, stderr, status) = run_command("helm", &["repo", "update"])
+        .await
+        .context(err_msg)?;
 
     if stderr.contains("Error: no repositories found. You must add one before updating")
         && status.code() == Some(1)
@@ -235,21 +297,19 @@ fn helm_update_repos() -> Result<()> {
 /// # Arguments
 ///
 /// * `action` - The action to perform, either 'install' or 'uninstall'
-/// * `chart` - A Helm Chart struct with the name, repo and optional values filename.
+/// * `chart` - A Helm Chart struct with the name, repo and optional release settings.
 ///
-pub fn helm_chart(action: &str, chart: &Chart) -> Result<()> {
+pub async fn helm_chart(action: &str, chart: &Chart) -> Result<()> {
     match action {
-        "install" => {
-            // Use chart.namespace if set, otherwise use chart.name as the default.
-            let namespace = chart.namespace.as_ref().unwrap_or(&chart.name);
+        "install" => helm_install_chart(chart).await.map(|_| ()),
 
-            // Call helm_install_chart with the provided values or None if not set.
-            helm_install_chart(&chart.name, &chart.repo, namespace, chart.values.as_deref())
+        "uninstall" => {
+            helm_uninstall_chart(chart.release(), chart.namespace()).await?;
+            store::remove("chart", &chart.name);
+            Ok(())
         }
 
-        "uninstall" => helm_uninstall_chart(&chart.name),
-
-        "prepare" => helm_prepare_chart(&chart.name, &chart.repo),
+        "prepare" => helm_prepare_chart(chart, &helm_chart_ref(chart).await?).await,
 
         _ => Err(anyhow::anyhow!(
             "Invalid action, only 'install', 'uninstall', or 'prepare' are allowed"
@@ -257,41 +317,322 @@ pub fn helm_chart(action: &str, chart: &Chart) -> Result<()> {
     }
 }
 
+/// Log in to the configured OCI registries
+///
+/// Registries without a user name are not logged in to, but their
+/// TLS and plain HTTP settings are still applied to the charts pulled from them.
+/// The password is passed on stdin, so it never appears in the command line or transcript.
+///
+/// # Arguments
+///
+/// * `registries` - The OCI registries to log in to
+///
+pub async fn helm_registry_login(registries: &[Registry]) -> Result<()> {
+    for registry in registries {
+        let mut flags = Vec::new();
+        if registry.insecure.unwrap_or(false) {
+            flags.push("--insecure-skip-tls-verify");
+        }
+        if registry.plain_http.unwrap_or(false) {
+            flags.push("--plain-http");
+        }
+        REGISTRY_FLAGS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(registry.host.clone(), flags);
+
+        let (Some(username), Some(password_env)) = (&registry.username, &registry.password_env)
+        else {
+            debug!(
+                "No credentials for registry {}, skipping login",
+                registry.host
+            );
+            continue;
+        };
+        if cache::is_offline() {
+            info!("Offline, skipping login to registry {}", registry.host);
+            continue;
+        }
+
+        let password = std::env::var(password_env).with_context(|| {
+            format!(
+                "The password of registry {} must be set in the environment variable {}",
+                registry.host, password_env
+            )
+        })?;
+
+        println!("Logging in to registry: {}", registry.host);
+        let mut args = vec![
+            "registry",
+            "login",
+            &registry.host,
+            "--username",
+            username,
+            "--password-stdin",
+        ];
+        if registry.insecure.unwrap_or(false) {
+            args.push("--insecure");
+        }
+        let (stdout, stderr, status) =
+            run_command_with_input("helm", &args, Some(password.as_bytes())).await?;
+        debug!("stdout: {}", stdout);
+        debug!("stderr: {}", stderr);
+
+        if status.code() != Some(0) {
+            let error_msg = format!(
+                "Failed to log in to registry {}: {}",
+                registry.host,
+                stderr.trim()
+            );
+            return Err(anyhow::anyhow!(error_msg));
+        }
+    }
+
+    Ok(())
+}
+
+/// Get the extra helm flags for a chart reference
+///
+/// Returns the flags of the configured registry for OCI chart references.
+///
+fn helm_registry_flags(chart_ref: &str) -> Vec<&'static str> {
+    let Some(host) = chart_ref
+        .strip_prefix("oci://")
+        .and_then(|path| path.split('/').next())
+    else {
+        return Vec::new();
+    };
+
+    REGISTRY_FLAGS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(host)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Resolve the reference helm uses to find a chart
+///
+/// For a repository this is "<repo>/<chart>", which also covers OCI registries.
+/// For a local directory or git repository this is the chart directory,
+/// whose dependencies are built first.
+///
+/// # Arguments
+///
+/// * `chart` - The Helm chart to resolve
+///
+async fn helm_chart_ref(chart: &Chart) -> Result<String> {
+    let chart_dir = if let Some(repo) = &chart.repo {
+        return Ok(format!("{}/{}", repo.trim_end_matches('/'), chart.chart()));
+    } else if let Some(path) = &chart.path {
+        Path::new(path).to_path_buf()
+    } else if let Some(git) = &chart.git {
+        let checkout = git_checkout(&git.url, git.reference.as_deref()).await?;
+        checkout.join(git.path.as_deref().unwrap_or_default())
+    } else {
+        return Err(anyhow::anyhow!(
+            "Chart {} has no 'repo', 'path' or 'git' source",
+            chart.name
+        ));
+    };
+
+    let chart_dir = chart_dir
+        .to_string_lossy()
+        .trim_end_matches('/')
+        .to_string();
+    let chart_yaml = format!("{}/Chart.yaml", chart_dir);
+    let err_msg = format!("Chart {} has no Chart.yaml in '{}'", chart.name, chart_dir);
+    let definition = std::fs::read_to_string(&chart_yaml).context(err_msg)?;
+
+    // Local charts don't have their dependencies downloaded yet.
+    if definition.contains("dependencies:") {
+        info!("Building the dependencies of chart: {}", chart_dir);
+        let (stdout, stderr, status) =
+            run_command("helm", &["dependency", "build", &chart_dir]).await?;
+        debug!("stdout: {}", stdout);
+        debug!("stderr: {}", stderr);
+
+        if status.code() != Some(0) {
+            let error_msg = format!("Failed to build the chart dependencies: {}", stderr);
+            return Err(anyhow::anyhow!(error_msg));
+        }
+    }
+
+    Ok(chart_dir)
+}
+
+/// Check if a Helm release exists in the given namespace
+///
+/// # Arguments
+///
+/// * `release` - The name of the Helm release
+/// * `namespace` - The namespace of the Helm release
+///
+async fn helm_release_exists(release: &str, namespace: &str) -> Result<bool> {
+    // Include releases in every state, so stuck and failed releases are found too.
+    let (stdout, stderr, status) = run_command(
+        "helm",
+        &["list", "--namespace", namespace, "--short", "--all"],
+    )
+    .await?;
+    debug!("stdout: {}", stdout);
+    debug!("stderr: {}", stderr);
+
+    if status.code() != Some(0) {
+        let error_msg = format!("Failed to list Helm releases: {}", stderr);
+        return Err(anyhow::anyhow!(error_msg));
+    }
+
+    Ok(stdout.lines().any(|line| line.trim() == release))
+}
+
+/// The state of a Helm release.
+#[derive(Debug, Default)]
+pub struct ReleaseStatus {
+    /// The version of the installed chart.
+    pub version: String,
+    /// The revision of the release.
+    pub revision: u64,
+    /// The status of the release, for example "deployed" or "failed".
+    pub status: String,
+    /// The outcome of the last run of the test hooks, "passed" or "failed".
+    pub test: Option<String>,
+    /// The rendered manifest of the release.
+    pub manifest: String,
+}
+
+/// Get the state of a Helm release
+///
+/// # Arguments
+///
+/// * `release` - The name of the Helm release
+/// * `namespace` - The namespace of the Helm release
+///
+/// # Returns
+///
+/// The state of the release, or None if it isn't installed.
+///
+pub async fn helm_release_status(release: &str, namespace: &str) -> Result<Option<ReleaseStatus>> {
+    if !helm_release_exists(release, namespace).await? {
+        return Ok(None);
+    }
+
+    let args = ["status", release, "--namespace", namespace, "-o", "json"];
+    let (stdout, stderr, status) = run_command("helm", &args).await?;
+    if status.code() != Some(0) {
+        let error_msg = format!(
+            "Failed to get the status of Helm release {}: {}",
+            release, stderr
+        );
+        return Err(anyhow::anyhow!(error_msg));
+    }
+    let err_msg = format!("Failed to parse the status of Helm release {}", release);
+    let output: serde_json::Value = serde_json::from_str(&stdout).context(err_msg)?;
+
+    // The test hooks keep the phase of their last run.
+    let phases: Vec<&str> = output["hooks"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|hook| {
+            hook["events"]
+                .as_array()
+                .is_some_and(|events| events.iter().any(|event| event == "test"))
+        })
+        .filter_map(|hook| hook["last_run"]["phase"].as_str())
+        .filter(|phase| !phase.is_empty())
+        .collect();
+    let test = if phases.iter().any(|phase| *phase != "Succeeded") {
+        Some("failed".to_string())
+    } else if phases.is_empty() {
+        None
+    } else {
+        Some("passed".to_string())
+    };
+
+    Ok(Some(ReleaseStatus {
+        version: output["chart"]["metadata"]["version"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        revision: output["version"].as_u64().unwrap_or_default(),
+        status: output["info"]["status"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        test,
+        manifest: output["manifest"].as_str().unwrap_or_default().to_string(),
+    }))
+}
+
+/// List the names of the installed Helm repositories
+pub async fn helm_repo_names() -> Result<Vec<String>> {
+    let (stdout, stderr, status) = run_command("helm", &["repo", "list", "-o", "json"]).await?;
+    debug!("stderr: {}", stderr);
+
+    if status.code() != Some(0) {
+        // helm fails when there are no repositories at all.
+        if stderr.contains("no repositories to show") {
+            return Ok(Vec::new());
+        }
+        let error_msg = format!("Failed to list Helm repositories: {}", stderr);
+        return Err(anyhow::anyhow!(error_msg));
+    }
+    let repos: Vec<serde_json::Value> =
+        serde_json::from_str(&stdout).context("Failed to parse the Helm repositories")?;
+
+    Ok(repos
+        .iter()
+        .filter_map(|repo| repo["name"].as_str().map(|name| name.to_string()))
+        .collect())
+}
+
 /// Install or upgrade the given Helm chart release
 ///
+/// The release is left as it is when its inputs haven't changed since it was
+/// last installed, unless `--force` was given.
+///
 /// # Arguments
 ///
-/// * `name` - The name of the Helm release to install or upgrade
-/// * `repo` - The Helm repository where the chart is located
-/// * `values` - An optional name of the values file to use.
+/// * `chart` - The Helm chart to install or upgrade
+///
+/// # Returns
+///
+/// True if the release was installed or upgraded, false if it was unchanged.
 ///
 /// # Examples
 ///
 /// ```rust
 /// use loopy::helm::helm_install_chart;
-/// let result = helm_install_chart("example", "example-repo");
+/// let result = helm_install_chart(&chart).await;
 /// assert!(result.is_ok());
 /// ```
 ///
-fn helm_install_chart(
-    name: &str,
-    repo: &str,
-    namespace: &str,
-    values_filename: Option<&str>,
-) -> Result<()> {
-    info!("Installing Helm chart: {} into {}", name, namespace);
+async fn helm_install_chart(chart: &Chart) -> Result<bool> {
+    let release = chart.release();
+    let namespace = chart.namespace();
+
+    info!(
+        "Installing Helm chart: {} as release {} into {}",
+        chart.chart(),
+        release,
+        namespace
+    );
+
+    let chart_ref = helm_chart_ref(chart).await?;
 
     // Check if the chart directory exists
-    let chart_dir: String = format!("config/helm/{}", name);
+    let chart_dir: String = format!("config/helm/{}", chart.name);
     if !Path::new(&chart_dir).exists() {
         info!("Helm chart directory does not exist, preparing chart");
-        helm_prepare_chart(name, repo)?;
+        helm_prepare_chart(chart, &chart_ref).await?;
     } else {
         debug!("Helm chart directory exists: {}", chart_dir);
     }
 
-    // Determine the values file to use.
-    let values_file = if let Some(filename) = values_filename {
+    // Determine the values files to use, layered in order.
+    let mut values_files = Vec::new();
+    for filename in chart.values_files() {
         let path = format!("{}/{}", chart_dir, filename);
 
         if !Path::new(&path).exists() {
@@ -302,8 +643,9 @@ fn helm_install_chart(
         } else {
             debug!("Using values file: {}", path);
         }
-        path
-    } else {
+        values_files.push(path);
+    }
+    if values_files.is_empty() {
         let default_path = format!("{}/values.yaml", chart_dir);
         debug!("No values file provided, using default: {}", default_path);
 
@@ -313,112 +655,488 @@ fn helm_install_chart(
                 "A default values file does not exist, creating one now: {}",
                 default_path
             );
-            helm_prepare_chart(name, repo)?;
+            helm_prepare_chart(chart, &chart_ref).await?;
         }
 
-        default_path
-    };
+        values_files.push(default_path);
+    }
+
+    // Render any template references in the values files.
+    let mut rendered_files = Vec::new();
+    for values_file in &values_files {
+        let rendered = template::render_file(Path::new(values_file)).await?;
+        rendered_files.push(rendered.to_string_lossy().to_string());
+    }
+
+    // Read each layer, so the values can be checked before helm sees them.
+    let mut layers = Vec::new();
+    for (values_file, rendered) in values_files.iter().zip(&rendered_files) {
+        let path = Path::new(rendered);
+        let content = if secrets::is_encrypted(path) {
+            secrets::decrypt_file(path).await?
+        } else {
+            std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read values file '{}'", values_file))?
+        };
+        layers.push((values_file.clone(), content));
+    }
+    let mut values_files = rendered_files;
+
+    // When any values file is encrypted, the layers are merged in memory and passed on stdin.
+    let mut values_input = None;
+    if values_files
+        .iter()
+        .any(|file| secrets::is_encrypted(Path::new(file)))
+    {
+        let mut merged = serde_yaml::Value::Null;
+        for (values_file, content) in &layers {
+            let layer: serde_yaml::Value = serde_yaml::from_str(content)
+                .with_context(|| format!("Failed to parse values file '{}'", values_file))?;
+            helm_merge_values(&mut merged, layer);
+        }
+        values_input = Some(serde_yaml::to_string(&merged)?.into_bytes());
+        values_files = vec!["-".to_string()];
+    }
+
+    let timeout = chart.timeout.as_deref().unwrap_or("10m0s");
+    let set_args: Vec<String> = chart
+        .set
+        .iter()
+        .flatten()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    let set_string_args: Vec<String> = chart
+        .set_string
+        .iter()
+        .flatten()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+
+    let mut flags: Vec<&str> = Vec::new();
+    if let Some(version) = &chart.version {
+        flags.extend(["--version", version]);
+    }
+    for set in &set_args {
+        flags.extend(["--set", set]);
+    }
+    for set_string in &set_string_args {
+        flags.extend(["--set-string", set_string]);
+    }
+    if chart.wait.unwrap_or(true) {
+        flags.push("--wait");
+    }
+    if chart.atomic.unwrap_or(false) {
+        flags.push("--atomic");
+    }
+    if chart.skip_crds.unwrap_or(false) {
+        flags.push("--skip-crds");
+    }
+    flags.extend(["--timeout", timeout]);
+    flags.extend(helm_registry_flags(&chart_ref));
+    flags.extend(chart.extra_args.iter().flatten().map(|arg| arg.as_str()));
+
+    // Skip the upgrade if nothing that goes into the release has changed.
+    let version = helm_resolved_version(chart, &chart_ref).await?;
+    let hash = helm_inputs_hash(
+        &chart_ref,
+        version.as_deref(),
+        release,
+        namespace,
+        &flags,
+        &layers,
+    )?;
+    if changes::is_unchanged("chart", &chart.name, &hash)
+        && helm_release_status(release, namespace)
+            .await?
+            .is_some_and(|state| state.status == "deployed")
+    {
+        return Ok(false);
+    }
+    store::clear_hash("chart", &chart.name);
+
+    // Check the values before helm sees them.
+    values::values_validate(chart, &chart_ref, &layers).await?;
+
+    // Recover a release that an earlier run left stuck or failed.
+    helm_recover_release(release, namespace, timeout).await?;
 
     // Check if the helm release already exists
-    let (stdout, stderr, status) = run_command("helm", &["list", "--namespace", namespace])?;
+    let action = if helm_release_exists(release, namespace).await? {
+        debug!("Helm release already exists, upgrading: {}", release);
+        "upgrade"
+    } else {
+        debug!("Helm release does not exist, installing: {}", release);
+        "install"
+    };
+
+    let mut args: Vec<&str> = vec![action, release, &chart_ref, "--namespace", namespace];
+    if action == "install" {
+        args.push("--create-namespace");
+    }
+    for values_file in &values_files {
+        args.extend(["--values", values_file]);
+    }
+    args.extend(flags);
+
+    let (stdout, stderr, status) =
+        run_command_streaming_with_input("helm", &args, release, values_input)
+            .await
+            .with_context(|| format!("Failed to {} Helm chart '{}'", action, release))?;
     debug!("stdout: {}", stdout);
     debug!("stderr: {}", stderr);
 
-    // Handle the error condition first.
     if status.code() != Some(0) {
-        let error_msg = format!("Failed to list Helm releases: {}", stderr);
-        return Err(anyhow::anyhow!(error_msg));
-    } else if stdout.contains(name) {
-        debug!("Helm release already exists, upgrading: {}", name);
-        // If the helm release is already installed, upgrade it.
-        let (stdout, stderr, status) = run_command(
-            "helm",
-            &[
-                "upgrade",
-                name,
-                "--values",
-                &values_file,
-                "--namespace",
-                namespace,
-                "--wait",
-                "--timeout",
-                "10m0s",
-                &format!("{}/{}", repo, name),
-            ],
-        )
-        .with_context(|| format!("Failed to upgrade Helm chart '{}'", name))?;
-        debug!("stdout: {}", stdout);
-        debug!("stderr: {}", stderr);
+        let error_msg = format!("Failed to {} Helm chart: {}", action, stderr);
 
-        if status.code() != Some(0) {
-            let error_msg = format!("Failed to upgrade Helm chart: {}", stderr);
-            return Err(anyhow::anyhow!(error_msg));
+        // With atomic, helm has already cleaned up the failed release itself.
+        if chart.rollback.unwrap_or(false) && !chart.atomic.unwrap_or(false) {
+            if action == "upgrade" {
+                match helm_last_deployed_revision(release, namespace, u64::MAX).await? {
+                    Some(revision) => {
+                        println!(
+                            "Rolling back Helm release {} to revision {}",
+                            release, revision
+                        );
+                        helm_rollback(release, namespace, revision, timeout).await?;
+                        return Err(anyhow::anyhow!(
+                            "{}, rolled back to revision {}.",
+                            error_msg.trim_end(),
+                            revision
+                        ));
+                    }
+                    None => warn!("Helm release {} has no revision to roll back to", release),
+                }
+            } else {
+                println!("Uninstalling the failed Helm release {}", release);
+                helm_uninstall_chart(release, namespace).await?;
+                return Err(anyhow::anyhow!(
+                    "{}, the release was uninstalled.",
+                    error_msg.trim_end()
+                ));
+            }
         }
-    } else {
-        debug!("Helm release does not exist, installing: {}", name);
-        // If the helm release doesn't currently exist, install it.
-        let (stdout, stderr, status) = run_command(
-            "helm",
-            &[
-                "install",
-                name,
-                "--values",
-                &values_file,
-                "--namespace",
-                namespace,
-                "--create-namespace",
-                "--wait",
-                "--timeout",
-                "10m0s",
-                &format!("{}/{}", repo, name),
-            ],
-        )
-        .with_context(|| format!("Failed to install Helm chart '{}'", name))?;
-        debug!("stdout: {}", stdout);
-        debug!("stderr: {}", stderr);
 
-        if status.code() != Some(0) {
-            let error_msg = format!("Failed to install Helm chart: {}", stderr);
-            return Err(anyhow::anyhow!(error_msg));
+        return Err(anyhow::anyhow!(error_msg));
+    }
+
+    helm_record_release(chart, Some(hash)).await?;
+
+    Ok(true)
+}
+
+/// Get the version of the given Helm chart that helm will install
+///
+/// A chart without a version, or with a range such as `^1.2`, resolves to the
+/// newest matching release, which changes after `helm repo update`.
+///
+/// # Arguments
+///
+/// * `chart` - The Helm chart to resolve
+/// * `chart_ref` - The reference helm uses to find the chart
+///
+/// # Returns
+///
+/// The resolved version, or None for local and git charts, whose files are hashed instead.
+///
+async fn helm_resolved_version(chart: &Chart, chart_ref: &str) -> Result<Option<String>> {
+    if Path::new(chart_ref).is_dir() {
+        return Ok(None);
+    }
+
+    // Offline, helm can't look up the newest release, so the configured version is used.
+    if cache::is_offline() {
+        return Ok(chart.version.clone());
+    }
+
+    let mut args = vec!["show", "chart", chart_ref];
+    if let Some(version) = &chart.version {
+        args.extend(["--version", version]);
+    }
+    args.extend(helm_registry_flags(chart_ref));
+    let (stdout, stderr, status) = run_command("helm", &args).await?;
+    if status.code() != Some(0) {
+        let error_msg = format!(
+            "Failed to get the version of Helm chart {}: {}",
+            chart_ref, stderr
+        );
+        return Err(anyhow::anyhow!(error_msg));
+    }
+
+    let err_msg = format!("Failed to parse the definition of Helm chart {}", chart_ref);
+    let definition: serde_yaml::Value = serde_yaml::from_str(&stdout).context(err_msg)?;
+    match definition["version"].as_str() {
+        Some(version) => Ok(Some(version.to_string())),
+        None => Err(anyhow::anyhow!("Helm chart {} has no version", chart_ref)),
+    }
+}
+
+/// Hash the inputs of a Helm release
+///
+/// The hash covers the chart source and the version helm resolved it to, the
+/// release flags and the content of each values layer. The files of local and
+/// git charts are hashed as well, as their templates can change without a new
+/// chart version.
+///
+/// # Arguments
+///
+/// * `chart_ref` - The chart reference passed to helm
+/// * `version` - The resolved version of the chart, if it comes from a repository or registry
+/// * `release` - The name of the Helm release
+/// * `namespace` - The namespace of the Helm release
+/// * `flags` - The flags passed to helm install and upgrade
+/// * `layers` - The values files and their content, in the order they are layered
+///
+fn helm_inputs_hash(
+    chart_ref: &str,
+    version: Option<&str>,
+    release: &str,
+    namespace: &str,
+    flags: &[&str],
+    layers: &[(String, String)],
+) -> Result<String> {
+    let mut inputs = vec![
+        chart_ref.to_string(),
+        version.unwrap_or_default().to_string(),
+        release.to_string(),
+        namespace.to_string(),
+    ];
+    if Path::new(chart_ref).is_dir() {
+        inputs.push(changes::hash_dir(Path::new(chart_ref))?);
+    }
+    inputs.extend(flags.iter().map(|flag| flag.to_string()));
+    for (_, content) in layers {
+        inputs.push(content.clone());
+    }
+
+    changes::hash_inputs(inputs.join("\0").as_bytes())
+}
+
+/// Record the deployed release of the given Helm chart in the state store
+///
+/// # Arguments
+///
+/// * `chart` - The Helm chart that was installed, upgraded or rolled back
+/// * `hash` - The hash of the installed inputs, if they are known
+///
+async fn helm_record_release(chart: &Chart, hash: Option<String>) -> Result<()> {
+    let Some(release) = helm_release_status(chart.release(), chart.namespace()).await? else {
+        return Ok(());
+    };
+
+    let now = Local::now();
+    store::record(store::Component {
+        phase: state::phase(),
+        kind: "chart".to_string(),
+        name: chart.name.clone(),
+        release: Some(chart.release().to_string()),
+        namespace: Some(chart.namespace().to_string()),
+        version: Some(release.version),
+        revision: Some(release.revision),
+        hash,
+        objects: Vec::new(),
+        installed: now,
+        updated: now,
+    });
+
+    Ok(())
+}
+
+/// Recover a Helm release that is stuck or failed
+///
+/// A release left in a pending state by an interrupted run is rolled back to its
+/// last deployed revision. A release without any deployed revision, for example
+/// after a failed first install, is uninstalled so it can be installed again.
+///
+/// # Arguments
+///
+/// * `release` - The name of the Helm release
+/// * `namespace` - The namespace of the Helm release
+/// * `timeout` - The time to wait for a rollback
+///
+async fn helm_recover_release(release: &str, namespace: &str, timeout: &str) -> Result<()> {
+    let Some(state) = helm_release_status(release, namespace).await? else {
+        return Ok(());
+    };
+    let stuck = state.status.starts_with("pending-");
+    if !stuck && state.status != "failed" {
+        return Ok(());
+    }
+
+    match helm_last_deployed_revision(release, namespace, state.revision).await? {
+        Some(revision) if stuck => {
+            warn!(
+                "Helm release {} is stuck in {} at revision {}",
+                release, state.status, state.revision
+            );
+            println!(
+                "Helm release {} is stuck in {}, rolling back to revision {}",
+                release, state.status, revision
+            );
+            helm_rollback(release, namespace, revision, timeout).await
+        }
+        // Helm upgrades a failed release from its last deployed revision.
+        Some(_) => Ok(()),
+        None => {
+            println!(
+                "Helm release {} is {} without a deployed revision, uninstalling it first",
+                release, state.status
+            );
+            helm_uninstall_chart(release, namespace).await
         }
     }
+}
+
+/// Find the last revision of a Helm release that was deployed successfully
+///
+/// # Arguments
+///
+/// * `release` - The name of the Helm release
+/// * `namespace` - The namespace of the Helm release
+/// * `before` - Only consider revisions before this one
+///
+async fn helm_last_deployed_revision(
+    release: &str,
+    namespace: &str,
+    before: u64,
+) -> Result<Option<u64>> {
+    let args = ["history", release, "--namespace", namespace, "-o", "json"];
+    let (stdout, stderr, status) = run_command("helm", &args).await?;
+    debug!("stderr: {}", stderr);
+
+    if status.code() != Some(0) {
+        let error_msg = format!(
+            "Failed to get the history of Helm release {}: {}",
+            release, stderr
+        );
+        return Err(anyhow::anyhow!(error_msg));
+    }
+    let err_msg = format!("Failed to parse the history of Helm release {}", release);
+    let history: Vec<serde_json::Value> = serde_json::from_str(&stdout).context(err_msg)?;
+
+    Ok(history
+        .iter()
+        .filter(|entry| {
+            entry["status"]
+                .as_str()
+                .is_some_and(|status| status == "deployed" || status == "superseded")
+        })
+        .filter_map(|entry| entry["revision"].as_u64())
+        .filter(|revision| *revision < before)
+        .max())
+}
+
+/// Roll back a Helm release to the given revision
+///
+/// # Arguments
+///
+/// * `release` - The name of the Helm release
+/// * `namespace` - The namespace of the Helm release
+/// * `revision` - The revision to roll back to
+/// * `timeout` - The time to wait for the rollback
+///
+async fn helm_rollback(release: &str, namespace: &str, revision: u64, timeout: &str) -> Result<()> {
+    let revision = revision.to_string();
+    let args = [
+        "rollback",
+        release,
+        &revision,
+        "--namespace",
+        namespace,
+        "--wait",
+        "--timeout",
+        timeout,
+    ];
+    let (stdout, stderr, status) = run_command_streaming("helm", &args, release)
+        .await
+        .with_context(|| format!("Failed to roll back Helm release '{}'", release))?;
+    debug!("stdout: {}", stdout);
+    debug!("stderr: {}", stderr);
+
+    if status.code() != Some(0) {
+        let error_msg = format!("Failed to roll back Helm release {}: {}", release, stderr);
+        return Err(anyhow::anyhow!(error_msg));
+    }
 
     Ok(())
 }
 
+/// Roll back the release of the given Helm chart
+///
+/// # Arguments
+///
+/// * `chart` - The Helm chart to roll back
+/// * `revision` - The revision to roll back to, or the last deployed revision before the current one
+///
+/// # Returns
+///
+/// The revision the release was rolled back to.
+///
+pub async fn helm_rollback_chart(chart: &Chart, revision: Option<u64>) -> Result<u64> {
+    let release = chart.release();
+    let namespace = chart.namespace();
+    let timeout = chart.timeout.as_deref().unwrap_or("10m0s");
+
+    let Some(state) = helm_release_status(release, namespace).await? else {
+        return Err(anyhow::anyhow!(
+            "Helm release {} is not installed in namespace {}",
+            release,
+            namespace
+        ));
+    };
+    let revision = match revision {
+        Some(revision) => revision,
+        None => helm_last_deployed_revision(release, namespace, state.revision)
+            .await?
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Helm release {} has no deployed revision before revision {}",
+                    release,
+                    state.revision
+                )
+            })?,
+    };
+
+    println!(
+        "Rolling back Helm release {} from revision {} to revision {}",
+        release, state.revision, revision
+    );
+    helm_rollback(release, namespace, revision, timeout).await?;
+
+    // The rolled back release no longer matches the installed inputs.
+    if store::current().is_some_and(|store| store.component("chart", &chart.name).is_some()) {
+        helm_record_release(chart, None).await?;
+    }
+
+    Ok(revision)
+}
+
 /// Uninstall the given Helm chart release
 ///
 /// # Arguments
 ///
-/// * `name` - The name of the Helm release to uninstall
+/// * `release` - The name of the Helm release to uninstall
+/// * `namespace` - The namespace of the Helm release
 ///
 /// # Examples
 ///
 /// ```rust
 /// use loopy::helm::helm_uninstall_chart;
-/// let result = helm_uninstall_chart("example");
+/// let result = helm_uninstall_chart("example", "example");
 /// assert!(result.is_ok());
 /// ```
 ///
-fn helm_uninstall_chart(name: &str) -> Result<()> {
-    info!("Uninstalling Helm chart: {}", name);
+pub async fn helm_uninstall_chart(release: &str, namespace: &str) -> Result<()> {
+    info!("Uninstalling Helm release: {} from {}", release, namespace);
 
     // Check if the helm release exists in the specified namespace
-    let (stdout, stderr, status) = run_command("helm", &["list", "--namespace", name])?;
-    debug!("stdout: {}", stdout);
-    debug!("stderr: {}", stderr);
-
-    // Handle the error condition first.
-    if status.code() != Some(0) {
-        let error_msg = format!("Failed to list Helm releases: {}", stderr);
-        return Err(anyhow::anyhow!(error_msg));
-    } else if stdout.contains(name) {
-        debug!("Helm chart '{}' is installed, uninstalling.", name);
+    if helm_release_exists(release, namespace).await? {
+        debug!("Helm release '{}' is installed, uninstalling.", release);
 
         // If the helm release exists, uninstall it.
         let (stdout, stderr, status) =
-            run_command("helm", &["uninstall", name, "--namespace", name])
-                .with_context(|| format!("Failed to uninstall Helm chart '{}'", name))?;
+            run_command("helm", &["uninstall", release, "--namespace", namespace])
+                .await
+                .with_context(|| format!("Failed to uninstall Helm release '{}'", release))?;
         debug!("stdout: {}", stdout);
         debug!("stderr: {}", stderr);
 
@@ -427,32 +1145,179 @@ fn helm_uninstall_chart(name: &str) -> Result<()> {
             return Err(anyhow::anyhow!(error_msg));
         }
     } else {
-        info!("Helm chart '{}' is not installed, skipping.", name)
+        info!("Helm release '{}' is not installed, skipping.", release)
     }
 
     Ok(())
 }
 
+/// Pull the default values of the given Helm chart into its directory under config/helm
+///
+/// # Arguments
+///
+/// * `chart` - The Helm chart to pull the default values of
+///
+pub async fn helm_default_values(chart: &Chart) -> Result<()> {
+    let chart_ref = helm_chart_ref(chart).await?;
+    helm_prepare_chart(chart, &chart_ref).await
+}
+
+/// Get the upstream default values of the given Helm chart, at its pinned version
+///
+/// # Arguments
+///
+/// * `chart` - The Helm chart to get the default values of
+///
+pub async fn helm_upstream_values(chart: &Chart) -> Result<String> {
+    let chart_ref = helm_chart_ref(chart).await?;
+    helm_show_values(chart, &chart_ref).await
+}
+
+/// Get the default values and the values schema of the given Helm chart
+///
+/// Local charts are read from their directory. Other charts are pulled once
+/// per chart reference and pinned version into `.loopy/cache/charts`, or to a
+/// temporary directory when no version is pinned.
+///
+/// # Arguments
+///
+/// * `chart` - The Helm chart to read
+/// * `chart_ref` - The reference helm uses to find the chart
+///
+/// # Returns
+///
+/// The default values, and the values.schema.json of the chart if it has one.
+///
+pub async fn helm_chart_files(chart: &Chart, chart_ref: &str) -> Result<(String, Option<String>)> {
+    let read = |dir: &Path| -> Result<(String, Option<String>)> {
+        let defaults = std::fs::read_to_string(dir.join("values.yaml")).unwrap_or_default();
+        let schema_file = dir.join("values.schema.json");
+        let schema = if schema_file.is_file() {
+            let err_msg = format!("Failed to read {}", schema_file.display());
+            Some(std::fs::read_to_string(&schema_file).context(err_msg)?)
+        } else {
+            None
+        };
+        Ok((defaults, schema))
+    };
+
+    if Path::new(chart_ref).is_dir() {
+        return read(Path::new(chart_ref));
+    }
+
+    // A pinned version of a chart never changes, so it is only pulled once.
+    let cache_dir = chart.version.as_ref().map(|version| {
+        let key = format!("{}\0{}", chart_ref, version);
+        Path::new(CHART_CACHE_DIR).join(hash_content(key.as_bytes()))
+    });
+    if let Some(cache_dir) = cache_dir.as_ref().filter(|dir| dir.is_dir()) {
+        debug!("Using the cached copy of chart {}", chart_ref);
+        return read(&helm_pulled_chart(cache_dir, chart_ref)?);
+    }
+    if cache::is_offline() {
+        return Err(anyhow::anyhow!(
+            "Helm chart {} is not cached, run once without --offline to pull it",
+            chart_ref
+        ));
+    }
+
+    // Pull next to the cache, so a complete pull can be moved into place.
+    let err_msg = format!("Failed to create directory {}", CHART_CACHE_DIR);
+    std::fs::create_dir_all(CHART_CACHE_DIR).context(err_msg)?;
+    let pull_dir =
+        tempfile::tempdir_in(CHART_CACHE_DIR).context("Failed to create a temporary directory")?;
+    let pull_path = pull_dir.path().to_string_lossy().to_string();
+    let mut args = vec!["pull", chart_ref, "--untar", "--untardir", &pull_path];
+    if let Some(version) = &chart.version {
+        args.extend(["--version", version]);
+    }
+    args.extend(helm_registry_flags(chart_ref));
+    let (stdout, stderr, status) = run_command("helm", &args).await?;
+    debug!("stdout: {}", stdout);
+    debug!("stderr: {}", stderr);
+
+    if status.code() != Some(0) {
+        let error_msg = format!("Failed to pull Helm chart: {}", stderr);
+        return Err(anyhow::anyhow!(error_msg));
+    }
+
+    let Some(cache_dir) = cache_dir else {
+        return read(&helm_pulled_chart(pull_dir.path(), chart_ref)?);
+    };
+    let pulled = pull_dir.into_path();
+    if let Err(e) = std::fs::rename(&pulled, &cache_dir) {
+        // Another chart with the same reference may have been pulled meanwhile.
+        let _ = std::fs::remove_dir_all(&pulled);
+        if !cache_dir.is_dir() {
+            let err_msg = format!("Failed to move the pulled chart to {}", cache_dir.display());
+            return Err(e).context(err_msg);
+        }
+    }
+
+    read(&helm_pulled_chart(&cache_dir, chart_ref)?)
+}
+
+/// Find the chart that helm pull unpacked, into a directory named after it.
+///
+/// # Arguments
+///
+/// * `pull_dir` - The directory the chart was unpacked into
+/// * `chart_ref` - The reference helm used to find the chart
+///
+fn helm_pulled_chart(pull_dir: &Path, chart_ref: &str) -> Result<std::path::PathBuf> {
+    let err_msg = format!("Failed to read {}", pull_dir.display());
+    std::fs::read_dir(pull_dir)
+        .context(err_msg)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| path.is_dir())
+        .ok_or_else(|| anyhow::anyhow!("Helm pulled no chart for {}", chart_ref))
+}
+
+/// Run helm show values for the given Helm chart
+///
+/// # Arguments
+///
+/// * `chart` - The Helm chart to show the values of
+/// * `chart_ref` - The reference helm uses to find the chart
+///
+async fn helm_show_values(chart: &Chart, chart_ref: &str) -> Result<String> {
+    let mut args = vec!["show", "values", chart_ref];
+    if let Some(version) = &chart.version {
+        args.extend(["--version", version]);
+    }
+    args.extend(helm_registry_flags(chart_ref));
+    let (stdout, stderr, status) = run_command("helm", &args).await?;
+
+    // Handle the error condition first.
+    if status.code() != Some(0) {
+        let error_msg = format!("Failed to get Helm chart values: {}", stderr);
+        return Err(anyhow::anyhow!(error_msg));
+    }
+
+    Ok(stdout)
+}
+
 /// Prepare the given Helm chart by creating its directory and values.yaml file if they don't already exist
 ///
 /// # Arguments
 ///
-/// * `name` - The name of the Helm chart to prepare
-/// * `repo` - The Helm repository where the chart is located
+/// * `chart` - The Helm chart to prepare
+/// * `chart_ref` - The reference helm uses to find the chart
 ///
 /// # Examples
 ///
 /// ```rust
 /// use loopy::helm::helm_prepare_chart;
-/// let result = helm_prepare_chart("example", "example-repo");
+/// let result = helm_prepare_chart(&chart, "example-repo/example");
 /// assert!(result.is_ok());
 /// ```
 ///
-fn helm_prepare_chart(name: &str, repo: &str) -> Result<()> {
-    info!("Preparing Helm chart: {}", name);
+async fn helm_prepare_chart(chart: &Chart, chart_ref: &str) -> Result<()> {
+    info!("Preparing Helm chart: {}", chart.name);
 
     // Create the chart directory if it doesn't exist
-    let chart_dir: String = format!("config/helm/{}", name);
+    let chart_dir: String = format!("config/helm/{}", chart.name);
     if !Path::new(&chart_dir).exists() {
         info!("Creating Helm chart directory: {}", chart_dir);
         let err_msg = format!("Failed to create Helm chart directory '{}'", chart_dir);
@@ -463,14 +1328,7 @@ fn helm_prepare_chart(name: &str, repo: &str) -> Result<()> {
     let defaults_file = format!("{}/values.yaml", chart_dir);
     if !Path::new(&defaults_file).exists() {
         info!("Creating Helm chart defaults file: {}", defaults_file);
-        let (stdout, stderr, status) =
-            run_command("helm", &["show", "values", &format!("{}/{}", repo, name)])?;
-
-        // Handle the error condition first.
-        if status.code() != Some(0) {
-            let error_msg = format!("Failed to get Helm chart values: {}", stderr);
-            return Err(anyhow::anyhow!(error_msg));
-        }
+        let stdout = helm_show_values(chart, chart_ref).await?;
 
         let err_msg = format!(
             "Failed to create Helm chart defaults file '{}'",
@@ -504,45 +1362,127 @@ fn helm_prepare_chart(name: &str, repo: &str) -> Result<()> {
 ///
 pub async fn helm_process_repos(repos: &[Repository], action: &str) -> Result<()> {
     for repo in repos {
+        if state::is_completed("repository", &repo.name) {
+            continue;
+        }
         let err_message = format!("Failed to {} Helm repository: {}", action, repo.name);
         println!("{} Helm repository: {}", action, repo.name);
-        helm_repo(action, Some(&repo.name), Some(&repo.url))
-            .await
-            .context(err_message)?;
+        logger::component(
+            "helm",
+            &repo.name,
+            action,
+            helm_repo(action, Some(&repo.name), Some(&repo.url)),
+        )
+        .await
+        .context(err_message)?;
         println!("Successfully {} Helm repository: {}", action, repo.name);
+        state::complete("repository", &repo.name)?;
+        signal::check()?;
     }
     Ok(())
 }
 
-/// Process Helm Charts
+/// Process Helm Chart
 ///
 /// # Arguments
 ///
-/// * `charts` - The list of Helm charts to process
-/// * `action` - The action to perform on the Helm charts
+/// * `chart` - The Helm chart to process
+/// * `action` - The action to perform on the Helm chart
 ///
 /// # Examples
 ///
 /// ```rust
-/// use loopy::helm::helm_process_charts;
-/// let result = helm_process_charts(&[], "install");
+/// use loopy::helm::helm_process_chart;
+/// let result = helm_process_chart(&chart, "install").await;
 /// assert!(result.is_ok());
 /// ```
 ///
-pub async fn helm_process_charts(charts: &[Chart], action: &str) -> Result<()> {
-    if charts.is_empty() {
+pub async fn helm_process_chart(chart: &Chart, action: &str) -> Result<()> {
+    if state::is_completed("chart", &chart.name) {
+        return Ok(());
+    }
+    if action == "uninstall" && !store::is_owned("chart", &chart.name) {
         println!(
-            "No Helm chart {} were found in the configuration file. Skipping...",
-            action
+            "Skipping Helm chart {}, it was not installed by {}",
+            chart.name, PACKAGE_NAME
         );
-    } else {
-        for chart in charts {
-            println!("{} Helm chart: {}", action, chart.name);
-            let err_msg = format!("Failed to {} Helm chart {}", action, chart.name);
-            helm_chart(action, chart).context(err_msg)?;
-            println!("Successfully {} Helm chart: {}", action, chart.name);
+        return state::complete("chart", &chart.name);
+    }
+    println!("{} Helm chart: {}", action, chart.name);
+    let err_msg = format!("Failed to {} Helm chart {}", action, chart.name);
+    let changed = logger::component("helm", &chart.name, action, async {
+        match action {
+            "install" => helm_install_chart(chart).await,
+            _ => helm_chart(action, chart).await.map(|_| true),
         }
+    })
+    .await
+    .context(err_msg)?;
+    if changed {
+        println!("Successfully {} Helm chart: {}", action, chart.name);
+    } else {
+        changes::skip("Helm chart", &chart.name);
     }
+    store::save().await?;
+    state::complete("chart", &chart.name)
+}
+
+/// Run the test hooks of the given Helm release
+///
+/// # Arguments
+///
+/// * `test` - A Helm test with the release name and optional namespace and timeout.
+///
+/// # Examples
+///
+/// ```rust
+/// use loopy::helm::helm_test;
+/// let result = helm_test(&test).await;
+/// assert!(result.is_ok());
+/// ```
+///
+pub async fn helm_test(test: &HelmTest) -> Result<()> {
+    let namespace = test.namespace.as_ref().unwrap_or(&test.release);
+    let timeout = test.timeout.as_deref().unwrap_or("5m0s");
+
+    info!(
+        "Running Helm tests for release {} in {}",
+        test.release, namespace
+    );
+
+    let err_msg = format!("Failed to run Helm tests for release '{}'", test.release);
+    let (stdout, stderr, status) = run_command_streaming(
+        "helm",
+        &[
+            "test",
+            &test.release,
+            "--namespace",
+            namespace,
+            "--timeout",
+            timeout,
+            "--logs",
+        ],
+        &test.release,
+    )
+    .await
+    .context(err_msg)?;
+
+    // Show the test hook output, including the logs of the test Pods.
+    for line in stdout.lines() {
+        println!("[{}] {}", test.release, line);
+    }
+    debug!("stderr: {}", stderr);
+
+    if status.code() != Some(0) {
+        error!("Helm tests failed for release '{}'", test.release);
+        error!("stderr: {}", stderr);
+        return Err(anyhow::anyhow!(
+            "Helm tests failed for release '{}': {}",
+            test.release,
+            stderr.trim()
+        ));
+    }
+
     Ok(())
 }
 
@@ -589,3 +1529,21 @@ mod tests {
     }
 }
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolved_version_changes_the_hash() {
+        let layers = vec![("values.yaml".to_string(), "a: 1\n".to_string())];
+        let flags = ["--wait", "--timeout", "10m0s"];
+        let hash = |version| {
+            helm_inputs_hash("repo/chart", version, "chart", "default", &flags, &layers).unwrap()
+        };
+
+        assert_eq!(hash(Some("1.2.0")), hash(Some("1.2.0")));
+        assert_ne!(hash(Some("1.2.0")), hash(Some("1.3.0")));
+        assert_ne!(hash(None), hash(Some("1.2.0")));
+    }
+}
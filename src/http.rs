@@ -0,0 +1,178 @@
+//! HTTP
+//!
+//! This module contains functions for running HTTP smoke tests against deployed services.
+//!
+
+use crate::config::HttpTest;
+use crate::forward::{forward_temporary, ForwardTarget};
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use regex::Regex;
+use reqwest::{Client, Method, StatusCode, Url};
+use std::time::Duration;
+
+/// Run an HTTP test.
+///
+/// Sends an HTTP request and asserts on the status code, headers and body.
+/// If a Service is defined, a temporary port-forward is opened for the
+/// duration of the test. The request is retried until the endpoint passes
+/// or the configured number of retries is exhausted.
+///
+/// # Arguments
+///
+/// * `test` - The HTTP test to run
+///
+/// # Returns
+///
+/// A `Result` containing;
+///   - `()` if the test passed.
+///   - An error describing the last failure if it did not.
+///
+pub async fn http_test(test: &HttpTest) -> Result<()> {
+    let retries = test.retries.unwrap_or(10);
+    let interval = Duration::from_secs(test.interval.unwrap_or(5));
+    let timeout = Duration::from_secs(test.timeout.unwrap_or(10));
+
+    let err_msg = format!("Invalid URL in HTTP test: {}", test.url);
+    let mut url = Url::parse(&test.url).context(err_msg)?;
+
+    // Keep the port-forward alive until the test completes.
+    let _port_forward = match &test.service {
+        Some(service) => {
+            let namespace = test.namespace.as_deref().unwrap_or("default");
+            let port = test.port.unwrap_or(80);
+
+            let err_msg = format!(
+                "Failed to port-forward to Service {} in namespace {}",
+                service, namespace
+            );
+            let target = ForwardTarget {
+                namespace: namespace.to_string(),
+                service: Some(service.to_string()),
+                pod: None,
+                port,
+            };
+            let port_forward = forward_temporary(target).await.context(err_msg)?;
+
+            // Send the request through the local end of the port-forward, which is plain TCP.
+            url.set_scheme("http")
+                .map_err(|_| anyhow!("Failed to set the scheme on URL: {}", url))?;
+            url.set_host(Some("127.0.0.1"))?;
+            url.set_port(Some(port_forward.local_port))
+                .map_err(|_| anyhow!("Failed to set the port on URL: {}", url))?;
+            debug!("Using port-forwarded URL: {}", url);
+
+            Some(port_forward)
+        }
+        None => None,
+    };
+
+    let client = Client::builder().timeout(timeout).build()?;
+
+    let method = match &test.method {
+        Some(method) => {
+            let err_msg = format!("Invalid HTTP method: {}", method);
+            Method::from_bytes(method.to_uppercase().as_bytes()).context(err_msg)?
+        }
+        None => Method::GET,
+    };
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        debug!("HTTP test attempt {} of {}: {}", attempt, retries + 1, url);
+
+        match http_request(&client, method.clone(), url.clone(), test).await {
+            Ok(_) => {
+                info!("HTTP test passed: {}", url);
+                return Ok(());
+            }
+            Err(e) if attempt <= retries => {
+                warn!(
+                    "HTTP test attempt {} failed, retrying in {} seconds: {}",
+                    attempt,
+                    interval.as_secs(),
+                    e
+                );
+                tokio::time::sleep(interval).await;
+            }
+            Err(e) => {
+                return Err(e).context(format!(
+                    "HTTP test failed after {} attempts: {}",
+                    attempt, test.url
+                ))
+            }
+        }
+    }
+}
+
+/// Send a single HTTP request and check the response.
+///
+/// A helper function for http_test.
+///
+async fn http_request(client: &Client, method: Method, url: Url, test: &HttpTest) -> Result<()> {
+    let err_msg = format!("Failed to send {} request to {}", method, url);
+    let response = client
+        .request(method.clone(), url.clone())
+        .send()
+        .await
+        .context(err_msg)?;
+
+    // Does the status code match the expected status code?
+    let status = response.status();
+    match test.status {
+        Some(expected_status) => {
+            if status != StatusCode::from_u16(expected_status)? {
+                return Err(anyhow!(
+                    "Expected status code: '{}', Actual status code: '{}'",
+                    expected_status,
+                    status.as_u16()
+                ));
+            }
+        }
+        None => {
+            if !status.is_success() {
+                return Err(anyhow!(
+                    "Expected a successful status code, Actual status code: '{}'",
+                    status.as_u16()
+                ));
+            }
+        }
+    }
+
+    // Do the headers match the expected headers?
+    if let Some(expected_headers) = &test.headers {
+        for (name, expected_value) in expected_headers {
+            let actual_value = response
+                .headers()
+                .get(name)
+                .map(|value| value.to_str().unwrap_or_default().to_string());
+
+            if actual_value.as_deref() != Some(expected_value.as_str()) {
+                return Err(anyhow!(
+                    "Expected header '{}': '{}', Actual header: '{}'",
+                    name,
+                    expected_value,
+                    actual_value.unwrap_or_default()
+                ));
+            }
+        }
+    }
+
+    // Does the body match the expected regular expression?
+    if let Some(expected_body) = &test.body {
+        let body = response.text().await?;
+        let regex = Regex::new(expected_body)?;
+
+        if !regex.is_match(&body) {
+            debug!("Response body: {}", body);
+            return Err(anyhow!(
+                "Response body did not match the expression: '{}'",
+                expected_body
+            ));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,277 @@
+//! Init
+//!
+//! This module scaffolds a new loopy project, with the directory layout,
+//! a commented starter configuration and optionally a kind cluster config.
+//!
+
+use crate::config::{self, Chart};
+use crate::helm::{helm_default_values, helm_repo};
+
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+
+/// The directories of a loopy project.
+const PROJECT_DIRS: [&str; 4] = [
+    "config/capi",
+    "config/helm",
+    "config/manifests",
+    "config/carvel",
+];
+
+/// The bundled kind cluster config.
+const KIND_CONFIG: &str = include_str!("../config/capi/kind/config.yaml");
+
+/// The bundled local storage manifests for the kind cluster.
+const KIND_STORAGE: &str = include_str!("../config/capi/kind/hostpath-provisioner.yaml");
+
+/// The choices for a new project.
+#[derive(Debug, Default)]
+pub struct InitOptions {
+    /// Helm repositories as "<name>=<url>".
+    pub repos: Vec<String>,
+    /// Helm charts as "<repo>/<chart>".
+    pub charts: Vec<String>,
+    /// Whether to write a kind cluster config.
+    pub kind: bool,
+    /// Whether to overwrite an existing configuration file.
+    pub force: bool,
+}
+
+/// Initialise a loopy project.
+///
+/// Creates the directory layout and writes a starter configuration. When no
+/// choices are given on the command line and loopy runs in a terminal, the
+/// user is asked for them instead. The default values of each chart are
+/// pulled into its directory under config/helm.
+///
+/// # Arguments
+///
+/// * `config_file` - The path of the configuration file to write
+/// * `options` - The repositories, charts and kind choices
+///
+pub async fn init(config_file: &str, mut options: InitOptions) -> Result<()> {
+    if Path::new(config_file).exists() && !options.force {
+        return Err(anyhow!(
+            "The configuration file {} already exists, use --force to overwrite it.",
+            config_file
+        ));
+    }
+
+    let no_choices = options.repos.is_empty() && options.charts.is_empty() && !options.kind;
+    if no_choices && io::stdin().is_terminal() {
+        init_prompt(&mut options)?;
+    }
+
+    let repos = options
+        .repos
+        .iter()
+        .map(|repo| {
+            repo.split_once('=')
+                .filter(|(name, url)| !name.is_empty() && !url.is_empty())
+                .ok_or_else(|| anyhow!("Invalid repository '{}', use <name>=<url>.", repo))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let charts = options
+        .charts
+        .iter()
+        .map(|chart| {
+            chart
+                .split_once('/')
+                .filter(|(repo, name)| !repo.is_empty() && !name.is_empty())
+                .ok_or_else(|| anyhow!("Invalid chart '{}', use <repo>/<chart>.", chart))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for (repo, name) in &charts {
+        if !repos.iter().any(|(repo_name, _)| repo_name == repo) {
+            return Err(anyhow!(
+                "Chart {} uses the repository '{}', add it with --repo {}=<url>.",
+                name,
+                repo,
+                repo
+            ));
+        }
+    }
+
+    // Create the directory layout.
+    for dir in PROJECT_DIRS {
+        let err_msg = format!("Failed to create directory {}", dir);
+        fs::create_dir_all(dir).context(err_msg)?;
+    }
+
+    // Write the starter configuration.
+    let content = starter_config(&repos, &charts, options.kind);
+    let err_msg = format!("Failed to write {}", config_file);
+    fs::write(config_file, content).context(err_msg)?;
+    println!("Created {}", config_file);
+
+    if options.kind {
+        let kind_dir = Path::new("config/capi/kind");
+        fs::create_dir_all(kind_dir)?;
+        for (file, content) in [
+            ("config.yaml", KIND_CONFIG),
+            ("hostpath-provisioner.yaml", KIND_STORAGE),
+        ] {
+            let path = kind_dir.join(file);
+            if path.exists() && !options.force {
+                println!("Keeping the existing {}", path.display());
+                continue;
+            }
+            let err_msg = format!("Failed to write {}", path.display());
+            fs::write(&path, content).context(err_msg)?;
+            println!("Created {}", path.display());
+        }
+        println!("See docs/kind.md to create the cluster with this config.");
+    }
+
+    // Pull the default values of each chart, so they can be copied and changed.
+    if !charts.is_empty() {
+        for (name, url) in &repos {
+            helm_repo("install", Some(name), Some(url)).await?;
+        }
+        helm_repo("update", None, None).await?;
+
+        for (repo, name) in &charts {
+            let chart = Chart {
+                name: name.to_string(),
+                repo: Some(repo.to_string()),
+                ..Chart::default()
+            };
+            match helm_default_values(&chart).await {
+                Ok(()) => println!(
+                    "Pulled the default values of {} to config/helm/{}",
+                    name, name
+                ),
+                Err(e) => eprintln!("Failed to pull the default values of chart {}: {}", name, e),
+            }
+        }
+    }
+
+    // The starter configuration should always be valid.
+    config::load_config(config_file, None)?;
+
+    println!(
+        "Edit {} and run loopy --action install when ready.",
+        config_file
+    );
+
+    Ok(())
+}
+
+/// Ask the user for the repositories, charts and kind choices.
+fn init_prompt(options: &mut InitOptions) -> Result<()> {
+    println!("Add Helm repositories as <name>=<url>, one per line. Leave empty to finish.");
+    options.repos = prompt_list("repository")?;
+
+    if !options.repos.is_empty() {
+        println!("Add Helm charts as <repo>/<chart>, one per line. Leave empty to finish.");
+        options.charts = prompt_list("chart")?;
+    }
+
+    print!("Create a kind cluster config? [y/N] ");
+    io::stdout().flush()?;
+    let answer = read_line()?.to_lowercase();
+    options.kind = answer == "y" || answer == "yes";
+
+    Ok(())
+}
+
+/// Read entries until an empty line.
+fn prompt_list(kind: &str) -> Result<Vec<String>> {
+    let mut entries = Vec::new();
+    loop {
+        print!("{}: ", kind);
+        io::stdout().flush()?;
+        let entry = read_line()?;
+        if entry.is_empty() {
+            return Ok(entries);
+        }
+        entries.push(entry);
+    }
+}
+
+/// Read a trimmed line from stdin.
+fn read_line() -> Result<String> {
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+/// Build the starter configuration.
+fn starter_config(repos: &[(&str, &str)], charts: &[(&str, &str)], kind: bool) -> String {
+    let repositories = if repos.is_empty() {
+        " []".to_string()
+    } else {
+        repos
+            .iter()
+            .map(|(name, url)| format!("\n      - name: {}\n        url: {}", name, url))
+            .collect()
+    };
+    let chart_entries = if charts.is_empty() {
+        " []".to_string()
+    } else {
+        charts
+            .iter()
+            .map(|(repo, name)| format!("\n      - name: {}\n        repo: {}", name, repo))
+            .collect()
+    };
+    let kind_tool = if kind {
+        "\n    - name: kind\n      bin: kind"
+    } else {
+        ""
+    };
+
+    format!(
+        r#"# yaml-language-server: $schema=https://raw.githubusercontent.com/salt-labs/loopy/main/schema/loopy.schema.json
+---
+version: {version}
+
+log:
+  # One of error, warn, info, debug or trace.
+  level: info
+  # Write the log to a file as well.
+  # file: loopy.log
+
+# The maximum number of manifests and charts processed at the same time.
+parallelism: 4
+
+# Variables for values files and manifests, used as {{{{ vars.<name> }}}}.
+# vars:
+#   domain: kind.local
+
+# The dependencies are installed first, for example an ingress controller or cert-manager.
+dependencies:
+  # Tools that must be in PATH. With a url, a missing tool is downloaded to vendor/.
+  tools:
+    - name: helm
+      bin: helm
+    - name: kubectl
+      bin: kubectl{kind_tool}
+
+  # Manifests from a url, urls, git, a dir under config/manifests or a kustomization.
+  # Use depends_on to order them with the charts.
+  manifests: []
+
+  helm:
+    repositories:{repositories}
+
+    # Charts use config/helm/<name>/values.yaml, or the files listed in values.
+    charts:{chart_entries}
+
+  # Commands, HTTP requests, Pods or Helm tests that check the phase.
+  tests: []
+
+# The application is installed once the dependencies are ready.
+application:
+  manifests: []
+  helm:
+    charts: []
+  tests: []
+"#,
+        version = config::CONFIG_VERSION,
+        kind_tool = kind_tool,
+        repositories = repositories,
+        chart_entries = chart_entries,
+    )
+}
@@ -3,29 +3,46 @@
 //! This module contains functions for interacting with Kubernetes using the kubectl CLI.
 //!
 
-use crate::config::Manifests;
-use crate::utils::run_command;
+use crate::cache;
+use crate::changes;
+use crate::config::{GitSource, Manifests, PodTest};
+use crate::git::git_checkout;
+use crate::logger;
+use crate::secrets;
+use crate::state;
+use crate::store;
+use crate::template;
+use crate::utils::{run_command, run_command_redacted, run_command_with_input};
 use crate::PACKAGE_NAME;
 
 use anyhow::{anyhow, Context, Result};
-use k8s_openapi::api::core::v1::{Namespace, NamespaceSpec};
+use chrono::Local;
+use futures_util::StreamExt;
+use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::api::core::v1::{Container, Namespace, NamespaceSpec, Pod, PodSpec};
 use kube::api::ObjectMeta;
-use kube::api::{DeleteParams, ListParams, PatchParams};
+use kube::api::{DeleteParams, ListParams, LogParams, PatchParams, PostParams, PropagationPolicy};
 use kube::{api::Api, Client};
 use log::{debug, error, info, warn};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::future::Future;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 /// A function that applies or deletes a Kubernetes manifest.
-pub type ApplyFn<'a> =
-    fn(&'a Manifests) -> Box<dyn Future<Output = Result<(), anyhow::Error>> + Send + Unpin + 'a>;
+pub type ApplyFn =
+    for<'a> fn(
+        &'a Manifests,
+    ) -> Box<dyn Future<Output = Result<(), anyhow::Error>> + Send + Unpin + 'a>;
 
 /// Kubectl apply or delete a URL.
 ///
 /// Applies or deletes a Kubernetes manifest from a URL.
+/// The manifest is downloaded through the cache, so kubectl never fetches it itself.
 ///
 /// # Arguments
 ///
@@ -43,30 +60,20 @@ async fn kubectl_url(action: &str, url: &str, dry_run: bool) -> Result<()> {
 
     info!("{}ing Kubernetes manifest from URL: {}", action, url);
 
+    // Download the URL, or use the cached copy if it hasn't changed.
+    let cached_path = cache::fetch(url).await?;
+    let cached_path = cached_path.to_string_lossy();
+
     let args = if dry_run {
-        vec![action, "-f", url, "--dry-run=client"]
+        vec![action, "-f", &cached_path, "--dry-run=client"]
     } else {
-        vec![action, "-f", url]
+        vec![action, "-f", &cached_path]
     };
 
-    // Determine if the URL is valid.
-    let err_msg = format!("Failed to get URL: {}", url);
-    let response = reqwest::get(url).await.context(err_msg)?;
-
-    // Check the status code of the response.
-    if !response.status().is_success() {
-        error!("Failed to get URL: {}", url);
-        error!("Status code: {}", response.status());
-        error!("Response body: {}", response.text().await?);
-        return Err(anyhow::anyhow!("Failed to get URL: {}", url));
-    } else {
-        debug!("URL is valid: {}", url)
-    }
-
     // Apply or delete the manifest file using kubectl
     debug!("{}ing manifest file from URL: {}", action, url);
     let err_msg = format!("Failed to {} manifest file from URL: {}", action, url);
-    let (stdout, stderr, status) = run_command("kubectl", &args).context(err_msg)?;
+    let (stdout, stderr, status) = run_command("kubectl", &args).await.context(err_msg)?;
 
     // A list of allowed errors that can be safely ignored.
     let allowed_errors = ["(NotFound)", "resource mapping not found"];
@@ -105,20 +112,20 @@ async fn kubectl_url(action: &str, url: &str, dry_run: bool) -> Result<()> {
 /// # Arguments
 ///
 /// * `action` - The action to perform, either "apply" or "delete"
-/// * `chart_name` - The name of the chart where the manifest files are located
-/// * `filename` - Optional: The name of the manifest file to apply or delete. If not provided, all files in the chart directory will be applied or deleted.
+/// * `manifest_dir` - The directory where the manifest files are located
+/// * `filename` - Optional: The name of the manifest file to apply or delete. If not provided, all files in the directory will be applied or deleted.
 ///
 /// # Examples
 ///
 /// ```rust
 /// use loopy::kubectl::kubectl_apply_or_delete_manifests;
-/// let result = kubectl_apply_or_delete_manifests("apply", "example-chart", Some("namespace.yaml"));
+/// let result = kubectl_apply_or_delete_manifests("apply", Path::new("config/manifests/example"), Some("namespace.yaml"));
 /// assert!(result.is_ok());
 /// ```
 ///
 async fn kubectl_manifests(
     action: &str,
-    name: &str,
+    manifest_dir: &Path,
     filename: Option<&str>,
     dry_run: bool,
 ) -> Result<()> {
@@ -133,22 +140,33 @@ async fn kubectl_manifests(
         }
     };
 
-    let manifest_dir = format!("config/manifests/{}", name);
-
     if let Some(file) = filename {
-        let manifest_path = format!("{}/{}", manifest_dir, file);
-        info!("{}ing Kubernetes manifest: {}", action, manifest_path);
-        kubectl_manifest_single(action, &manifest_path, dry_run)?;
+        let manifest_path = manifest_dir.join(file);
+        info!(
+            "{}ing Kubernetes manifest: {}",
+            action,
+            manifest_path.display()
+        );
+        let manifest_path = template::render_file(&manifest_path).await?;
+        kubectl_manifest_single(action, &manifest_path.to_string_lossy(), dry_run).await?;
     } else {
         info!(
             "{}ing all Kubernetes manifests in directory: {}",
-            action, manifest_dir
+            action,
+            manifest_dir.display()
         );
 
         // Recursively collect all files through directories.
-        let entries: Vec<_> = std::fs::read_dir(&manifest_dir)?
-            .map(|entry| entry.map(|e| e.path()))
-            .collect::<Result<_, _>>()?;
+        let mut entries = Vec::new();
+        kubectl_collect_manifests(manifest_dir, &mut entries)?;
+        entries.sort();
+
+        // Render any template references in the manifest files.
+        let mut rendered_entries = Vec::new();
+        for entry in &entries {
+            rendered_entries.push(template::render_file(entry).await?);
+        }
+        let entries = rendered_entries;
 
         // Apply or delete the special manifests in their specific order.
         // TODO: Replace this janky manual work with a sorted list using sort_manifest_files
@@ -175,48 +193,236 @@ async fn kubectl_manifests(
             }
         };
 
-        // Apply the special manifests in their specific order.
-        for filename in file_order {
-            if let Some(path) = entries.iter().find(|p| p.ends_with(filename)) {
+        // Apply the special manifests in their specific order, in every nested directory.
+        for filename in &file_order {
+            for path in entries.iter().filter(|p| p.ends_with(filename)) {
                 debug!("{}ing manifest file: {}", action, path.display());
-                kubectl_manifest_single(action, &path.to_string_lossy(), dry_run)?;
+                kubectl_manifest_single(action, &path.to_string_lossy(), dry_run).await?;
             }
         }
 
-        // If this isn't the first time loopy has run, a namespace will already exist.
-        let err_msg = format!("Failed to check if namespace {} exists", PACKAGE_NAME);
-        let namespace_exists = kubectl_namespace_check(PACKAGE_NAME)
-            .await
-            .context(err_msg)?;
-
-        let wait_time = if namespace_exists {
-            debug!("Namespace {} already exists", PACKAGE_NAME);
-            10
-        } else {
-            debug!("Namespace {} does not exist", PACKAGE_NAME);
-            30
-        };
-
-        // TODO: Fix more jankiness
-        // Even more jankiness. Need to sleep in between applying the CRDs
-        // and the rest of the manifests. Otherwise, the CRDs are not
-        // available when the rest of the manifests are applied.
         if action == "apply" {
-            println!("Waiting for CRDs to be available...");
-            info!("Waiting {} seconds for CRDs to be available...", wait_time);
-            std::thread::sleep(std::time::Duration::from_secs(wait_time));
+            kubectl_wait_for_crds(&[]).await?;
         }
 
-        // Apply or delete the rest of the manifest files in the original order.
-        for manifest_path in entries {
+        // Apply or delete the rest of the manifest files in path order.
+        let rest = entries
+            .iter()
+            .filter(|p| !file_order.iter().any(|filename| p.ends_with(filename)));
+        for manifest_path in rest {
             debug!("{}ing manifest file: {}", action, manifest_path.display());
-            kubectl_manifest_single(action, &manifest_path.to_string_lossy(), dry_run)?;
+            kubectl_manifest_single(action, &manifest_path.to_string_lossy(), dry_run).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Collect manifest files.
+///
+/// A helper function for kubectl_manifests.
+///
+/// Recursively collects the YAML and JSON files in a directory and its
+/// nested directories. Hidden files and directories are skipped.
+///
+fn kubectl_collect_manifests(dir: &Path, entries: &mut Vec<PathBuf>) -> Result<()> {
+    let err_msg = format!("Failed to read manifest directory {}", dir.display());
+    for entry in std::fs::read_dir(dir).context(err_msg)? {
+        let path = entry?.path();
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if hidden {
+            continue;
+        }
+
+        if path.is_dir() {
+            kubectl_collect_manifests(&path, entries)?;
+        } else if path
+            .extension()
+            .is_some_and(|extension| ["yaml", "yml", "json"].iter().any(|e| extension == *e))
+        {
+            entries.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Kubectl apply or delete manifests from a git repository.
+///
+/// Checks out the repository and applies or deletes the manifest file or
+/// directory at the configured path, like a manifest directory.
+///
+/// # Arguments
+///
+/// * `action` - The action to perform, either "apply" or "delete"
+/// * `git` - The git repository, reference and path
+///
+async fn kubectl_git(action: &str, git: &GitSource) -> Result<()> {
+    let checkout = git_checkout(&git.url, git.reference.as_deref()).await?;
+    let target = checkout.join(git.path.as_deref().unwrap_or_default());
+
+    match (target.is_file(), target.parent(), target.file_name()) {
+        (true, Some(dir), Some(file)) => {
+            kubectl_manifests(action, dir, Some(&file.to_string_lossy()), false).await
         }
+        _ => kubectl_manifests(action, &target, None, false).await,
     }
+}
+
+/// Wait for CRDs.
+///
+/// Waits for newly applied CustomResourceDefinitions to be available before the
+/// resources that use them are applied.
+///
+/// # Arguments
+///
+/// * `crds` - The names of the CRDs to wait for. When empty, waits for a fixed time instead.
+///
+async fn kubectl_wait_for_crds(crds: &[String]) -> Result<()> {
+    if !crds.is_empty() {
+        println!("Waiting for CRDs to be available...");
+        let names: Vec<String> = crds.iter().map(|name| format!("crd/{}", name)).collect();
+        let mut args = vec!["wait", "--for", "condition=established", "--timeout", "60s"];
+        args.extend(names.iter().map(|name| name.as_str()));
+
+        let (stdout, stderr, status) = run_command("kubectl", &args).await?;
+        debug!("stdout: {}", stdout);
+        debug!("stderr: {}", stderr);
+        if status.code() != Some(0) {
+            return Err(anyhow::anyhow!(
+                "CRDs were not established in time: {}",
+                stderr.trim()
+            ));
+        }
+        return Ok(());
+    }
+
+    // If an install has completed before, the CRDs usually exist already.
+    let installed = store::current().is_some_and(|store| store.installed.is_some());
+    let wait_time = if installed {
+        debug!("An earlier install was recorded in the state store");
+        10
+    } else {
+        debug!("No earlier install was recorded in the state store");
+        30
+    };
+
+    // TODO: Fix more jankiness
+    // Even more jankiness. Need to sleep in between applying the CRDs
+    // and the rest of the manifests. Otherwise, the CRDs are not
+    // available when the rest of the manifests are applied.
+    println!("Waiting for CRDs to be available...");
+    info!("Waiting {} seconds for CRDs to be available...", wait_time);
+    tokio::time::sleep(Duration::from_secs(wait_time)).await;
 
     Ok(())
 }
 
+/// Kubectl apply or delete a kustomization.
+///
+/// Builds a kustomization with `kubectl kustomize` and applies or deletes the
+/// result. Resources are ordered by kind like the special manifest files:
+/// namespaces and CRDs first, then RBAC, webhooks and everything else.
+/// The build output is kept in memory, as it may contain generated Secrets.
+///
+/// # Arguments
+///
+/// * `action` - The action to perform, either "apply" or "delete"
+/// * `target` - The kustomization directory under config/manifests, or a remote URL
+///
+async fn kubectl_kustomize(action: &str, target: &str) -> Result<()> {
+    let (target, rendered) = kubectl_kustomize_build(target).await?;
+
+    // Order the resources by kind.
+    let mut documents = Vec::new();
+    for document in serde_yaml::Deserializer::from_str(&rendered) {
+        let value = serde_yaml::Value::deserialize(document)
+            .with_context(|| format!("Failed to parse the kustomization {}", target))?;
+        if value.is_null() {
+            continue;
+        }
+        let kind = value["kind"].as_str().unwrap_or_default().to_string();
+        documents.push((kubectl_kind_order(&kind), kind, value));
+    }
+    documents.sort_by_key(|(order, _, _)| *order);
+    if action == "delete" {
+        documents.reverse();
+    }
+
+    let crds: Vec<String> = documents
+        .iter()
+        .filter(|(_, kind, _)| kind == "CustomResourceDefinition")
+        .filter_map(|(_, _, value)| value["metadata"]["name"].as_str().map(|s| s.to_string()))
+        .collect();
+
+    // Apply namespaces and CRDs first, so they are available for the rest.
+    let (first, rest): (Vec<_>, Vec<_>) = documents
+        .into_iter()
+        .partition(|(order, _, _)| (*order <= 1) == (action == "apply"));
+    for (number, batch) in [first, rest].into_iter().enumerate() {
+        if batch.is_empty() {
+            continue;
+        }
+        let mut content = String::new();
+        for (_, _, value) in &batch {
+            content.push_str("---\n");
+            content.push_str(&serde_yaml::to_string(value)?);
+        }
+        kubectl_run_manifest(action, &target, "-", Some(content.as_bytes()), false).await?;
+
+        if action == "apply" && number == 0 && !crds.is_empty() {
+            kubectl_wait_for_crds(&crds).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Build a kustomization.
+///
+/// # Arguments
+///
+/// * `target` - The kustomization directory under config/manifests, or a remote URL
+///
+/// # Returns
+///
+/// The resolved target and the rendered resources.
+///
+async fn kubectl_kustomize_build(target: &str) -> Result<(String, String)> {
+    let target = if target.contains("://") || target.starts_with("github.com/") {
+        target.to_string()
+    } else {
+        format!("config/manifests/{}", target)
+    };
+
+    info!("Building kustomization: {}", target);
+    let (stdout, stderr, status) =
+        run_command_redacted("kubectl", &["kustomize", &target], None).await?;
+    if status.code() != Some(0) {
+        return Err(anyhow::anyhow!(
+            "Failed to build kustomization {}: {}",
+            target,
+            stderr.trim()
+        ));
+    }
+    let rendered = template::render(&stdout).await?;
+
+    Ok((target, rendered))
+}
+
+/// The order in which a kind of resource is applied.
+fn kubectl_kind_order(kind: &str) -> u8 {
+    match kind {
+        "Namespace" => 0,
+        "CustomResourceDefinition" => 1,
+        "ServiceAccount" | "Role" | "ClusterRole" | "RoleBinding" | "ClusterRoleBinding" => 2,
+        "MutatingWebhookConfiguration" | "ValidatingWebhookConfiguration" => 3,
+        _ => 4,
+    }
+}
+
 /// Sort manifest files based on the action and user-defined priorities.
 ///
 /// A helper function for kubectl_manifests.
@@ -320,13 +526,7 @@ fn _sort_manifest_files(
 /// * `manifest_path` - The path to the manifest file to apply or delete
 /// * `dry_run` - If true, the manifests will be applied in dry-run mode
 ///
-fn kubectl_manifest_single(action: &str, manifest_path: &str, dry_run: bool) -> Result<()> {
-    let args = if dry_run {
-        vec![action, "-f", manifest_path, "--dry-run=client"]
-    } else {
-        vec![action, "-f", manifest_path]
-    };
-
+async fn kubectl_manifest_single(action: &str, manifest_path: &str, dry_run: bool) -> Result<()> {
     // Ensure the file or directory exists before applying or deleting it.
     if !std::path::Path::new(manifest_path).exists() {
         error!(
@@ -339,9 +539,53 @@ fn kubectl_manifest_single(action: &str, manifest_path: &str, dry_run: bool) ->
         ));
     }
 
+    // Encrypted manifests are decrypted in memory and passed on stdin.
+    if secrets::is_encrypted(Path::new(manifest_path)) {
+        let decrypted = secrets::decrypt_file(Path::new(manifest_path)).await?;
+        kubectl_run_manifest(
+            action,
+            manifest_path,
+            "-",
+            Some(decrypted.as_bytes()),
+            dry_run,
+        )
+        .await
+    } else {
+        kubectl_run_manifest(action, manifest_path, manifest_path, None, dry_run).await
+    }
+}
+
+/// Kubectl apply or delete manifest content.
+///
+/// A helper function for kubectl_manifest_single and kubectl_kustomize.
+///
+/// # Arguments
+///
+/// * `action` - The action to perform, either "apply" or "delete"
+/// * `label` - The name of the manifest in messages
+/// * `source` - The file to pass to kubectl, or "-" for the input
+/// * `input` - The manifest content when the source is "-"
+/// * `dry_run` - If true, the manifests will be applied in dry-run mode
+///
+async fn kubectl_run_manifest(
+    action: &str,
+    label: &str,
+    source: &str,
+    input: Option<&[u8]>,
+    dry_run: bool,
+) -> Result<()> {
+    let manifest_path = label;
+    let args = if dry_run {
+        vec![action, "-f", source, "--dry-run=client"]
+    } else {
+        vec![action, "-f", source]
+    };
+
     // Apply or delete the manifest file using kubectl
     let err_msg = format!("Failed to {} manifest: {}", action, manifest_path);
-    let (stdout, stderr, status) = run_command("kubectl", &args).expect(&err_msg);
+    let (stdout, stderr, status) = run_command_with_input("kubectl", &args, input)
+        .await
+        .context(err_msg)?;
 
     // A list of allowed errors that can be safely ignored.
     let allowed_errors = ["(NotFound)", "resource mapping not found"];
@@ -389,24 +633,29 @@ fn kubectl_manifest_single(action: &str, manifest_path: &str, dry_run: bool) ->
 /// ```
 ///
 pub async fn kubectl_apply_manifest(manifest: &Manifests) -> Result<()> {
-    match &manifest.url {
-        Some(url) => {
-            let err_msg = format!(
-                "Failed to apply Kubernetes manifests {} URL {}",
-                manifest.name, url
-            );
-            kubectl_url("apply", url, false).await.context(err_msg)?;
-            println!(
-                "Successfully applied Kubernetes manifests {} URL {}",
-                manifest.name, url
-            )
-        }
-        None => {
-            debug!(
-                "No URL provided for Kubernetes manifests {}, skipping.",
-                manifest.name
-            )
-        }
+    for url in manifest.urls() {
+        let err_msg = format!(
+            "Failed to apply Kubernetes manifests {} URL {}",
+            manifest.name, url
+        );
+        kubectl_url("apply", url, false).await.context(err_msg)?;
+        println!(
+            "Successfully applied Kubernetes manifests {} URL {}",
+            manifest.name, url
+        )
+    }
+
+    // If a git repository was provided, apply it next.
+    if let Some(git) = &manifest.git {
+        let err_msg = format!(
+            "Failed to apply Kubernetes manifests {} from git repository {}",
+            manifest.name, git.url
+        );
+        kubectl_git("apply", git).await.context(err_msg)?;
+        println!(
+            "Successfully applied Kubernetes manifests {} from git repository {}",
+            manifest.name, git.url
+        )
     }
 
     // If a directory was provided, apply that next.
@@ -417,7 +666,8 @@ pub async fn kubectl_apply_manifest(manifest: &Manifests) -> Result<()> {
                 manifest.name, dir
             );
             // action, name, filename, dry_run
-            kubectl_manifests("apply", dir, None, false)
+            let manifest_dir = Path::new("config/manifests").join(dir);
+            kubectl_manifests("apply", &manifest_dir, None, false)
                 .await
                 .context(err_msg)?;
             println!(
@@ -433,6 +683,19 @@ pub async fn kubectl_apply_manifest(manifest: &Manifests) -> Result<()> {
         }
     }
 
+    // If a kustomization was provided, build and apply it last.
+    if let Some(target) = &manifest.kustomize {
+        let err_msg = format!(
+            "Failed to apply Kubernetes manifests {} using kustomization {}",
+            manifest.name, target
+        );
+        kubectl_kustomize("apply", target).await.context(err_msg)?;
+        println!(
+            "Successfully applied Kubernetes manifests {} using kustomization {}",
+            manifest.name, target
+        )
+    }
+
     Ok(())
 }
 
@@ -453,24 +716,29 @@ pub async fn kubectl_apply_manifest(manifest: &Manifests) -> Result<()> {
 /// ```
 ///
 pub async fn kubectl_delete_manifest(manifest: &Manifests) -> Result<()> {
-    match &manifest.url {
-        Some(url) => {
-            let err_msg = format!(
-                "Failed to remove Kubernetes manifests {} URL {}",
-                manifest.name, url
-            );
-            kubectl_url("delete", url, false).await.context(err_msg)?;
-            println!(
-                "Successfully removed Kubernetes manifests {} URL {}",
-                manifest.name, url
-            )
-        }
-        None => {
-            debug!(
-                "No URL provided for Kubernetes manifests {}, skipping.",
-                manifest.name
-            )
-        }
+    for url in manifest.urls() {
+        let err_msg = format!(
+            "Failed to remove Kubernetes manifests {} URL {}",
+            manifest.name, url
+        );
+        kubectl_url("delete", url, false).await.context(err_msg)?;
+        println!(
+            "Successfully removed Kubernetes manifests {} URL {}",
+            manifest.name, url
+        )
+    }
+
+    // If a git repository was provided, delete it next.
+    if let Some(git) = &manifest.git {
+        let err_msg = format!(
+            "Failed to remove Kubernetes manifests {} from git repository {}",
+            manifest.name, git.url
+        );
+        kubectl_git("delete", git).await.context(err_msg)?;
+        println!(
+            "Successfully removed Kubernetes manifests {} from git repository {}",
+            manifest.name, git.url
+        )
     }
 
     // If a directory was provided, delete that next.
@@ -480,7 +748,8 @@ pub async fn kubectl_delete_manifest(manifest: &Manifests) -> Result<()> {
                 "Failed to remove Kubernetes manifests {} using directory {}",
                 manifest.name, dir
             );
-            kubectl_manifests("delete", dir, None, false)
+            let manifest_dir = Path::new("config/manifests").join(dir);
+            kubectl_manifests("delete", &manifest_dir, None, false)
                 .await
                 .context(err_msg)?;
             println!(
@@ -496,6 +765,232 @@ pub async fn kubectl_delete_manifest(manifest: &Manifests) -> Result<()> {
         }
     }
 
+    // If a kustomization was provided, build and delete it last.
+    if let Some(target) = &manifest.kustomize {
+        let err_msg = format!(
+            "Failed to remove Kubernetes manifests {} using kustomization {}",
+            manifest.name, target
+        );
+        kubectl_kustomize("delete", target).await.context(err_msg)?;
+        println!(
+            "Successfully removed Kubernetes manifests {} using kustomization {}",
+            manifest.name, target
+        )
+    }
+
+    Ok(())
+}
+
+/// The state of the resources of a manifest in the cluster.
+#[derive(Debug, Default)]
+pub struct ResourceStatus {
+    /// The number of resources in the manifest.
+    pub total: usize,
+    /// The number of those resources that exist in the cluster.
+    pub found: usize,
+    /// The ready Pods of the Deployments, StatefulSets and DaemonSets.
+    pub ready: u64,
+    /// The desired Pods of the Deployments, StatefulSets and DaemonSets.
+    pub desired: u64,
+    /// The resources that exist in the cluster.
+    pub objects: Vec<ObjectRef>,
+}
+
+/// A reference to a resource in the cluster.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectRef {
+    /// The API version of the resource.
+    pub api_version: String,
+    /// The kind of the resource.
+    pub kind: String,
+    /// The namespace of the resource, if it is namespaced.
+    pub namespace: Option<String>,
+    /// The name of the resource.
+    pub name: String,
+}
+
+impl std::fmt::Display for ObjectRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.namespace {
+            Some(namespace) => write!(f, "{}/{} in {}", self.kind, self.name, namespace),
+            None => write!(f, "{}/{}", self.kind, self.name),
+        }
+    }
+}
+
+/// Kubectl manifest content.
+///
+/// Collects the resources of every source of a manifest set, rendered and
+/// decrypted as they are applied.
+///
+/// # Arguments
+///
+/// * `manifest` - The manifest set to collect
+///
+pub async fn kubectl_manifest_content(manifest: &Manifests) -> Result<String> {
+    let mut files = Vec::new();
+    for url in manifest.urls() {
+        files.push(cache::fetch(url).await?);
+    }
+    if let Some(git) = &manifest.git {
+        let checkout = git_checkout(&git.url, git.reference.as_deref()).await?;
+        let target = checkout.join(git.path.as_deref().unwrap_or_default());
+        if target.is_file() {
+            files.push(target);
+        } else {
+            let mut entries = Vec::new();
+            kubectl_collect_manifests(&target, &mut entries)?;
+            entries.sort();
+            files.extend(entries);
+        }
+    }
+    if let Some(dir) = &manifest.dir {
+        let mut entries = Vec::new();
+        kubectl_collect_manifests(&Path::new("config/manifests").join(dir), &mut entries)?;
+        entries.sort();
+        files.extend(entries);
+    }
+
+    let mut content = String::new();
+    for file in files {
+        let file = template::render_file(&file).await?;
+        let document = if secrets::is_encrypted(&file) {
+            secrets::decrypt_file(&file).await?
+        } else {
+            let err_msg = format!("Failed to read manifest {}", file.display());
+            std::fs::read_to_string(&file).context(err_msg)?
+        };
+        content.push_str("---\n");
+        content.push_str(&document);
+        content.push('\n');
+    }
+    if let Some(target) = &manifest.kustomize {
+        let (_, rendered) = kubectl_kustomize_build(target).await?;
+        content.push_str("---\n");
+        content.push_str(&rendered);
+    }
+
+    Ok(content)
+}
+
+/// Kubectl resource status.
+///
+/// Looks up the resources of a manifest in the cluster, and counts the ready
+/// and desired Pods of its workloads.
+///
+/// # Arguments
+///
+/// * `content` - The manifest, with one or more resources
+/// * `namespace` - The namespace of resources without one, or the current namespace
+///
+pub async fn kubectl_resource_status(
+    content: &str,
+    namespace: Option<&str>,
+) -> Result<ResourceStatus> {
+    let mut resource_status = ResourceStatus::default();
+    for document in serde_yaml::Deserializer::from_str(content) {
+        let value = serde_yaml::Value::deserialize(document).context("Failed to parse manifest")?;
+        if value["kind"].as_str().is_some_and(|kind| !kind.is_empty()) {
+            resource_status.total += 1;
+        }
+    }
+    if resource_status.total == 0 {
+        return Ok(resource_status);
+    }
+
+    let mut args = vec!["get", "-f", "-", "--ignore-not-found", "-o", "json"];
+    if let Some(namespace) = namespace {
+        args.extend(["--namespace", namespace]);
+    }
+    let (stdout, stderr, status) =
+        run_command_with_input("kubectl", &args, Some(content.as_bytes())).await?;
+    debug!("stderr: {}", stderr);
+
+    // Resources of kinds the cluster doesn't know yet are missing, not an error.
+    if stdout.trim().is_empty() {
+        if status.code() != Some(0) && !stderr.contains("resource mapping not found") {
+            return Err(anyhow!("Failed to get resources: {}", stderr.trim()));
+        }
+        return Ok(resource_status);
+    }
+    let output: serde_json::Value =
+        serde_json::from_str(&stdout).context("Failed to parse the kubectl output")?;
+    let items = match output["kind"].as_str() {
+        Some("List") => output["items"].as_array().cloned().unwrap_or_default(),
+        _ => vec![output],
+    };
+
+    for item in items {
+        resource_status.found += 1;
+        resource_status.objects.push(ObjectRef {
+            api_version: item["apiVersion"].as_str().unwrap_or_default().to_string(),
+            kind: item["kind"].as_str().unwrap_or_default().to_string(),
+            namespace: item["metadata"]["namespace"]
+                .as_str()
+                .map(|s| s.to_string()),
+            name: item["metadata"]["name"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+        });
+        let (ready, desired) = match item["kind"].as_str().unwrap_or_default() {
+            "Deployment" | "StatefulSet" => (
+                item["status"]["readyReplicas"].as_u64().unwrap_or(0),
+                item["spec"]["replicas"].as_u64().unwrap_or(1),
+            ),
+            "DaemonSet" => (
+                item["status"]["numberReady"].as_u64().unwrap_or(0),
+                item["status"]["desiredNumberScheduled"]
+                    .as_u64()
+                    .unwrap_or(0),
+            ),
+            _ => (0, 0),
+        };
+        resource_status.ready += ready;
+        resource_status.desired += desired;
+    }
+
+    Ok(resource_status)
+}
+
+/// Kubectl delete objects.
+///
+/// Deletes the given resources, ignoring those that are already gone.
+///
+/// # Arguments
+///
+/// * `objects` - The resources to delete
+///
+pub async fn kubectl_delete_objects(objects: &[ObjectRef]) -> Result<()> {
+    if objects.is_empty() {
+        return Ok(());
+    }
+
+    let mut content = String::new();
+    for object in objects {
+        let mut metadata = json!({ "name": object.name });
+        if let Some(namespace) = &object.namespace {
+            metadata["namespace"] = json!(namespace);
+        }
+        let document = json!({
+            "apiVersion": object.api_version,
+            "kind": object.kind,
+            "metadata": metadata,
+        });
+        content.push_str("---\n");
+        content.push_str(&serde_yaml::to_string(&document)?);
+    }
+
+    let args = ["delete", "-f", "-", "--ignore-not-found"];
+    let (stdout, stderr, status) =
+        run_command_with_input("kubectl", &args, Some(content.as_bytes())).await?;
+    debug!("stdout: {}", stdout);
+    debug!("stderr: {}", stderr);
+
+    if status.code() != Some(0) {
+        return Err(anyhow!("Failed to delete resources: {}", stderr.trim()));
+    }
+
     Ok(())
 }
 
@@ -690,47 +1185,389 @@ pub async fn kubectl_namespace_delete(name: &str) -> Result<()> {
     }
 }
 
-/// Process Kubernetes manifests.
+/// Process Kubernetes manifest.
 ///
-/// Applies Kubernetes manifests.
+/// Applies or deletes the Kubernetes manifests of a single entry.
 /// Returns an error if the application fails.
 ///
 /// # Arguments
 ///
-/// * `manifests` - The Kubernetes manifests to apply
-/// * `action` - The action to perform on the manifests
+/// * `manifest` - The Kubernetes manifest to apply
+/// * `action` - The action to perform on the manifest
+/// * `apply_fn` - The function that applies or deletes the manifest
 ///
 /// # Examples
 ///
 /// ```rust
-/// use loopy::kubectl::process_manifests;
-/// let result = process_manifests(&manifests, "apply");
+/// use loopy::kubectl::kubectl_process_manifest;
+/// let result = kubectl_process_manifest(&manifest, "install", apply_fn).await;
 /// assert!(result.is_ok());
 /// ```
 ///
-pub async fn kubectl_process_manifests<'a>(
-    manifests: &'a [Manifests],
+pub async fn kubectl_process_manifest(
+    manifest: &Manifests,
     action: &str,
-    apply_fn: ApplyFn<'a>,
+    apply_fn: ApplyFn,
 ) -> Result<()> {
-    if manifests.is_empty() {
+    if state::is_completed("manifest", &manifest.name) {
+        return Ok(());
+    }
+    if action == "uninstall" && !store::is_owned("manifest", &manifest.name) {
         println!(
-            "No Kubernetes {} manifests were found in the configuration file. Skipping...",
-            action
+            "Skipping Kubernetes manifests {}, they were not installed by {}",
+            manifest.name, PACKAGE_NAME
         );
+        return state::complete("manifest", &manifest.name);
+    }
+    println!("{} Kubernetes manifests for: {}", action, manifest.name);
+    let err_msg = format!(
+        "Failed to {} Kubernetes manifests for {}",
+        action, manifest.name
+    );
+
+    // Skip applying the manifests if their content hasn't changed and all of their resources exist.
+    let mut applied = None;
+    if action == "install" {
+        let content = kubectl_manifest_content(manifest)
+            .await
+            .context(err_msg.clone())?;
+        let hash = changes::hash_inputs(content.as_bytes()).context(err_msg.clone())?;
+        if changes::is_unchanged("manifest", &manifest.name, &hash) {
+            let resources = kubectl_resource_status(&content, None)
+                .await
+                .context(err_msg.clone())?;
+            if resources.found == resources.total {
+                changes::skip("Kubernetes manifests", &manifest.name);
+                return state::complete("manifest", &manifest.name);
+            }
+        }
+        applied = Some((content, hash));
+    }
+    store::clear_hash("manifest", &manifest.name);
+
+    logger::component("manifest", &manifest.name, action, apply_fn(manifest))
+        .await
+        .context(err_msg.clone())?;
+
+    // Record the applied resources, so an uninstall knows exactly what loopy owns.
+    match applied {
+        Some((content, hash)) => {
+            let resources = kubectl_resource_status(&content, None)
+                .await
+                .context(err_msg)?;
+            let now = Local::now();
+            store::record(store::Component {
+                phase: state::phase(),
+                kind: "manifest".to_string(),
+                name: manifest.name.clone(),
+                release: None,
+                namespace: None,
+                version: None,
+                revision: None,
+                hash: Some(hash),
+                objects: resources.objects,
+                installed: now,
+                updated: now,
+            });
+        }
+        None => store::remove("manifest", &manifest.name),
+    }
+    store::save().await?;
+    println!(
+        "Successfully {} Kubernetes manifest: {}",
+        action, manifest.name
+    );
+    state::complete("manifest", &manifest.name)
+}
+
+/// Run a test Pod.
+///
+/// Launches a Pod or Job from a manifest, or a Pod from an inline image and command,
+/// waits for it to complete and streams its logs to the output and log file.
+/// A random suffix is added to the name, so runs never collide with a leftover
+/// Pod or Job. The Pod or Job is deleted afterwards, whatever the outcome.
+///
+/// # Arguments
+///
+/// * `test` - The Pod test to run
+///
+/// # Returns
+///
+/// A `Result` containing the exit code of the first container of a Pod, or
+/// for a Job, 0 when it succeeded and 1 when it failed.
+///
+pub async fn kubectl_run_test_pod(test: &PodTest) -> Result<i32> {
+    let client = Client::try_default().await?;
+
+    let (namespace, pod_name, job_name) = kubectl_test_pod_create(&client, test).await?;
+
+    let result = kubectl_test_pod_wait(&client, &namespace, &pod_name, job_name.as_deref(), test)
+        .await
+        .with_context(|| format!("Test Pod {} in namespace {} failed", pod_name, namespace));
+
+    // Clean up the test resources, whatever the outcome.
+    let delete_params = DeleteParams {
+        propagation_policy: Some(PropagationPolicy::Background),
+        ..DeleteParams::default()
+    };
+    let cleanup = match &job_name {
+        Some(job_name) => {
+            info!("Deleting test Job {} in namespace {}", job_name, namespace);
+            let jobs: Api<Job> = Api::namespaced(client.clone(), &namespace);
+            jobs.delete(job_name, &delete_params).await.map(|_| ())
+        }
+        None => {
+            info!("Deleting test Pod {} in namespace {}", pod_name, namespace);
+            let pods: Api<Pod> = Api::namespaced(client.clone(), &namespace);
+            pods.delete(&pod_name, &delete_params).await.map(|_| ())
+        }
+    };
+    if let Err(e) = cleanup {
+        warn!("Failed to clean up test Pod {}: {}", pod_name, e);
+    }
+
+    result
+}
+
+/// Create the test Pod or Job.
+///
+/// A helper function for kubectl_run_test_pod.
+///
+/// # Returns
+///
+/// A tuple of the namespace, the Pod name and the Job name if a Job was created.
+///
+async fn kubectl_test_pod_create(
+    client: &Client,
+    test: &PodTest,
+) -> Result<(String, String, Option<String>)> {
+    let suffix = Alphanumeric
+        .sample_string(&mut rand::thread_rng(), 5)
+        .to_lowercase();
+
+    let document = match &test.manifest {
+        Some(manifest) => {
+            let manifest_path = format!("config/manifests/{}", manifest);
+            let err_msg = format!("Failed to read test manifest: {}", manifest_path);
+            let content = std::fs::read_to_string(&manifest_path).context(err_msg)?;
+
+            // Use the first Pod or Job defined in the manifest file.
+            let mut document = None;
+            for doc in serde_yaml::Deserializer::from_str(&content) {
+                let value = serde_yaml::Value::deserialize(doc)?;
+                match value.get("kind").and_then(|kind| kind.as_str()) {
+                    Some("Pod") | Some("Job") if document.is_none() => document = Some(value),
+                    Some(kind) => debug!("Ignoring {} in test manifest {}", kind, manifest_path),
+                    None => {}
+                }
+            }
+            document.ok_or_else(|| anyhow!("No Pod or Job was found in {}", manifest_path))?
+        }
+        None => {
+            let pod = Pod {
+                metadata: ObjectMeta {
+                    name: Some(format!("{}-test", PACKAGE_NAME)),
+                    ..Default::default()
+                },
+                spec: Some(PodSpec {
+                    restart_policy: Some("Never".to_string()),
+                    containers: vec![Container {
+                        name: "test".to_string(),
+                        image: test.image.clone(),
+                        command: test.command.clone(),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            serde_yaml::to_value(pod)?
+        }
+    };
+
+    let namespace = test
+        .namespace
+        .clone()
+        .or_else(|| {
+            document["metadata"]["namespace"]
+                .as_str()
+                .map(|namespace| namespace.to_string())
+        })
+        .unwrap_or_else(|| "default".to_string());
+
+    if document["kind"].as_str() == Some("Job") {
+        let mut job: Job = serde_yaml::from_value(document)?;
+        job.metadata.namespace = Some(namespace.clone());
+        job.metadata.name = kubectl_test_name(job.metadata.name.take(), &suffix);
+        let job_name = job.metadata.name.clone().unwrap_or_default();
+
+        info!("Creating test Job {} in namespace {}", job_name, namespace);
+        let jobs: Api<Job> = Api::namespaced(client.clone(), &namespace);
+        let err_msg = format!("Failed to create test Job {}", job_name);
+        jobs.create(&PostParams::default(), &job)
+            .await
+            .context(err_msg)?;
+
+        // Find the Pod that the Job controller created.
+        let pods: Api<Pod> = Api::namespaced(client.clone(), &namespace);
+        let list_params = ListParams::default().labels(&format!("job-name={}", job_name));
+        for _ in 0..30 {
+            if let Some(pod) = pods.list(&list_params).await?.items.into_iter().next() {
+                let pod_name = pod.metadata.name.unwrap_or_default();
+                return Ok((namespace, pod_name, Some(job_name)));
+            }
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+
+        Err(anyhow!("No Pod was created for test Job {}", job_name))
     } else {
-        for manifest in manifests {
-            println!("{} Kubernetes manifests for: {}", action, manifest.name);
-            let err_msg = format!(
-                "Failed to {} Kubernetes manifests for {}",
-                action, manifest.name
-            );
-            apply_fn(manifest).await.context(err_msg)?;
-            println!(
-                "Successfully {} Kubernetes manifest: {}",
-                action, manifest.name
-            );
+        let mut pod: Pod = serde_yaml::from_value(document)?;
+        pod.metadata.namespace = Some(namespace.clone());
+        pod.metadata.name = kubectl_test_name(pod.metadata.name.take(), &suffix);
+        let pod_name = pod.metadata.name.clone().unwrap_or_default();
+
+        info!("Creating test Pod {} in namespace {}", pod_name, namespace);
+        let pods: Api<Pod> = Api::namespaced(client.clone(), &namespace);
+        let err_msg = format!("Failed to create test Pod {}", pod_name);
+        pods.create(&PostParams::default(), &pod)
+            .await
+            .context(err_msg)?;
+
+        Ok((namespace, pod_name, None))
+    }
+}
+
+/// Add the random suffix of a test run to the name of a test Pod or Job.
+///
+/// The name is shortened to keep it a valid label value, as the Job controller
+/// labels its Pods with the Job name.
+///
+fn kubectl_test_name(name: Option<String>, suffix: &str) -> Option<String> {
+    let name = name.unwrap_or_else(|| format!("{}-test", PACKAGE_NAME));
+    let base: String = name.chars().take(62 - suffix.len()).collect();
+
+    Some(format!("{}-{}", base.trim_end_matches(['-', '.']), suffix))
+}
+
+/// Wait for the test Pod to complete.
+///
+/// A helper function for kubectl_run_test_pod.
+/// Streams the Pod logs once it has started, then returns the exit code
+/// of the first container, or the outcome of the Job.
+///
+async fn kubectl_test_pod_wait(
+    client: &Client,
+    namespace: &str,
+    pod_name: &str,
+    job_name: Option<&str>,
+    test: &PodTest,
+) -> Result<i32> {
+    let pods: Api<Pod> = Api::namespaced(client.clone(), namespace);
+    let timeout = Duration::from_secs(test.timeout.unwrap_or(300));
+    let started = Instant::now();
+
+    // Wait for the Pod to leave the Pending phase before following the logs.
+    loop {
+        let pod = pods.get(pod_name).await?;
+        let phase = pod
+            .status
+            .and_then(|status| status.phase)
+            .unwrap_or_default();
+        debug!("Test Pod {} is in phase {}", pod_name, phase);
+
+        if phase != "Pending" && !phase.is_empty() {
+            break;
+        }
+        if started.elapsed() > timeout {
+            return Err(anyhow!(
+                "Timed out waiting for test Pod {} to start",
+                pod_name
+            ));
         }
+        tokio::time::sleep(Duration::from_secs(2)).await;
+    }
+
+    // Stream the logs until the container exits.
+    let log_params = LogParams {
+        follow: true,
+        ..LogParams::default()
+    };
+    let prefix = job_name.unwrap_or(pod_name);
+    let mut stream = pods.log_stream(pod_name, &log_params).await?.boxed();
+    let mut buffer = String::new();
+    while let Some(chunk) =
+        tokio::time::timeout(timeout.saturating_sub(started.elapsed()), stream.next())
+            .await
+            .map_err(|_| anyhow!("Timed out waiting for test Pod {} to complete", pod_name))?
+    {
+        buffer.push_str(&String::from_utf8_lossy(&chunk?));
+        while let Some(index) = buffer.find('\n') {
+            let line: String = buffer.drain(..=index).collect();
+            println!("[{}] {}", prefix, line.trim_end());
+            info!("[{}] {}", prefix, line.trim_end());
+        }
+    }
+    if !buffer.is_empty() {
+        println!("[{}] {}", prefix, buffer.trim_end());
+        info!("[{}] {}", prefix, buffer.trim_end());
+    }
+
+    // A Job may retry its Pod, so its own status decides the outcome.
+    if let Some(job_name) = job_name {
+        let jobs: Api<Job> = Api::namespaced(client.clone(), namespace);
+        loop {
+            let status = jobs.get(job_name).await?.status.unwrap_or_default();
+            let condition = |kind: &str| {
+                status
+                    .conditions
+                    .iter()
+                    .flatten()
+                    .any(|condition| condition.type_ == kind && condition.status == "True")
+            };
+
+            if status.succeeded.unwrap_or(0) > 0 || condition("Complete") {
+                info!("Test Job {} succeeded", job_name);
+                return Ok(0);
+            }
+            if condition("Failed") {
+                info!(
+                    "Test Job {} failed after {} attempts",
+                    job_name,
+                    status.failed.unwrap_or(0)
+                );
+                return Ok(1);
+            }
+            if started.elapsed() > timeout {
+                return Err(anyhow!(
+                    "Timed out waiting for test Job {} to complete",
+                    job_name
+                ));
+            }
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+    }
+
+    // The log stream can end before the status is updated, so poll for the exit code.
+    loop {
+        let pod = pods.get(pod_name).await?;
+        let exit_code = pod
+            .status
+            .and_then(|status| status.container_statuses)
+            .and_then(|statuses| statuses.into_iter().next())
+            .and_then(|status| status.state)
+            .and_then(|state| state.terminated)
+            .map(|terminated| terminated.exit_code);
+
+        if let Some(exit_code) = exit_code {
+            info!("Test Pod {} exited with code {}", pod_name, exit_code);
+            return Ok(exit_code);
+        }
+        if started.elapsed() > timeout {
+            return Err(anyhow!(
+                "Timed out waiting for test Pod {} to complete",
+                pod_name
+            ));
+        }
+        tokio::time::sleep(Duration::from_secs(2)).await;
     }
-    Ok(())
 }
@@ -1,44 +1,229 @@
 //! Logging setup.
 //!
 //! This module contains the setup_logging function, which sets up the logger
-//! for the application.
+//! for the application, and the log context that adds structured fields
+//! describing the phase and component being processed to each log record.
 //!
 
 use chrono::Local;
-use colored::*;
-use log::Level;
 use log::LevelFilter;
+use serde_json::{json, Map, Value};
+use std::future::Future;
+use std::time::Instant;
+
+tokio::task_local! {
+    /// The log context of the currently running task.
+    static LOG_CONTEXT: LogContext;
+}
+
+/// Log output formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Plain human readable text.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "Invalid log format '{}', only 'text' or 'json' are allowed",
+                format
+            )),
+        }
+    }
+}
+
+/// Structured fields attached to every log record of a task.
+#[derive(Debug, Clone, Default)]
+pub struct LogContext {
+    /// The phase being processed, for example "dependencies" or "application".
+    pub phase: Option<String>,
+    /// The type of component, for example "helm", "manifest", "test" or "tool".
+    pub component_type: Option<String>,
+    /// The name of the component.
+    pub component: Option<String>,
+    /// The action being performed on the component.
+    pub action: Option<String>,
+    /// When the component started processing.
+    pub started: Option<Instant>,
+}
+
+/// Run a future within a phase.
+///
+/// Log records emitted while the future runs carry the phase field.
+///
+/// # Arguments
+///
+/// * `phase` - The name of the phase
+/// * `future` - The future to run
+///
+pub async fn phase<F: Future>(phase: &str, future: F) -> F::Output {
+    let context = LogContext {
+        phase: Some(phase.to_string()),
+        ..LogContext::default()
+    };
+    LOG_CONTEXT.scope(context, future).await
+}
+
+/// The log context of the current task.
+///
+/// Used to carry the context into a future that runs on another thread.
+///
+pub fn current() -> LogContext {
+    LOG_CONTEXT
+        .try_with(|context| context.clone())
+        .unwrap_or_default()
+}
+
+/// Run a future within a log context.
+///
+/// # Arguments
+///
+/// * `context` - The log context, for example from [`current`]
+/// * `future` - The future to run
+///
+pub async fn scope<F: Future>(context: LogContext, future: F) -> F::Output {
+    LOG_CONTEXT.scope(context, future).await
+}
+
+/// Run a future for a component.
+///
+/// Log records emitted while the future runs carry the component fields
+/// and the duration since the component started. The phase of the
+/// enclosing context is kept. A final record is logged with the total duration.
+///
+/// # Arguments
+///
+/// * `component_type` - The type of component, for example "helm"
+/// * `component` - The name of the component
+/// * `action` - The action being performed
+/// * `future` - The future to run
+///
+pub async fn component<F: Future>(
+    component_type: &str,
+    component: &str,
+    action: &str,
+    future: F,
+) -> F::Output {
+    let phase = LOG_CONTEXT
+        .try_with(|context| context.phase.clone())
+        .ok()
+        .flatten();
+    let started = Instant::now();
+    let context = LogContext {
+        phase,
+        component_type: Some(component_type.to_string()),
+        component: Some(component.to_string()),
+        action: Some(action.to_string()),
+        started: Some(started),
+    };
+
+    LOG_CONTEXT
+        .scope(context, async move {
+            let output = future.await;
+            log::info!(
+                "Finished {} {} {} in {:.1}s",
+                action,
+                component_type,
+                component,
+                started.elapsed().as_secs_f64()
+            );
+            output
+        })
+        .await
+}
+
+/// Format a log record as a JSON object.
+fn format_json(timestamp: &str, record: &log::Record, message: &std::fmt::Arguments) -> String {
+    let mut fields = Map::new();
+    fields.insert("timestamp".to_string(), json!(timestamp));
+    fields.insert("level".to_string(), json!(record.level().to_string()));
+    fields.insert("target".to_string(), json!(record.target()));
+    fields.insert("message".to_string(), json!(message.to_string()));
+
+    let _ = LOG_CONTEXT.try_with(|context| {
+        let optional_fields = [
+            ("phase", &context.phase),
+            ("component_type", &context.component_type),
+            ("component", &context.component),
+            ("action", &context.action),
+        ];
+        for (key, value) in optional_fields {
+            if let Some(value) = value {
+                fields.insert(key.to_string(), json!(value));
+            }
+        }
+        if let Some(started) = context.started {
+            fields.insert(
+                "duration_ms".to_string(),
+                json!(started.elapsed().as_millis() as u64),
+            );
+        }
+    });
+
+    Value::Object(fields).to_string()
+}
+
+/// Format a log record as plain text.
+fn format_text(timestamp: &str, record: &log::Record, message: &std::fmt::Arguments) -> String {
+    let prefix = LOG_CONTEXT
+        .try_with(
+            |context| match (&context.component_type, &context.component) {
+                (Some(component_type), Some(component)) => {
+                    format!("[{}/{}] ", component_type, component)
+                }
+                _ => String::new(),
+            },
+        )
+        .unwrap_or_default();
+
+    format!("{} [{}] {}{}", timestamp, record.level(), prefix, message)
+}
 
 /// Sets up logging.
 ///
+/// Log records are written without color codes so the log file stays plain.
+///
 /// # Arguments
 ///
 /// `log_level` - The log level to use
 /// `log_file` - The path to the log file
+/// `log_format` - The format of each log record
+/// `module_levels` - Log level overrides per module, for example "loopy::helm"
 ///
 pub fn setup_logging(
     log_level: LevelFilter,
     log_file: Option<String>,
+    log_format: LogFormat,
+    module_levels: Vec<(String, LevelFilter)>,
 ) -> Result<(), fern::InitError> {
     let mut base_config = fern::Dispatch::new()
         // Default format for log messages.
         .format(move |out, message, record| {
-            let color = match record.level() {
-                Level::Error => Color::Red,
-                Level::Warn => Color::Yellow,
-                Level::Info => Color::Green,
-                Level::Debug => Color::Cyan,
-                Level::Trace => Color::Blue,
-            };
-            out.finish(format_args!(
-                "{} [{}] {}",
-                Local::now().format("%Y-%m-%dT%H:%M:%S"),
-                format!("{}", record.level()).color(color),
-                message
-            ))
+            let timestamp = Local::now().format("%Y-%m-%dT%H:%M:%S").to_string();
+            match log_format {
+                LogFormat::Json => {
+                    out.finish(format_args!("{}", format_json(&timestamp, record, message)))
+                }
+                LogFormat::Text => {
+                    out.finish(format_args!("{}", format_text(&timestamp, record, message)))
+                }
+            }
         })
         .level(log_level);
 
+    // Apply any log level overrides for specific modules.
+    for (module, level) in module_levels {
+        base_config = base_config.level_for(module, level);
+    }
+
     // If a log file is provided, log to that file.
     if let Some(log_file) = log_file {
         base_config = base_config.chain(fern::log_file(log_file)?);
@@ -33,13 +33,30 @@ use std::path::PathBuf;
 use std::str::FromStr;
 
 mod args;
+mod cache;
+mod changes;
 mod config;
 mod fortune;
+mod forward;
+mod git;
+mod graph;
 mod helm;
+mod http;
+mod init;
 mod kubectl;
 mod logger;
 mod msvc;
+mod runs;
+mod secrets;
+mod signal;
+mod snapshot;
+mod state;
+mod status;
+mod store;
+mod template;
 mod utils;
+mod validate;
+mod values;
 
 // Constants.
 const PACKAGE_NAME: &str = env!("CARGO_PKG_NAME");
@@ -51,19 +68,98 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     #[cfg(target_env = "msvc")]
     msvc::link_libraries();
 
-    // Let's start the loop.
-    let figlet_msg: String = "start".to_string();
-    figlet(figlet_msg.as_str(), None, None, None);
-    println!("{} has started.", PACKAGE_NAME);
-
     // Parse the command line arguments
     let args = args::Args::parse();
 
+    // Let's start the loop, keeping the output of config commands and JSON clean for piping.
+    let banner = !matches!(
+        args.command,
+        Some(args::Command::Config { .. }) | Some(args::Command::Status { json: true })
+    );
+    if banner {
+        let figlet_msg: String = "start".to_string();
+        figlet(figlet_msg.as_str(), None, None, None);
+        println!("{} has started.", PACKAGE_NAME);
+    }
+
     // Destructure Args back into individual vars
-    let args::Args { config, action, .. } = args;
+    let args::Args {
+        config,
+        profile,
+        action,
+        resume,
+        dry_run,
+        force,
+        offline,
+        command,
+    } = args;
+
+    // A new project doesn't have a configuration to load yet.
+    if let Some(args::Command::Init {
+        repos,
+        charts,
+        kind,
+        force,
+    }) = &command
+    {
+        let options = init::InitOptions {
+            repos: repos.clone(),
+            charts: charts.clone(),
+            kind: *kind,
+            force: *force,
+        };
+        if let Err(e) = init::init(config.as_deref().unwrap_or_default(), options).await {
+            eprintln!("Failed to initialise the project: {:#}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // Every command reads what loopy installed in the cluster, so it only touches what
+    // it owns and can tell when the configuration changed. Without a cluster, commands
+    // continue without the store.
+    let config_file = config.as_deref().unwrap_or_default();
+    if store::load()
+        .await
+        .is_ok_and(|installed| installed.config_hash.is_some())
+    {
+        if let Ok(hash) = config::config_hash(config_file, profile.as_deref()) {
+            store::set_config_hash(hash);
+        }
+    }
+    let reports_store = matches!(command, Some(args::Command::Status { .. })) || action.is_some();
+    if store::config_changed() == Some(true) && !reports_store {
+        eprintln!(
+            "The configuration has changed since the last install by {}.",
+            PACKAGE_NAME
+        );
+    }
+
+    // Inspecting the configuration doesn't need any tools or a cluster.
+    if let Some(args::Command::Config { command }) = &command {
+        let file = config.as_deref().unwrap_or_default();
+        match command {
+            args::ConfigCommand::Show => {
+                let resolved = config::resolve_config(file, profile.as_deref())?;
+                print!("{}", serde_yaml::to_string(&resolved.value)?);
+            }
+            args::ConfigCommand::Validate => match config::load_config(file, profile.as_deref()) {
+                Ok(_) => println!("The configuration {} is valid.", file),
+                Err(e) => {
+                    eprintln!("{:#}", e);
+                    std::process::exit(1);
+                }
+            },
+            args::ConfigCommand::Migrate => config::migrate_config(file)?,
+            args::ConfigCommand::Schema => {
+                println!("{}", serde_json::to_string_pretty(&config::schema())?);
+            }
+        }
+        return Ok(());
+    }
 
     // Load the configuration from the file.
-    let config_loaded = match config {
+    let config_loaded = match &config {
         Some(file) => {
             // Ensure the config file isn't an empty string.
             if file.is_empty() {
@@ -71,7 +167,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 std::process::exit(1);
             }
             // Return the loaded config file
-            config::load_config(&file)?
+            config::load_config(file, profile.as_deref())?
         }
 
         None => {
@@ -97,10 +193,79 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .as_ref()
         .and_then(|log| log.fortune.as_ref())
         .unwrap_or(&false);
-    logger::setup_logging(log_level, log_file)?;
+    let log_format = config_loaded
+        .log
+        .as_ref()
+        .and_then(|log| log.format.as_ref())
+        .and_then(|format| logger::LogFormat::from_str(format).ok())
+        .unwrap_or(logger::LogFormat::Text);
+    let module_levels = config_loaded
+        .log
+        .as_ref()
+        .and_then(|log| log.modules.as_ref())
+        .map(|modules| {
+            modules
+                .iter()
+                .filter_map(|(module, level)| {
+                    LevelFilter::from_str(level)
+                        .ok()
+                        .map(|level| (module.to_owned(), level))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    logger::setup_logging(log_level, log_file, log_format, module_levels)?;
 
     info!("Logging initialized with level: {:?}", log_level);
 
+    // Browsing past runs doesn't need any tools or a cluster.
+    if let Some(args::Command::Runs { command }) = &command {
+        let result = match command {
+            args::RunsCommand::List => runs::runs_list(),
+            args::RunsCommand::Show { id } => runs::runs_show(id),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to read runs: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // A dry run only shows what the action would do.
+    if let (true, None, Some(action)) = (dry_run, &command, &action) {
+        graph::print_plan(action, &config_loaded)?;
+        return Ok(());
+    }
+
+    // Only use cached content when working offline.
+    cache::set_offline(offline);
+
+    // Install unchanged components again when forced.
+    changes::set_force(force);
+
+    // Identify the configuration, so the state store can tell when it changed.
+    store::set_config_hash(config::config_hash(
+        config.as_deref().unwrap_or_default(),
+        profile.as_deref(),
+    )?);
+
+    // Make the configured variables available to values files and manifests.
+    template::set_vars(config_loaded.template_vars());
+
+    // Record a transcript of every command executed during an action. The run
+    // is marked as failed if main returns early with an error.
+    let _run = match (&command, &action) {
+        (None, Some(action)) => {
+            let run = runs::start_run(action)?;
+            println!("Recording this run to {}", run.dir().display());
+
+            // Stop cleanly between steps when the user presses Ctrl-C.
+            signal::install_handler();
+            Some(run)
+        }
+        _ => None,
+    };
+
     // Define where any downloaded tools will be stored.
     let vendor_dir = PathBuf::from(VENDOR_PATH);
 
@@ -121,7 +286,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             warn!("{} is not found in PATH", tool.name);
 
             // If a URL was provided, prompt the user to download the tool.
-            if tool.url.as_ref().map_or(true, |url| url.is_empty()) {
+            if tool.url.as_deref().unwrap_or_default().is_empty() {
                 println!(
                     "Please download the required tool {} and add it to your PATH",
                     tool.name
@@ -144,16 +309,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     create_dir(&vendor_dir)?;
 
                     // Download the tool and place in the vendor directory.
-                    let binary_path =
-                        download_tool(&client, tool.url.as_ref().unwrap(), &tool.name, &vendor_dir)
-                            .await?;
+                    let binary_path = logger::component(
+                        "tool",
+                        &tool.name,
+                        "download",
+                        download_tool(&client, tool.url.as_ref().unwrap(), &tool.name, &vendor_dir),
+                    )
+                    .await?;
                     println!("Successfully downloaded {}", tool.name);
 
                     // Update the PATH environment variable to include the vendor directory.
                     update_path(&vendor_dir);
 
                     // Make the downloaded tool executable.
-                    run_command("chmod", &["+x", (binary_path.to_str().unwrap())])?;
+                    run_command("chmod", &["+x", (binary_path.to_str().unwrap())]).await?;
 
                     println!("{} is now available in PATH", tool.name);
                 } else {
@@ -167,45 +336,170 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
     info!("All required tools are now present in PATH");
 
-    // Perform a match based on the provided action to perform
-    // or exit if no action was provided.
-    match action.as_deref() {
-        Some("install") => {
-            println!("Install mode activated...");
-            // Install all required components
-            if let Err(e) = process_install_uninstall("install", &config_loaded).await {
-                eprintln!("Installation failed: {}", e);
+    if let Some(e) = store::load_error() {
+        warn!("Failed to read the loopy state store: {}", e);
+    }
+
+    // Run the provided command, otherwise perform a match based on the
+    // provided action to perform or exit if no action was provided.
+    match command {
+        Some(args::Command::Runs { .. }) => unreachable!("runs are handled before this point"),
+        Some(args::Command::Config { .. }) => {
+            unreachable!("config commands are handled before this point")
+        }
+        Some(args::Command::Init { .. }) => unreachable!("init is handled before this point"),
+
+        Some(args::Command::Rollback { chart, revision }) => {
+            let found = config_loaded
+                .dependencies
+                .helm
+                .charts
+                .iter()
+                .chain(config_loaded.application.helm.charts.iter())
+                .find(|entry| entry.name == chart);
+            let Some(found) = found else {
+                eprintln!("Chart {} is not defined in the configuration.", chart);
                 std::process::exit(1);
+            };
+            if !store::is_owned("chart", &chart) {
+                println!(
+                    "Helm chart {} was not installed by {}, rolling it back anyway",
+                    chart, PACKAGE_NAME
+                );
+            }
+            let result = match helm::helm_rollback_chart(found, revision).await {
+                Ok(revision) => store::save().await.map(|_| revision),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(revision) => println!(
+                    "Successfully rolled back Helm chart {} to revision {}",
+                    chart, revision
+                ),
+                Err(e) => {
+                    eprintln!("Rollback failed: {:#}", e);
+                    std::process::exit(1);
+                }
             }
         }
 
-        Some("uninstall") => {
-            println!("Un-install mode activated...");
-            // Uninstall all required components
-            if let Err(e) = process_install_uninstall("uninstall", &config_loaded).await {
-                eprintln!("Uninstallation failed: {}", e);
+        Some(args::Command::Snapshot { command }) => {
+            let result = match &command {
+                args::SnapshotCommand::Save { name } => {
+                    snapshot::snapshot_save(&config_loaded, name).await
+                }
+                args::SnapshotCommand::Restore { name } => {
+                    snapshot::snapshot_restore(&config_loaded, name).await
+                }
+                args::SnapshotCommand::List => snapshot::snapshot_list(),
+            };
+            if let Err(e) = result {
+                eprintln!("Snapshot failed: {:#}", e);
                 std::process::exit(1);
             }
         }
 
-        None => {
-            println!("No action was specified, nothing to do. See '--help' for usage information.");
-            std::process::exit(0);
+        Some(args::Command::Status { json }) => {
+            match status::status_show(&config_loaded, json).await {
+                Ok(true) => {}
+                Ok(false) => std::process::exit(1),
+                Err(e) => {
+                    eprintln!("Failed to get the status: {:#}", e);
+                    std::process::exit(1);
+                }
+            }
         }
 
-        _ => {
-            println!("Invalid action. Please provide a valid action (install or uninstall).");
-            std::process::exit(1);
+        Some(args::Command::Values { command }) => match command {
+            args::ValuesCommand::Diff { charts } => {
+                match values::values_diff(&config_loaded, &charts).await {
+                    Ok(true) => {}
+                    Ok(false) => std::process::exit(1),
+                    Err(e) => {
+                        eprintln!("Failed to compare the chart values: {:#}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            args::ValuesCommand::Refresh { charts } => {
+                if let Err(e) = values::values_refresh(&config_loaded, &charts).await {
+                    eprintln!("Failed to refresh the chart values: {:#}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+
+        Some(args::Command::Forward { names }) => {
+            println!("Port-forward mode activated...");
+            if store::current().is_some_and(|store| !store.is_recorded()) {
+                println!(
+                    "Nothing was installed by {} in this cluster yet, the port-forwards may not find their targets.",
+                    PACKAGE_NAME
+                );
+            }
+            if let Err(e) = forward::forward_configured(&config_loaded, &names).await {
+                eprintln!("Port-forwarding failed: {}", e);
+                std::process::exit(1);
+            }
         }
+
+        None => match action.as_deref() {
+            Some("install") => {
+                println!("Install mode activated...");
+                // Install all required components
+                if let Err(e) = process_install_uninstall("install", &config_loaded, resume).await {
+                    runs::finish_run(false);
+                    if signal::interrupted() {
+                        eprintln!("Installation was interrupted: {}", e);
+                        eprintln!("Run again with --resume to continue where it stopped.");
+                        std::process::exit(signal::INTERRUPTED_EXIT_CODE);
+                    }
+                    eprintln!("Installation failed: {:#}", e);
+                    std::process::exit(1);
+                }
+                runs::finish_run(true);
+            }
+
+            Some("uninstall") => {
+                println!("Un-install mode activated...");
+                // Uninstall all required components
+                if let Err(e) = process_install_uninstall("uninstall", &config_loaded, resume).await
+                {
+                    runs::finish_run(false);
+                    if signal::interrupted() {
+                        eprintln!("Uninstallation was interrupted: {}", e);
+                        eprintln!("Run again with --resume to continue where it stopped.");
+                        std::process::exit(signal::INTERRUPTED_EXIT_CODE);
+                    }
+                    eprintln!("Uninstallation failed: {:#}", e);
+                    std::process::exit(1);
+                }
+                runs::finish_run(true);
+            }
+
+            None => {
+                println!(
+                    "No action was specified, nothing to do. See '--help' for usage information."
+                );
+                std::process::exit(0);
+            }
+
+            _ => {
+                println!("Invalid action. Please provide a valid action (install or uninstall).");
+                std::process::exit(1);
+            }
+        },
     }
 
     // Let's end the loop.
-    let figlet_msg: String = "end".to_string();
-    figlet(figlet_msg.as_str(), None, None, None);
-    println!("{} has finished.", PACKAGE_NAME);
+    if banner {
+        let figlet_msg: String = "end".to_string();
+        figlet(figlet_msg.as_str(), None, None, None);
+        println!("{} has finished.", PACKAGE_NAME);
+    }
 
-    // If fortune is enabled, tell the user their fortune.
-    if &fortune_enabled.to_string() == "true" {
+    // If fortune is enabled, tell the user their fortune, unless the output is piped.
+    if banner && &fortune_enabled.to_string() == "true" {
         println!(" ");
         if let Some(fortune_message) = show_fortune() {
             println!("Here is your fortune for today: {}", fortune_message);
@@ -0,0 +1,398 @@
+//! Runs
+//!
+//! This module records a transcript of every command executed during a run
+//! and provides functions for browsing the transcripts of past runs.
+//!
+//! Each run is stored in its own directory under `.loopy/runs/<id>/`, with a
+//! `run.json` summary and one JSON file per executed command. Only the most
+//! recent runs are kept.
+//!
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+/// The directory where run transcripts are stored.
+const RUNS_DIR: &str = ".loopy/runs";
+
+/// The number of runs that are kept, including the active run.
+const MAX_RUNS: usize = 50;
+
+/// The currently active run, if any.
+static RUN: OnceLock<Mutex<ActiveRun>> = OnceLock::new();
+
+/// State of the currently active run.
+struct ActiveRun {
+    /// The run directory.
+    dir: PathBuf,
+    /// The environment when the run started, used to compute the env diff of each command.
+    env: BTreeMap<String, String>,
+    /// The number of commands recorded so far.
+    commands: usize,
+    /// The run summary.
+    summary: RunSummary,
+}
+
+/// Summary of a single run, stored as `run.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunSummary {
+    /// The run identifier, which is also the directory name.
+    pub id: String,
+    /// The action that was performed.
+    pub action: String,
+    /// The loopy version that performed the run.
+    pub version: String,
+    /// When the run started.
+    pub started: DateTime<Local>,
+    /// When the run finished, if it did.
+    pub finished: Option<DateTime<Local>>,
+    /// The outcome of the run, "running", "succeeded" or "failed".
+    pub status: String,
+    /// The number of commands executed.
+    pub commands: usize,
+}
+
+/// Transcript of a single command execution.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transcript {
+    /// The command and its arguments.
+    pub argv: Vec<String>,
+    /// Environment variables that differ from the start of the run.
+    /// A value of `None` means the variable was removed.
+    pub env: BTreeMap<String, Option<String>>,
+    /// The working directory.
+    pub cwd: String,
+    /// When the command started.
+    pub started: DateTime<Local>,
+    /// When the command finished.
+    pub finished: DateTime<Local>,
+    /// The exit code, if the command exited normally.
+    pub exit_code: Option<i32>,
+    /// The standard output of the command.
+    pub stdout: String,
+    /// The standard error of the command.
+    pub stderr: String,
+}
+
+/// Marks the active run as failed when it is dropped before it was finished,
+/// for example when an error is returned early.
+pub struct RunGuard {
+    /// The run directory.
+    dir: PathBuf,
+}
+
+impl RunGuard {
+    /// The path to the run directory.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        finish_run(false);
+    }
+}
+
+/// Start a run.
+///
+/// Creates the run directory and starts recording every executed command.
+/// The oldest runs are removed, so only the most recent ones are kept.
+///
+/// # Arguments
+///
+/// * `action` - The action being performed
+///
+/// # Returns
+///
+/// A guard that finishes the run as failed if it wasn't finished before it is dropped.
+///
+pub fn start_run(action: &str) -> Result<RunGuard> {
+    let started = Local::now();
+    // The process ID keeps runs started within the same second apart.
+    let id = format!("{}-{}", started.format("%Y%m%d-%H%M%S"), std::process::id());
+    let dir = Path::new(RUNS_DIR).join(&id);
+
+    prune_runs();
+
+    let err_msg = format!("Failed to create run directory {}", dir.display());
+    fs::create_dir_all(&dir).context(err_msg)?;
+
+    let summary = RunSummary {
+        id,
+        action: action.to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        started,
+        finished: None,
+        status: "running".to_string(),
+        commands: 0,
+    };
+    write_json(&dir.join("run.json"), &summary)?;
+
+    let run = ActiveRun {
+        dir: dir.clone(),
+        env: env::vars().collect(),
+        commands: 0,
+        summary,
+    };
+    if RUN.set(Mutex::new(run)).is_err() {
+        return Err(anyhow!("A run has already been started"));
+    }
+
+    debug!("Recording command transcripts to {}", dir.display());
+
+    Ok(RunGuard { dir })
+}
+
+/// Finish the active run.
+///
+/// Records the outcome of the run in its summary. Only the first call has an effect.
+///
+/// # Arguments
+///
+/// * `succeeded` - Whether the run succeeded
+///
+pub fn finish_run(succeeded: bool) {
+    let Some(run) = RUN.get() else {
+        return;
+    };
+    let mut run = run.lock().unwrap_or_else(|e| e.into_inner());
+    if run.summary.finished.is_some() {
+        return;
+    }
+
+    run.summary.finished = Some(Local::now());
+    run.summary.status = if succeeded { "succeeded" } else { "failed" }.to_string();
+    run.summary.commands = run.commands;
+
+    if let Err(e) = write_json(&run.dir.join("run.json"), &run.summary) {
+        warn!("Failed to write the run summary: {}", e);
+    }
+}
+
+/// Record a command.
+///
+/// Writes the transcript of a command to the active run directory.
+/// Does nothing when no run is active.
+///
+/// # Arguments
+///
+/// * `argv` - The command and its arguments
+/// * `envs` - Environment variables set for this command only
+/// * `started` - When the command started
+/// * `exit_code` - The exit code of the command
+/// * `stdout` - The standard output of the command
+/// * `stderr` - The standard error of the command
+///
+pub fn record(
+    argv: &[&str],
+    envs: &[(&str, &str)],
+    started: DateTime<Local>,
+    exit_code: Option<i32>,
+    stdout: &str,
+    stderr: &str,
+) {
+    let Some(run) = RUN.get() else {
+        return;
+    };
+    let mut run = run.lock().unwrap_or_else(|e| e.into_inner());
+
+    // Compare the current environment to the environment at the start of the run.
+    let mut env_diff: BTreeMap<String, Option<String>> = BTreeMap::new();
+    let current: BTreeMap<String, String> = env::vars().collect();
+    for (key, value) in &current {
+        if run.env.get(key) != Some(value) {
+            env_diff.insert(key.clone(), Some(value.clone()));
+        }
+    }
+    for key in run.env.keys() {
+        if !current.contains_key(key) {
+            env_diff.insert(key.clone(), None);
+        }
+    }
+    for (key, value) in envs {
+        env_diff.insert(key.to_string(), Some(value.to_string()));
+    }
+
+    let transcript = Transcript {
+        argv: argv.iter().map(|arg| arg.to_string()).collect(),
+        env: env_diff,
+        cwd: env::current_dir()
+            .map(|dir| dir.display().to_string())
+            .unwrap_or_default(),
+        started,
+        finished: Local::now(),
+        exit_code,
+        stdout: stdout.to_string(),
+        stderr: stderr.to_string(),
+    };
+
+    run.commands += 1;
+    let file_name = format!(
+        "{:04}-{}.json",
+        run.commands,
+        argv.first().copied().unwrap_or("command")
+    );
+    if let Err(e) = write_json(&run.dir.join(file_name), &transcript) {
+        warn!("Failed to record the command transcript: {}", e);
+    }
+}
+
+/// List runs.
+///
+/// Prints a summary of every recorded run, oldest first.
+///
+pub fn runs_list() -> Result<()> {
+    let summaries = read_summaries()?;
+
+    if summaries.is_empty() {
+        println!("No runs have been recorded in {}", RUNS_DIR);
+        return Ok(());
+    }
+
+    println!(
+        "{:<24} {:<10} {:<10} {:>8}  STARTED",
+        "ID", "ACTION", "STATUS", "COMMANDS"
+    );
+    for summary in summaries {
+        println!(
+            "{:<24} {:<10} {:<10} {:>8}  {}",
+            summary.id,
+            summary.action,
+            summary.status,
+            summary.commands,
+            summary.started.format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+
+    Ok(())
+}
+
+/// Show a run.
+///
+/// Prints the summary of a run and the transcript of every command it executed.
+///
+/// # Arguments
+///
+/// * `id` - The run identifier, or "last" for the most recent run
+///
+pub fn runs_show(id: &str) -> Result<()> {
+    let id = if id == "last" {
+        read_summaries()?
+            .pop()
+            .map(|summary| summary.id)
+            .ok_or_else(|| anyhow!("No runs have been recorded in {}", RUNS_DIR))?
+    } else {
+        id.to_string()
+    };
+
+    let dir = Path::new(RUNS_DIR).join(&id);
+    let summary: RunSummary = read_json(&dir.join("run.json"))
+        .with_context(|| format!("Run {} was not found in {}", id, RUNS_DIR))?;
+
+    println!("Run:      {}", summary.id);
+    println!("Action:   {}", summary.action);
+    println!("Status:   {}", summary.status);
+    println!("Version:  {}", summary.version);
+    println!("Started:  {}", summary.started.format("%Y-%m-%d %H:%M:%S"));
+    if let Some(finished) = summary.finished {
+        println!("Finished: {}", finished.format("%Y-%m-%d %H:%M:%S"));
+    }
+
+    let mut files: Vec<PathBuf> = fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.file_name().is_some_and(|name| name != "run.json"))
+        .collect();
+    files.sort();
+
+    for file in files {
+        let transcript: Transcript = read_json(&file)?;
+        let duration = transcript.finished - transcript.started;
+
+        println!();
+        println!("$ {}", transcript.argv.join(" "));
+        println!(
+            "  exit code: {}, duration: {:.1}s, cwd: {}",
+            transcript
+                .exit_code
+                .map_or("none".to_string(), |code| code.to_string()),
+            duration.num_milliseconds() as f64 / 1000.0,
+            transcript.cwd
+        );
+        for (key, value) in &transcript.env {
+            match value {
+                Some(value) => println!("  env: {}={}", key, value),
+                None => println!("  env: unset {}", key),
+            }
+        }
+        for line in transcript.stdout.lines() {
+            println!("  stdout: {}", line);
+        }
+        for line in transcript.stderr.lines() {
+            println!("  stderr: {}", line);
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove the oldest runs, leaving room for a new one.
+fn prune_runs() {
+    let summaries = match read_summaries() {
+        Ok(summaries) => summaries,
+        Err(e) => {
+            warn!("Failed to read the recorded runs: {}", e);
+            return;
+        }
+    };
+
+    let excess = (summaries.len() + 1).saturating_sub(MAX_RUNS);
+    for summary in summaries.iter().take(excess) {
+        let dir = Path::new(RUNS_DIR).join(&summary.id);
+        match fs::remove_dir_all(&dir) {
+            Ok(()) => debug!("Removed old run {}", dir.display()),
+            Err(e) => warn!("Failed to remove old run {}: {}", dir.display(), e),
+        }
+    }
+}
+
+/// Read the summaries of all recorded runs, oldest first.
+fn read_summaries() -> Result<Vec<RunSummary>> {
+    let runs_dir = Path::new(RUNS_DIR);
+    if !runs_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut summaries = Vec::new();
+    for entry in fs::read_dir(runs_dir)? {
+        let path = entry?.path().join("run.json");
+        match read_json::<RunSummary>(&path) {
+            Ok(summary) => summaries.push(summary),
+            Err(e) => debug!("Skipping unreadable run {}: {}", path.display(), e),
+        }
+    }
+    summaries.sort_by_key(|summary| summary.started);
+
+    Ok(summaries)
+}
+
+/// Write a value to a file as pretty printed JSON.
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let content = serde_json::to_string_pretty(value)?;
+    let err_msg = format!("Failed to write {}", path.display());
+    fs::write(path, content).context(err_msg)
+}
+
+/// Read a value from a JSON file.
+fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<T> {
+    let err_msg = format!("Failed to read {}", path.display());
+    let content = fs::read_to_string(path).context(err_msg)?;
+    let err_msg = format!("Failed to parse {}", path.display());
+    serde_json::from_str(&content).context(err_msg)
+}
@@ -0,0 +1,220 @@
+//! Secrets
+//!
+//! This module decrypts SOPS encrypted values files and manifests, and creates
+//! the Kubernetes Secrets defined in the `secrets` section of a phase.
+//!
+//! Decrypted content is only held in memory and passed to helm and kubectl on
+//! stdin. It is never written to disk, logged or recorded in a run transcript.
+//! SOPS finds the age key through `SOPS_AGE_KEY_FILE` or `SOPS_AGE_KEY`.
+//!
+
+use crate::config::Secret;
+use crate::template;
+use crate::utils::run_command_redacted;
+
+use anyhow::{anyhow, Context, Result};
+use k8s_openapi::api::core::v1::{Namespace, Secret as KubeSecret};
+use k8s_openapi::ByteString;
+use kube::api::{Api, DeleteParams, ObjectMeta, Patch, PatchParams, PostParams};
+use kube::Client;
+use log::{debug, info};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Check if a file is encrypted with SOPS.
+///
+/// # Arguments
+///
+/// * `path` - The path of the file
+///
+pub fn is_encrypted(path: &Path) -> bool {
+    if !path.is_file() {
+        return false;
+    }
+
+    fs::read_to_string(path)
+        .map(|content| content.contains("\nsops:") && content.contains("ENC["))
+        .unwrap_or(false)
+}
+
+/// Decrypt a SOPS encrypted file in memory.
+///
+/// Template references in the decrypted content are rendered as well.
+///
+/// # Arguments
+///
+/// * `path` - The path of the encrypted file
+///
+/// # Returns
+///
+/// The decrypted content.
+///
+pub async fn decrypt_file(path: &Path) -> Result<String> {
+    info!("Decrypting {}", path.display());
+
+    let path_str = path.to_string_lossy();
+    let (stdout, stderr, status) =
+        run_command_redacted("sops", &["--decrypt", &path_str], None).await?;
+
+    if status.code() != Some(0) {
+        return Err(anyhow!(
+            "Failed to decrypt {}: {}",
+            path.display(),
+            stderr.trim()
+        ));
+    }
+
+    let err_msg = format!("Failed to render {}", path.display());
+    template::render(&stdout).await.context(err_msg)
+}
+
+/// Process secret.
+///
+/// Creates or updates the Secret on install, and deletes it on uninstall.
+///
+/// # Arguments
+///
+/// * `secret` - The secret to process
+/// * `action` - The action to perform (install or uninstall)
+///
+pub async fn process_secret(secret: &Secret, action: &str) -> Result<()> {
+    let client = Client::try_default().await?;
+    let namespace = secret.namespace.as_deref().unwrap_or("default");
+    let secrets_api: Api<KubeSecret> = Api::namespaced(client.clone(), namespace);
+
+    match action {
+        "install" => {
+            println!("Creating Secret: {} in {}", secret.name, namespace);
+            secret_namespace(&client, namespace).await?;
+
+            let kube_secret = KubeSecret {
+                metadata: ObjectMeta {
+                    name: Some(secret.name.clone()),
+                    namespace: Some(namespace.to_string()),
+                    ..ObjectMeta::default()
+                },
+                type_: secret.type_.clone(),
+                data: Some(secret_data(secret)?),
+                ..KubeSecret::default()
+            };
+
+            let params = PatchParams::apply(crate::PACKAGE_NAME).force();
+            let err_msg = format!("Failed to create Secret {}", secret.name);
+            secrets_api
+                .patch(&secret.name, &params, &Patch::Apply(&kube_secret))
+                .await
+                .context(err_msg)?;
+            println!("Successfully created Secret: {}", secret.name);
+        }
+
+        "uninstall" => {
+            println!("Deleting Secret: {} in {}", secret.name, namespace);
+            match secrets_api
+                .delete(&secret.name, &DeleteParams::default())
+                .await
+            {
+                Ok(_) => println!("Successfully deleted Secret: {}", secret.name),
+                Err(kube::Error::Api(e)) if e.code == 404 => {
+                    info!("Secret {} is not present, skipping.", secret.name)
+                }
+                Err(e) => return Err(anyhow!("Failed to delete Secret {}: {}", secret.name, e)),
+            }
+        }
+
+        _ => {
+            return Err(anyhow!(
+                "Invalid action, only 'install' or 'uninstall' are allowed"
+            ))
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the data of a secret from its environment variables and files.
+fn secret_data(secret: &Secret) -> Result<BTreeMap<String, ByteString>> {
+    let mut data = BTreeMap::new();
+
+    for (key, source) in &secret.data {
+        let value = match (&source.env, &source.file) {
+            (Some(env), None) => std::env::var(env)
+                .with_context(|| {
+                    format!(
+                        "The environment variable {} for key {} of Secret {} is not set",
+                        env, key, secret.name
+                    )
+                })?
+                .into_bytes(),
+            (None, Some(file)) => fs::read(file).with_context(|| {
+                format!(
+                    "Failed to read {} for key {} of Secret {}",
+                    file, key, secret.name
+                )
+            })?,
+            _ => {
+                return Err(anyhow!(
+                    "Key {} of Secret {} must define exactly one of 'env' or 'file'",
+                    key,
+                    secret.name
+                ))
+            }
+        };
+        data.insert(key.clone(), ByteString(value));
+    }
+
+    Ok(data)
+}
+
+/// Create the namespace of a secret if it doesn't exist.
+async fn secret_namespace(client: &Client, name: &str) -> Result<()> {
+    let namespaces: Api<Namespace> = Api::all(client.clone());
+    if namespaces.get_opt(name).await?.is_some() {
+        return Ok(());
+    }
+
+    debug!("Creating namespace {} for secrets", name);
+    let namespace = Namespace {
+        metadata: ObjectMeta {
+            name: Some(name.to_string()),
+            ..ObjectMeta::default()
+        },
+        ..Namespace::default()
+    };
+    namespaces
+        .create(&PostParams::default(), &namespace)
+        .await
+        .with_context(|| format!("Failed to create namespace {}", name))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn is_encrypted_content(content: &str) -> bool {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        is_encrypted(file.path())
+    }
+
+    #[test]
+    fn sops_files_are_encrypted() {
+        let content = "password: ENC[AES256_GCM,data:abc=,type:str]\nsops:\n  age:\n    - recipient: age1\n  version: 3.8.1\n";
+        assert!(is_encrypted_content(content));
+    }
+
+    #[test]
+    fn plain_files_with_a_sops_key_are_not_encrypted() {
+        let content = "image: app\nsops:\n  enabled: true\n";
+        assert!(!is_encrypted_content(content));
+    }
+
+    #[test]
+    fn files_without_sops_metadata_are_not_encrypted() {
+        assert!(!is_encrypted_content("note: ENC[ is just text\n"));
+        assert!(!is_encrypted(Path::new("does/not/exist.yaml")));
+    }
+}
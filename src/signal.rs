@@ -0,0 +1,88 @@
+//! Signal
+//!
+//! This module handles Ctrl-C so that a run can stop cleanly.
+//!
+//! The first Ctrl-C asks loopy to stop once the current step has finished.
+//! The second Ctrl-C aborts the current step, killing any running command.
+//! Every command runs in its own process group, so the terminal doesn't send
+//! Ctrl-C to the commands directly.
+//!
+
+use anyhow::{anyhow, Result};
+use log::{error, warn};
+use std::sync::OnceLock;
+use tokio::sync::watch;
+
+/// The exit code used when loopy exits because of Ctrl-C.
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// The number of times Ctrl-C has been pressed.
+static INTERRUPTS: OnceLock<watch::Sender<usize>> = OnceLock::new();
+
+/// Get the interrupt counter.
+fn interrupts() -> &'static watch::Sender<usize> {
+    INTERRUPTS.get_or_init(|| watch::channel(0).0)
+}
+
+/// Install the Ctrl-C handler.
+///
+/// Must be called from within the tokio runtime.
+///
+pub fn install_handler() {
+    tokio::spawn(async {
+        loop {
+            if tokio::signal::ctrl_c().await.is_err() {
+                error!("Failed to listen for Ctrl-C");
+                return;
+            }
+
+            interrupts().send_modify(|count| *count += 1);
+            match *interrupts().borrow() {
+                1 => {
+                    warn!("Interrupt received, stopping after the current step");
+                    eprintln!(
+                        "\nInterrupt received, stopping after the current step. Press Ctrl-C again to abort it."
+                    );
+                }
+                2 => {
+                    warn!("Second interrupt received, aborting the current step");
+                    eprintln!("\nAborting the current step, stopping its commands.");
+                }
+                // Exiting here would skip the cleanup of the running commands.
+                _ => {
+                    warn!("Interrupt received while aborting the current step");
+                    eprintln!("\nStill aborting the current step, please wait.");
+                }
+            }
+        }
+    });
+}
+
+/// Check if the user has asked to stop.
+pub fn interrupted() -> bool {
+    *interrupts().borrow() >= 1
+}
+
+/// Check for an interrupt between steps.
+///
+/// # Returns
+///
+/// An error if the user has asked to stop, so the run stops before the next step.
+///
+pub fn check() -> Result<()> {
+    if interrupted() {
+        return Err(anyhow!("Interrupted by the user"));
+    }
+    Ok(())
+}
+
+/// Wait until the user asks to abort the current step.
+///
+/// Resolves once Ctrl-C has been pressed twice.
+///
+pub async fn aborted() {
+    let mut receiver = interrupts().subscribe();
+    if receiver.wait_for(|count| *count >= 2).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
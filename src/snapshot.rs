@@ -0,0 +1,458 @@
+//! Snapshot
+//!
+//! This module saves the state of the configured charts and manifest sets, so
+//! the cluster can be brought back to it without a full uninstall and install.
+//!
+//! A snapshot is stored in `.loopy/snapshots/<name>.json`, with the kube context
+//! it was saved from, the revision of every installed release, the resources of
+//! every manifest set that exist and a hash of every values file. It is usually
+//! saved once the dependencies are installed, so the application can be reset
+//! while the dependencies stay. A snapshot is only restored to the kube context
+//! it was saved from.
+//!
+//! Charts and manifest sets that the state store shows loopy doesn't own are
+//! left out of both saving and restoring.
+//!
+
+use crate::config::{Chart, Config, Manifests};
+use crate::helm::{helm_chart, helm_release_status, helm_rollback_chart};
+use crate::kubectl::{
+    kubectl_apply_manifest, kubectl_delete_objects, kubectl_manifest_content,
+    kubectl_resource_status, ObjectRef,
+};
+use crate::store;
+use crate::utils::hash_content;
+use crate::PACKAGE_NAME;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local};
+use kube::config::Kubeconfig;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The directory where snapshots are stored.
+const SNAPSHOTS_DIR: &str = ".loopy/snapshots";
+
+/// The saved state of the cluster.
+#[derive(Debug, Serialize, Deserialize)]
+struct Snapshot {
+    /// The name of the snapshot.
+    name: String,
+    /// When the snapshot was saved.
+    created: DateTime<Local>,
+    /// The kube context of the cluster the snapshot was saved from.
+    #[serde(default)]
+    context: Option<String>,
+    /// The releases that were installed.
+    releases: Vec<ReleaseSnapshot>,
+    /// The manifest sets and their resources that existed.
+    manifests: Vec<ManifestSnapshot>,
+    /// The SHA-256 of every values file, by path.
+    values: BTreeMap<String, String>,
+}
+
+/// The saved state of a release.
+#[derive(Debug, Serialize, Deserialize)]
+struct ReleaseSnapshot {
+    /// The name of the chart in the configuration.
+    chart: String,
+    /// The name of the release.
+    release: String,
+    /// The namespace of the release.
+    namespace: String,
+    /// The revision that was deployed.
+    revision: u64,
+    /// The chart version that was deployed.
+    version: String,
+}
+
+/// The saved state of a manifest set.
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestSnapshot {
+    /// The name of the manifest set in the configuration.
+    name: String,
+    /// The SHA-256 of the manifest content.
+    hash: String,
+    /// The resources that existed.
+    objects: Vec<ObjectRef>,
+}
+
+/// Save a snapshot.
+///
+/// Records the state of every configured chart and manifest set, replacing
+/// any snapshot with the same name.
+///
+/// # Arguments
+///
+/// * `config` - The loaded configuration
+/// * `name` - The name of the snapshot
+///
+pub async fn snapshot_save(config: &Config, name: &str) -> Result<()> {
+    let path = snapshot_path(name)?;
+
+    let mut releases = Vec::new();
+    for chart in snapshot_charts(config) {
+        if !store::is_owned("chart", &chart.name) {
+            println!(
+                "Helm chart {} was not installed by {}, skipping",
+                chart.name, PACKAGE_NAME
+            );
+            continue;
+        }
+        let Some(release) = helm_release_status(chart.release(), chart.namespace()).await? else {
+            continue;
+        };
+        if release.status != "deployed" {
+            return Err(anyhow!(
+                "Helm release {} is {}, only deployed releases can be restored",
+                chart.release(),
+                release.status
+            ));
+        }
+        releases.push(ReleaseSnapshot {
+            chart: chart.name.clone(),
+            release: chart.release().to_string(),
+            namespace: chart.namespace().to_string(),
+            revision: release.revision,
+            version: release.version,
+        });
+    }
+
+    let mut manifests = Vec::new();
+    for manifest in snapshot_manifests(config) {
+        if !store::is_owned("manifest", &manifest.name) {
+            println!(
+                "Kubernetes manifests {} were not installed by {}, skipping",
+                manifest.name, PACKAGE_NAME
+            );
+            continue;
+        }
+        let content = kubectl_manifest_content(manifest).await?;
+        let resources = kubectl_resource_status(&content, None).await?;
+        manifests.push(ManifestSnapshot {
+            name: manifest.name.clone(),
+            hash: hash_content(content.as_bytes()),
+            objects: resources.objects,
+        });
+    }
+
+    let snapshot = Snapshot {
+        name: name.to_string(),
+        created: Local::now(),
+        context: kube_context(),
+        releases,
+        manifests,
+        values: snapshot_values(config),
+    };
+    write_snapshot(&path, &snapshot)?;
+
+    println!(
+        "Saved snapshot {} with {} releases and {} manifest sets",
+        name,
+        snapshot.releases.len(),
+        snapshot.manifests.len()
+    );
+
+    Ok(())
+}
+
+/// Restore a snapshot.
+///
+/// Brings the configured charts and manifest sets back to the snapshot, the
+/// application before the dependencies. Releases installed after the snapshot
+/// are uninstalled and changed releases are rolled back. Resources of manifest
+/// sets that were created after the snapshot are deleted, and manifest sets
+/// with missing resources are applied again.
+///
+/// # Arguments
+///
+/// * `config` - The loaded configuration
+/// * `name` - The name of the snapshot
+///
+pub async fn snapshot_restore(config: &Config, name: &str) -> Result<()> {
+    let path = snapshot_path(name)?;
+    let mut snapshot = read_snapshot(&path)?;
+
+    // A snapshot only describes the cluster it was saved from.
+    let context = kube_context();
+    match &snapshot.context {
+        Some(saved) if Some(saved) != context.as_ref() => {
+            return Err(anyhow!(
+                "Snapshot {} was saved from kube context {}, but the current context is {}",
+                name,
+                saved,
+                context.as_deref().unwrap_or("not set")
+            ));
+        }
+        Some(_) => {}
+        None => warn!(
+            "Snapshot {} doesn't record its kube context, restoring to the current one",
+            name
+        ),
+    }
+    println!(
+        "Restoring snapshot {} from {}",
+        name,
+        snapshot.created.format("%Y-%m-%d %H:%M:%S")
+    );
+
+    let phases = [
+        (
+            &config.application.helm.charts,
+            &config.application.manifests,
+        ),
+        (
+            &config.dependencies.helm.charts,
+            &config.dependencies.manifests,
+        ),
+    ];
+    for (charts, manifests) in phases {
+        // Components that loopy doesn't own were left out of the snapshot.
+        for chart in charts.iter().rev() {
+            if store::is_owned("chart", &chart.name) {
+                restore_chart(chart, &mut snapshot).await?;
+            }
+        }
+        for manifest in manifests.iter().rev() {
+            if store::is_owned("manifest", &manifest.name) {
+                restore_manifest(manifest, &snapshot).await?;
+            }
+        }
+    }
+
+    // The cluster matches the snapshot, but the files may not.
+    let values = snapshot_values(config);
+    for (file, hash) in &snapshot.values {
+        match values.get(file) {
+            Some(current) if current == hash => {}
+            Some(_) => println!(
+                "Values file {} changed since the snapshot, install again to apply it",
+                file
+            ),
+            None => println!("Values file {} was removed since the snapshot", file),
+        }
+    }
+
+    // Rolled back releases have a new revision with the same content.
+    write_snapshot(&path, &snapshot)?;
+    store::save().await?;
+
+    println!("Restored snapshot {}", name);
+
+    Ok(())
+}
+
+/// List the saved snapshots.
+pub fn snapshot_list() -> Result<()> {
+    let mut snapshots = Vec::new();
+    if Path::new(SNAPSHOTS_DIR).is_dir() {
+        let err_msg = format!("Failed to read {}", SNAPSHOTS_DIR);
+        for entry in fs::read_dir(SNAPSHOTS_DIR).context(err_msg)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                snapshots.push(read_snapshot(&path)?);
+            }
+        }
+    }
+    snapshots.sort_by_key(|snapshot| snapshot.created);
+
+    if snapshots.is_empty() {
+        println!("No snapshots have been saved in {}", SNAPSHOTS_DIR);
+        return Ok(());
+    }
+
+    println!(
+        "{:<24} {:>8} {:>9}  CREATED",
+        "NAME", "RELEASES", "MANIFESTS"
+    );
+    for snapshot in snapshots {
+        println!(
+            "{:<24} {:>8} {:>9}  {}",
+            snapshot.name,
+            snapshot.releases.len(),
+            snapshot.manifests.len(),
+            snapshot.created.format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+
+    Ok(())
+}
+
+/// Bring the release of a chart back to the snapshot.
+async fn restore_chart(chart: &Chart, snapshot: &mut Snapshot) -> Result<()> {
+    let current = helm_release_status(chart.release(), chart.namespace()).await?;
+    let saved = snapshot.releases.iter_mut().find(|release| {
+        release.release == chart.release() && release.namespace == chart.namespace()
+    });
+
+    match (saved, current) {
+        (None, None) => {}
+        (None, Some(_)) => {
+            println!(
+                "Uninstalling Helm chart {}, it was installed after the snapshot",
+                chart.name
+            );
+            helm_chart("uninstall", chart).await?;
+        }
+        (Some(saved), None) => {
+            println!(
+                "Installing Helm chart {}, it was uninstalled after the snapshot",
+                chart.name
+            );
+            warn!(
+                "Helm chart {} is installed from the configuration, the snapshot had version {}",
+                chart.name, saved.version
+            );
+            helm_chart("install", chart).await?;
+            if let Some(release) = helm_release_status(chart.release(), chart.namespace()).await? {
+                saved.revision = release.revision;
+            }
+        }
+        (Some(saved), Some(release)) => {
+            if release.revision == saved.revision && release.status == "deployed" {
+                info!("Helm chart {} is unchanged", chart.name);
+                return Ok(());
+            }
+            helm_rollback_chart(chart, Some(saved.revision)).await?;
+            if let Some(release) = helm_release_status(chart.release(), chart.namespace()).await? {
+                saved.revision = release.revision;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Bring the resources of a manifest set back to the snapshot.
+async fn restore_manifest(manifest: &Manifests, snapshot: &Snapshot) -> Result<()> {
+    let content = kubectl_manifest_content(manifest).await?;
+    let current = kubectl_resource_status(&content, None).await?;
+    let saved = snapshot
+        .manifests
+        .iter()
+        .find(|saved| saved.name == manifest.name);
+    let saved_objects = saved.map(|saved| saved.objects.as_slice()).unwrap_or(&[]);
+    let mut current = current.objects;
+
+    // Resources that were deleted since the snapshot are applied again.
+    let missing = saved_objects
+        .iter()
+        .filter(|object| !current.contains(object))
+        .count();
+    if missing > 0 {
+        if saved.is_some_and(|saved| saved.hash != hash_content(content.as_bytes())) {
+            warn!(
+                "Manifests {} changed since the snapshot, applying the current files",
+                manifest.name
+            );
+        }
+        println!(
+            "Applying Kubernetes manifests {}, {} resources are missing",
+            manifest.name, missing
+        );
+        kubectl_apply_manifest(manifest).await?;
+        store::clear_hash("manifest", &manifest.name);
+
+        // Applying may have created resources that didn't exist before either.
+        current = kubectl_resource_status(&content, None).await?.objects;
+    }
+
+    // Resources that were created after the snapshot are deleted.
+    let added: Vec<ObjectRef> = current
+        .into_iter()
+        .filter(|object| !saved_objects.contains(object))
+        .collect();
+    if !added.is_empty() {
+        for object in &added {
+            println!("Deleting {}, it was created after the snapshot", object);
+        }
+        kubectl_delete_objects(&added).await?;
+        store::clear_hash("manifest", &manifest.name);
+    }
+
+    Ok(())
+}
+
+/// The charts of both phases.
+fn snapshot_charts(config: &Config) -> impl Iterator<Item = &Chart> {
+    config
+        .dependencies
+        .helm
+        .charts
+        .iter()
+        .chain(config.application.helm.charts.iter())
+}
+
+/// The manifest sets of both phases.
+fn snapshot_manifests(config: &Config) -> impl Iterator<Item = &Manifests> {
+    config
+        .dependencies
+        .manifests
+        .iter()
+        .chain(config.application.manifests.iter())
+}
+
+/// Hash the values files of every chart.
+fn snapshot_values(config: &Config) -> BTreeMap<String, String> {
+    let mut values = BTreeMap::new();
+    for chart in snapshot_charts(config) {
+        let mut files = chart.values_files();
+        if files.is_empty() {
+            files.push("values.yaml");
+        }
+        for file in files {
+            let path = Path::new("config/helm").join(&chart.name).join(file);
+            if let Ok(content) = fs::read(&path) {
+                values.insert(path.display().to_string(), hash_content(&content));
+            }
+        }
+    }
+
+    values
+}
+
+/// The path of a snapshot, checking that the name is safe to use as a file name.
+fn snapshot_path(name: &str) -> Result<PathBuf> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    if !valid {
+        return Err(anyhow!(
+            "Invalid snapshot name '{}', use letters, digits, '-', '_' and '.'",
+            name
+        ));
+    }
+
+    Ok(Path::new(SNAPSHOTS_DIR).join(format!("{}.json", name)))
+}
+
+/// Read a snapshot.
+fn read_snapshot(path: &Path) -> Result<Snapshot> {
+    let err_msg = format!("Snapshot {} was not found", path.display());
+    let content = fs::read_to_string(path).context(err_msg)?;
+    let err_msg = format!("Failed to parse snapshot {}", path.display());
+    serde_json::from_str(&content).context(err_msg)
+}
+
+/// The current kube context, if one is set.
+fn kube_context() -> Option<String> {
+    Kubeconfig::read()
+        .ok()
+        .and_then(|kubeconfig| kubeconfig.current_context)
+}
+
+/// Write a snapshot.
+fn write_snapshot(path: &Path, snapshot: &Snapshot) -> Result<()> {
+    let err_msg = format!("Failed to create directory {}", SNAPSHOTS_DIR);
+    fs::create_dir_all(SNAPSHOTS_DIR).context(err_msg)?;
+    let err_msg = format!("Failed to write snapshot {}", path.display());
+    fs::write(path, serde_json::to_string_pretty(snapshot)?).context(err_msg)
+}
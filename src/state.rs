@@ -0,0 +1,219 @@
+//! State
+//!
+//! This module records the progress of an install or uninstall in a state file,
+//! so that an interrupted run can be resumed with `--resume`.
+//!
+//! Each completed step is stored as `<phase>/<kind>/<name>`, for example
+//! `dependencies/chart/cert-manager`. The state file is removed once a run completes.
+//!
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+/// The file where the progress of the current run is stored.
+const STATE_FILE: &str = ".loopy/state.json";
+
+/// The progress of the current run.
+static PROGRESS: OnceLock<Mutex<Progress>> = OnceLock::new();
+
+/// The state file contents.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct State {
+    /// The action that was performed.
+    pub action: String,
+    /// When the run started.
+    pub started: Option<DateTime<Local>>,
+    /// When the state was last updated.
+    pub updated: Option<DateTime<Local>>,
+    /// The steps that completed, in order.
+    pub completed: Vec<String>,
+}
+
+/// Progress of the current run.
+struct Progress {
+    /// The state that is written to the state file.
+    state: State,
+    /// The steps that completed in the run being resumed.
+    resumed: Vec<String>,
+    /// The phase being processed.
+    phase: String,
+}
+
+/// Start recording progress.
+///
+/// # Arguments
+///
+/// * `action` - The action being performed
+/// * `resume` - Whether to skip the steps completed by the last interrupted run
+///
+pub fn start(action: &str, resume: bool) -> Result<()> {
+    let mut resumed = Vec::new();
+    let mut started = Some(Local::now());
+
+    if resume {
+        match read_state()? {
+            Some(previous) if previous.action != action => {
+                return Err(anyhow!(
+                    "Cannot resume, the interrupted run was an {} but this is an {}",
+                    previous.action,
+                    action
+                ));
+            }
+            Some(previous) => {
+                println!(
+                    "Resuming the interrupted {}, {} steps already completed",
+                    action,
+                    previous.completed.len()
+                );
+                info!("Resuming from {}", STATE_FILE);
+                resumed = previous.completed;
+                started = previous.started;
+            }
+            None => {
+                warn!("No interrupted run was found in {}", STATE_FILE);
+                println!("No interrupted run was found, starting from the beginning");
+            }
+        }
+    }
+
+    let progress = Progress {
+        state: State {
+            action: action.to_string(),
+            started,
+            updated: None,
+            completed: Vec::new(),
+        },
+        resumed,
+        phase: String::new(),
+    };
+    if PROGRESS.set(Mutex::new(progress)).is_err() {
+        return Err(anyhow!("Progress recording has already been started"));
+    }
+
+    Ok(())
+}
+
+/// Begin a phase.
+///
+/// Steps completed afterwards are recorded against this phase.
+///
+/// # Arguments
+///
+/// * `phase` - The name of the phase, for example "dependencies"
+///
+pub fn begin_phase(phase: &str) {
+    if let Some(progress) = PROGRESS.get() {
+        let mut progress = progress.lock().unwrap_or_else(|e| e.into_inner());
+        progress.phase = phase.to_string();
+    }
+}
+
+/// The phase being processed, or an empty string outside of a run.
+pub fn phase() -> String {
+    match PROGRESS.get() {
+        Some(progress) => progress
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .phase
+            .clone(),
+        None => String::new(),
+    }
+}
+
+/// Check if a step already completed in the run being resumed.
+///
+/// Steps that completed are carried over into the new state, and a message is printed.
+///
+/// # Arguments
+///
+/// * `kind` - The kind of step, for example "chart"
+/// * `name` - The name of the step
+///
+pub fn is_completed(kind: &str, name: &str) -> bool {
+    let Some(progress) = PROGRESS.get() else {
+        return false;
+    };
+    let mut progress = progress.lock().unwrap_or_else(|e| e.into_inner());
+
+    let key = format!("{}/{}/{}", progress.phase, kind, name);
+    if !progress.resumed.contains(&key) {
+        return false;
+    }
+
+    println!(
+        "Skipping {} {}, it completed in the interrupted run",
+        kind, name
+    );
+    debug!("Skipping completed step {}", key);
+    if !progress.state.completed.contains(&key) {
+        progress.state.completed.push(key);
+    }
+
+    true
+}
+
+/// Record a completed step.
+///
+/// # Arguments
+///
+/// * `kind` - The kind of step, for example "chart"
+/// * `name` - The name of the step
+///
+pub fn complete(kind: &str, name: &str) -> Result<()> {
+    let Some(progress) = PROGRESS.get() else {
+        return Ok(());
+    };
+    let mut progress = progress.lock().unwrap_or_else(|e| e.into_inner());
+
+    let key = format!("{}/{}/{}", progress.phase, kind, name);
+    debug!("Completed step {}", key);
+    progress.state.completed.push(key);
+    progress.state.updated = Some(Local::now());
+
+    write_state(&progress.state)
+}
+
+/// Finish recording progress.
+///
+/// Removes the state file, as there is nothing left to resume.
+///
+pub fn finish() {
+    let path = Path::new(STATE_FILE);
+    if path.exists() {
+        if let Err(e) = fs::remove_file(path) {
+            warn!("Failed to remove {}: {}", STATE_FILE, e);
+        }
+    }
+}
+
+/// Read the state file, if it exists.
+fn read_state() -> Result<Option<State>> {
+    let path = Path::new(STATE_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let err_msg = format!("Failed to read {}", STATE_FILE);
+    let content = fs::read_to_string(path).context(err_msg)?;
+    let err_msg = format!("Failed to parse {}", STATE_FILE);
+    let state = serde_json::from_str(&content).context(err_msg)?;
+
+    Ok(Some(state))
+}
+
+/// Write the state file.
+fn write_state(state: &State) -> Result<()> {
+    let path = Path::new(STATE_FILE);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let content = serde_json::to_string_pretty(state)?;
+    let err_msg = format!("Failed to write {}", STATE_FILE);
+    fs::write(path, content).context(err_msg)
+}
@@ -0,0 +1,351 @@
+//! Status
+//!
+//! This module reports what is installed in the cluster for every Helm
+//! repository, chart and manifest set in the configuration, and whether it is
+//! healthy, as a table or as JSON.
+//!
+//! A chart is healthy when its release is deployed, all Pods of its workloads
+//! are ready and its test hooks didn't fail the last time they ran. A
+//! manifest set is healthy when all of its resources exist and the Pods of its
+//! workloads are ready. A configured test is healthy when it passed the last
+//! time it ran.
+//!
+//! The state store tells which components loopy installed, and whether the
+//! configuration changed since the last install.
+//!
+
+use crate::config::{Chart, Config, Helm, Manifests, Test};
+use crate::helm::{helm_release_status, helm_repo_names};
+use crate::kubectl::{kubectl_manifest_content, kubectl_resource_status, ResourceStatus};
+use crate::store::{self, Store};
+use crate::PACKAGE_NAME;
+
+use anyhow::Result;
+use crossterm::style::{Color, Stylize};
+use serde::Serialize;
+use std::io::IsTerminal;
+
+/// The state of a configured component.
+#[derive(Debug, Default, Serialize)]
+pub struct ComponentStatus {
+    /// The phase the component belongs to.
+    pub phase: String,
+    /// The kind of component, "repository", "chart", "manifest" or "test".
+    pub kind: String,
+    /// The name of the component.
+    pub name: String,
+    /// Whether the component is installed.
+    pub installed: bool,
+    /// The installed chart version.
+    pub version: Option<String>,
+    /// The release revision.
+    pub revision: Option<u64>,
+    /// The release status, or whether the resources of a manifest set exist.
+    pub status: Option<String>,
+    /// The ready Pods of the workloads.
+    pub ready: Option<u64>,
+    /// The desired Pods of the workloads.
+    pub desired: Option<u64>,
+    /// The outcome of the last run of the test hooks or the configured test.
+    pub test: Option<String>,
+    /// Whether loopy installed the component, if the state store could be read.
+    pub owned: Option<bool>,
+    /// Whether the component is installed and healthy.
+    pub healthy: bool,
+    /// Why the state couldn't be determined.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Show status.
+///
+/// Prints the state of every configured component.
+///
+/// # Arguments
+///
+/// * `config` - The loaded configuration
+/// * `json` - Print JSON instead of a table
+///
+/// # Returns
+///
+/// True if every component is installed and healthy.
+///
+pub async fn status_show(config: &Config, json: bool) -> Result<bool> {
+    let mut statuses = Vec::new();
+    let repo_names = helm_repo_names().await;
+    let installed = store::current();
+
+    let phases = [
+        (
+            "dependencies",
+            &config.dependencies.helm,
+            &config.dependencies.manifests,
+            &config.dependencies.tests,
+        ),
+        (
+            "application",
+            &config.application.helm,
+            &config.application.manifests,
+            &config.application.tests,
+        ),
+    ];
+    for (phase, helm, manifests, tests) in phases {
+        status_repos(phase, helm, &repo_names, &mut statuses);
+        for manifest in manifests {
+            let mut status = status_manifest(phase, manifest).await;
+            status.owned = status_owned(&installed, "manifest", &manifest.name);
+            statuses.push(status);
+        }
+        for chart in &helm.charts {
+            let mut status = status_chart(phase, chart).await;
+            status.owned = status_owned(&installed, "chart", &chart.name);
+            statuses.push(status);
+        }
+        status_tests(phase, tests, &installed, &mut statuses);
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&statuses)?);
+    } else {
+        status_store(config, &installed);
+        status_table(&statuses);
+    }
+
+    Ok(statuses.iter().all(|status| status.healthy))
+}
+
+/// The state of the Helm repositories of a phase.
+fn status_repos(
+    phase: &str,
+    helm: &Helm,
+    repo_names: &Result<Vec<String>>,
+    statuses: &mut Vec<ComponentStatus>,
+) {
+    for repo in &helm.repositories {
+        let mut status = ComponentStatus {
+            phase: phase.to_string(),
+            kind: "repository".to_string(),
+            name: repo.name.clone(),
+            ..ComponentStatus::default()
+        };
+        match repo_names {
+            Ok(names) => {
+                status.installed = names.contains(&repo.name);
+                status.healthy = status.installed;
+                status.status = status.installed.then(|| "added".to_string());
+            }
+            Err(e) => status.error = Some(format!("{:#}", e)),
+        }
+        statuses.push(status);
+    }
+}
+
+/// The state of a manifest set.
+async fn status_manifest(phase: &str, manifest: &Manifests) -> ComponentStatus {
+    let mut status = ComponentStatus {
+        phase: phase.to_string(),
+        kind: "manifest".to_string(),
+        name: manifest.name.clone(),
+        ..ComponentStatus::default()
+    };
+
+    let resources = match kubectl_manifest_content(manifest).await {
+        Ok(content) => kubectl_resource_status(&content, None).await,
+        Err(e) => Err(e),
+    };
+    match resources {
+        Ok(resources) => {
+            status.installed = resources.found > 0;
+            status.status = Some(if resources.found == 0 {
+                "missing".to_string()
+            } else if resources.found < resources.total {
+                format!("partial {}/{}", resources.found, resources.total)
+            } else {
+                "applied".to_string()
+            });
+            status_pods(&mut status, &resources);
+            status.healthy = resources.found == resources.total
+                && status.installed
+                && status.ready >= status.desired;
+        }
+        Err(e) => status.error = Some(format!("{:#}", e)),
+    }
+
+    status
+}
+
+/// The state of the release of a chart.
+async fn status_chart(phase: &str, chart: &Chart) -> ComponentStatus {
+    let mut status = ComponentStatus {
+        phase: phase.to_string(),
+        kind: "chart".to_string(),
+        name: chart.name.clone(),
+        ..ComponentStatus::default()
+    };
+
+    let release = match helm_release_status(chart.release(), chart.namespace()).await {
+        Ok(Some(release)) => release,
+        Ok(None) => return status,
+        Err(e) => {
+            status.error = Some(format!("{:#}", e));
+            return status;
+        }
+    };
+    status.installed = true;
+    status.version = Some(release.version);
+    status.revision = Some(release.revision);
+    status.test = release.test;
+
+    match kubectl_resource_status(&release.manifest, Some(chart.namespace())).await {
+        Ok(resources) => status_pods(&mut status, &resources),
+        Err(e) => status.error = Some(format!("{:#}", e)),
+    }
+    status.healthy = release.status == "deployed"
+        && status.error.is_none()
+        && status.ready >= status.desired
+        && status.test.as_deref() != Some("failed");
+    status.status = Some(release.status);
+
+    status
+}
+
+/// The outcomes of the configured tests of a phase, as recorded in the state store.
+fn status_tests(
+    phase: &str,
+    tests: &[Test],
+    installed: &Option<Store>,
+    statuses: &mut Vec<ComponentStatus>,
+) {
+    for (index, test) in tests.iter().enumerate() {
+        let name = format!("{} #{}", test.label(), index + 1);
+        let outcome = installed
+            .as_ref()
+            .and_then(|installed| installed.test(phase, &name))
+            .map(|outcome| if outcome.passed { "passed" } else { "failed" });
+        statuses.push(ComponentStatus {
+            phase: phase.to_string(),
+            kind: "test".to_string(),
+            name,
+            installed: outcome.is_some(),
+            status: Some(outcome.unwrap_or("not run").to_string()),
+            test: outcome.map(str::to_string),
+            healthy: outcome == Some("passed"),
+            ..ComponentStatus::default()
+        });
+    }
+}
+
+/// Whether loopy installed a component, if the state store could be read.
+fn status_owned(installed: &Option<Store>, kind: &str, name: &str) -> Option<bool> {
+    let installed = installed.as_ref()?;
+    Some(installed.component(kind, name).is_some())
+}
+
+/// Print what the state store says about the last install.
+fn status_store(config: &Config, installed: &Option<Store>) {
+    let installed = match installed {
+        Some(installed) => installed,
+        None => {
+            let e = store::load_error().unwrap_or_default();
+            println!("The state store could not be read: {}", e);
+            println!();
+            return;
+        }
+    };
+    if !installed.is_recorded() {
+        println!("Nothing was installed by {} in this cluster.", PACKAGE_NAME);
+        println!();
+        return;
+    }
+
+    match installed.installed {
+        Some(time) => println!(
+            "Installed by {} {} on {}, the configuration {} since.",
+            PACKAGE_NAME,
+            installed.version,
+            time.format("%Y-%m-%d %H:%M:%S"),
+            match store::config_changed() {
+                Some(true) => "has changed",
+                _ => "has not changed",
+            }
+        ),
+        None => println!(
+            "An install by {} {} has not completed.",
+            PACKAGE_NAME, installed.version
+        ),
+    }
+    let orphans: Vec<String> = installed
+        .orphans(config)
+        .iter()
+        .map(|component| format!("{} {}", component.kind, component.name))
+        .collect();
+    if !orphans.is_empty() {
+        println!(
+            "Installed but no longer in the configuration: {}",
+            orphans.join(", ")
+        );
+    }
+    println!();
+}
+
+/// Record the Pods of the workloads, if there are any.
+fn status_pods(status: &mut ComponentStatus, resources: &ResourceStatus) {
+    if resources.desired > 0 {
+        status.ready = Some(resources.ready);
+        status.desired = Some(resources.desired);
+    }
+}
+
+/// Print the states as a table, colored when printing to a terminal.
+fn status_table(statuses: &[ComponentStatus]) {
+    if statuses.is_empty() {
+        println!("No repositories, charts, manifests or tests were found in the configuration.");
+        return;
+    }
+
+    let colored = std::io::stdout().is_terminal();
+    println!(
+        "{:<13} {:<11} {:<24} {:<16} {:<12} {:>4} {:>7}  TEST",
+        "PHASE", "KIND", "NAME", "STATUS", "VERSION", "REV", "READY"
+    );
+    for status in statuses {
+        let state = match (&status.status, status.installed, &status.error) {
+            (_, _, Some(_)) => "unknown".to_string(),
+            (Some(state), _, _) => state.clone(),
+            (None, false, _) => "not installed".to_string(),
+            (None, true, _) => "installed".to_string(),
+        };
+        let state = format!("{:<16}", state);
+        let state = match (colored, status.healthy, status.installed) {
+            (false, _, _) => state,
+            (true, true, _) => state.with(Color::Green).to_string(),
+            (true, false, true) => state.with(Color::Red).to_string(),
+            (true, false, false) => state.with(Color::Yellow).to_string(),
+        };
+        let ready = match (status.ready, status.desired) {
+            (Some(ready), Some(desired)) => format!("{}/{}", ready, desired),
+            _ => "-".to_string(),
+        };
+
+        println!(
+            "{:<13} {:<11} {:<24} {} {:<12} {:>4} {:>7}  {}",
+            status.phase,
+            status.kind,
+            status.name,
+            state,
+            status.version.as_deref().unwrap_or("-"),
+            status
+                .revision
+                .map(|revision| revision.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            ready,
+            status.test.as_deref().unwrap_or("-")
+        );
+    }
+
+    for status in statuses {
+        if let Some(error) = &status.error {
+            println!("{} {}: {}", status.kind, status.name, error);
+        }
+    }
+}
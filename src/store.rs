@@ -0,0 +1,455 @@
+//! Store
+//!
+//! This module keeps the state of an install in the cluster, in the
+//! `loopy-state` ConfigMap of the `loopy` namespace. It records the charts and
+//! manifest sets that loopy installed, with their versions, input hashes and
+//! timestamps, the hash of the configuration and the version of loopy that
+//! installed them.
+//!
+//! An install records each component as soon as it completes, so the store is
+//! accurate even when a run fails. An uninstall only removes the components
+//! recorded here, including those that were removed from the configuration
+//! since they were installed. Until an install completes, for example in a
+//! cluster installed by an older version, loopy is treated as owning every component.
+//!
+
+use crate::config::Config;
+use crate::kubectl::{kubectl_namespace, kubectl_namespace_check, ObjectRef};
+use crate::PACKAGE_NAME;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local};
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::api::{ObjectMeta, Patch, PatchParams};
+use kube::{api::Api, Client};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+/// The name of the ConfigMap that holds the store.
+pub const STORE_NAME: &str = "loopy-state";
+
+/// The ConfigMap key that holds the store.
+const STORE_KEY: &str = "state.json";
+
+/// The store read from the cluster, once it has been loaded.
+static STORE: Mutex<Option<Store>> = Mutex::new(None);
+
+/// Why the store could not be loaded, if it couldn't.
+static LOAD_ERROR: Mutex<Option<String>> = Mutex::new(None);
+
+/// How long to wait for the cluster when loading the store.
+const LOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serialises writing the store, so a newer state is never overwritten by an older one.
+static SAVE_LOCK: OnceLock<tokio::sync::Mutex<()>> = OnceLock::new();
+
+/// The hash of the loaded configuration.
+static CONFIG_HASH: OnceLock<String> = OnceLock::new();
+
+/// The state of the install.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Store {
+    /// The version of loopy that last changed the store.
+    pub version: String,
+    /// The hash of the configuration of the last completed install.
+    pub config_hash: Option<String>,
+    /// When the store was created.
+    pub created: Option<DateTime<Local>>,
+    /// When the last install completed.
+    pub installed: Option<DateTime<Local>>,
+    /// When the store was last changed.
+    pub updated: Option<DateTime<Local>>,
+    /// The components that loopy installed, in the order they were installed.
+    pub components: Vec<Component>,
+    /// The outcome of the last run of every configured test.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tests: Vec<TestOutcome>,
+}
+
+/// A chart or manifest set that loopy installed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Component {
+    /// The phase the component was installed in.
+    pub phase: String,
+    /// The kind of component, "chart" or "manifest".
+    pub kind: String,
+    /// The name of the component in the configuration.
+    pub name: String,
+    /// The name of the Helm release.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release: Option<String>,
+    /// The namespace of the Helm release.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// The installed chart version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// The installed release revision.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<u64>,
+    /// The hash of the inputs that were installed, if they are known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// The resources of a manifest set.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub objects: Vec<ObjectRef>,
+    /// When the component was first installed.
+    pub installed: DateTime<Local>,
+    /// When the component was last installed, upgraded or rolled back.
+    pub updated: DateTime<Local>,
+}
+
+/// The outcome of the last run of a configured test.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestOutcome {
+    /// The phase the test belongs to.
+    pub phase: String,
+    /// The label of the test and its position in the phase, for example "smoke #1".
+    pub name: String,
+    /// Whether the test passed.
+    pub passed: bool,
+    /// When the test last ran.
+    pub ran: DateTime<Local>,
+}
+
+impl Store {
+    /// Find the outcome of a test.
+    pub fn test(&self, phase: &str, name: &str) -> Option<&TestOutcome> {
+        self.tests
+            .iter()
+            .find(|test| test.phase == phase && test.name == name)
+    }
+
+    /// Find a component.
+    pub fn component(&self, kind: &str, name: &str) -> Option<&Component> {
+        self.components
+            .iter()
+            .find(|component| component.kind == kind && component.name == name)
+    }
+
+    /// Whether anything was ever recorded, as opposed to a cluster without a store.
+    pub fn is_recorded(&self) -> bool {
+        self.created.is_some()
+    }
+
+    /// The components that are no longer in the configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The loaded configuration
+    ///
+    pub fn orphans(&self, config: &Config) -> Vec<Component> {
+        let charts = config
+            .dependencies
+            .helm
+            .charts
+            .iter()
+            .chain(config.application.helm.charts.iter())
+            .map(|chart| ("chart", chart.name.as_str()));
+        let manifests = config
+            .dependencies
+            .manifests
+            .iter()
+            .chain(config.application.manifests.iter())
+            .map(|manifest| ("manifest", manifest.name.as_str()));
+        let configured: Vec<(&str, &str)> = charts.chain(manifests).collect();
+
+        self.components
+            .iter()
+            .filter(|component| {
+                !configured.contains(&(component.kind.as_str(), component.name.as_str()))
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// Set the hash of the loaded configuration.
+///
+/// # Arguments
+///
+/// * `hash` - The hash of the configuration, with its includes and profile applied
+///
+pub fn set_config_hash(hash: String) {
+    if CONFIG_HASH.set(hash).is_err() {
+        debug!("The configuration hash has already been set");
+    }
+}
+
+/// Load the store from the cluster.
+///
+/// The store is empty when the cluster has none yet. Once loaded, components
+/// are recorded in it and written back with [`save`]. When the cluster can't be
+/// reached, the error is kept for [`load_error`].
+///
+pub async fn load() -> Result<Store> {
+    let result = match tokio::time::timeout(LOAD_TIMEOUT, read()).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!(
+            "The cluster did not respond within {} seconds",
+            LOAD_TIMEOUT.as_secs()
+        )),
+    };
+
+    match result {
+        Ok(store) => {
+            *STORE.lock().unwrap_or_else(|e| e.into_inner()) = Some(store.clone());
+            Ok(store)
+        }
+        Err(e) => {
+            *LOAD_ERROR.lock().unwrap_or_else(|e| e.into_inner()) = Some(format!("{:#}", e));
+            Err(e)
+        }
+    }
+}
+
+/// Why the store could not be loaded, if loading it failed.
+pub fn load_error() -> Option<String> {
+    LOAD_ERROR.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Read the store from the cluster.
+async fn read() -> Result<Store> {
+    let client = Client::try_default().await?;
+    let config_maps: Api<ConfigMap> = Api::namespaced(client, PACKAGE_NAME);
+
+    let err_msg = format!("Failed to read ConfigMap {}/{}", PACKAGE_NAME, STORE_NAME);
+    let store = match config_maps.get_opt(STORE_NAME).await.context(err_msg)? {
+        Some(config_map) => {
+            let content = config_map
+                .data
+                .as_ref()
+                .and_then(|data| data.get(STORE_KEY))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "ConfigMap {}/{} has no {} key",
+                        PACKAGE_NAME,
+                        STORE_NAME,
+                        STORE_KEY
+                    )
+                })?;
+            let err_msg = format!("Failed to parse ConfigMap {}/{}", PACKAGE_NAME, STORE_NAME);
+            serde_json::from_str(content).context(err_msg)?
+        }
+        None => {
+            info!("No state was found in {}/{}", PACKAGE_NAME, STORE_NAME);
+            Store::default()
+        }
+    };
+
+    Ok(store)
+}
+
+/// The loaded store, if it has been loaded.
+pub fn current() -> Option<Store> {
+    STORE.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Write the store to the cluster, creating the namespace if needed.
+///
+/// Does nothing when the store hasn't been loaded or is still empty.
+///
+pub async fn save() -> Result<()> {
+    let _lock = SAVE_LOCK
+        .get_or_init(|| tokio::sync::Mutex::new(()))
+        .lock()
+        .await;
+
+    // Nothing is written for a cluster where loopy hasn't installed anything.
+    let recorded =
+        current().is_some_and(|store| store.is_recorded() || !store.components.is_empty());
+    if !recorded {
+        return Ok(());
+    }
+    let Some(store) = update(|store| {
+        let now = Local::now();
+        store.version = env!("CARGO_PKG_VERSION").to_string();
+        store.created.get_or_insert(now);
+        store.updated = Some(now);
+    }) else {
+        return Ok(());
+    };
+
+    if !kubectl_namespace_check(PACKAGE_NAME).await? {
+        kubectl_namespace("create", PACKAGE_NAME).await?;
+    }
+
+    let mut labels = BTreeMap::new();
+    labels.insert("app".to_owned(), "loopy".to_owned());
+    let mut data = BTreeMap::new();
+    data.insert(STORE_KEY.to_owned(), serde_json::to_string_pretty(&store)?);
+    let config_map = ConfigMap {
+        metadata: ObjectMeta {
+            name: Some(STORE_NAME.to_owned()),
+            namespace: Some(PACKAGE_NAME.to_owned()),
+            labels: Some(labels),
+            ..Default::default()
+        },
+        data: Some(data),
+        ..Default::default()
+    };
+
+    let client = Client::try_default().await?;
+    let config_maps: Api<ConfigMap> = Api::namespaced(client, PACKAGE_NAME);
+    let err_msg = format!("Failed to write ConfigMap {}/{}", PACKAGE_NAME, STORE_NAME);
+    config_maps
+        .patch(
+            STORE_NAME,
+            &PatchParams::apply(PACKAGE_NAME).force(),
+            &Patch::Apply(&config_map),
+        )
+        .await
+        .context(err_msg)?;
+    debug!("Saved the state to {}/{}", PACKAGE_NAME, STORE_NAME);
+
+    Ok(())
+}
+
+/// Check if loopy owns a component.
+///
+/// Until an install has completed, the store may be missing components that an
+/// earlier version or an interrupted run installed, so every component is
+/// considered owned.
+///
+/// # Arguments
+///
+/// * `kind` - The kind of component, "chart" or "manifest"
+/// * `name` - The name of the component
+///
+pub fn is_owned(kind: &str, name: &str) -> bool {
+    match current() {
+        Some(store) if store.installed.is_some() => store.component(kind, name).is_some(),
+        _ => true,
+    }
+}
+
+/// The hash of the installed inputs of a component, if they are known.
+///
+/// # Arguments
+///
+/// * `kind` - The kind of component, "chart" or "manifest"
+/// * `name` - The name of the component
+///
+pub fn hash(kind: &str, name: &str) -> Option<String> {
+    current()?.component(kind, name)?.hash.clone()
+}
+
+/// Record an installed component.
+///
+/// The time it was first installed is kept, and so is its phase when the
+/// component is recorded outside of an install.
+///
+/// # Arguments
+///
+/// * `component` - The installed component
+///
+pub fn record(mut component: Component) {
+    update(|store| {
+        match store
+            .components
+            .iter_mut()
+            .find(|existing| existing.kind == component.kind && existing.name == component.name)
+        {
+            Some(existing) => {
+                component.installed = existing.installed;
+                if component.phase.is_empty() {
+                    component.phase = existing.phase.clone();
+                }
+                *existing = component;
+            }
+            None => store.components.push(component),
+        }
+    });
+}
+
+/// Record the outcome of a configured test, replacing the outcome of its last run.
+///
+/// # Arguments
+///
+/// * `phase` - The phase the test belongs to
+/// * `name` - The label of the test and its position in the phase
+/// * `passed` - Whether the test passed
+///
+pub fn record_test(phase: &str, name: &str, passed: bool) {
+    let outcome = TestOutcome {
+        phase: phase.to_string(),
+        name: name.to_string(),
+        passed,
+        ran: Local::now(),
+    };
+    update(|store| {
+        match store
+            .tests
+            .iter_mut()
+            .find(|test| test.phase == phase && test.name == name)
+        {
+            Some(existing) => *existing = outcome,
+            None => store.tests.push(outcome),
+        }
+    });
+}
+
+/// Forget the installed inputs of a component, so it is installed again by the next run.
+///
+/// # Arguments
+///
+/// * `kind` - The kind of component, "chart" or "manifest"
+/// * `name` - The name of the component
+///
+pub fn clear_hash(kind: &str, name: &str) {
+    update(|store| {
+        if let Some(component) = store
+            .components
+            .iter_mut()
+            .find(|component| component.kind == kind && component.name == name)
+        {
+            component.hash = None;
+        }
+    });
+}
+
+/// Remove an uninstalled component.
+///
+/// # Arguments
+///
+/// * `kind` - The kind of component, "chart" or "manifest"
+/// * `name` - The name of the component
+///
+pub fn remove(kind: &str, name: &str) {
+    update(|store| {
+        store
+            .components
+            .retain(|component| component.kind != kind || component.name != name);
+    });
+}
+
+/// Record that an install completed with the loaded configuration.
+pub fn complete() {
+    update(|store| {
+        store.installed = Some(Local::now());
+        store.config_hash = CONFIG_HASH.get().cloned();
+    });
+}
+
+/// Check if the configuration changed since the last completed install.
+///
+/// # Returns
+///
+/// None if no install has completed or the store hasn't been loaded.
+///
+pub fn config_changed() -> Option<bool> {
+    let installed = current()?.config_hash?;
+    Some(CONFIG_HASH.get() != Some(&installed))
+}
+
+/// Change the loaded store, returning the result.
+fn update(change: impl FnOnce(&mut Store)) -> Option<Store> {
+    let mut store = STORE.lock().unwrap_or_else(|e| e.into_inner());
+    let store = store.as_mut()?;
+    change(store);
+
+    Some(store.clone())
+}
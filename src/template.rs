@@ -0,0 +1,282 @@
+//! Template
+//!
+//! This module renders values files and manifests before they are used.
+//!
+//! A template reference looks like `{{ vars.domain }}`, `{{ env.HOME }}` or
+//! `{{ cluster.storage_class }}`. Any other `{{ ... }}` expression, such as a
+//! Helm `{{ .Values.name }}` template, is left untouched. Files that contain
+//! references are rendered to `.loopy/cache/rendered`, keeping their relative path.
+//! As they may hold secrets from the environment, only the current user can read
+//! them. SOPS encrypted files are rendered in memory once decrypted instead.
+//!
+//! The cluster facts are:
+//!
+//! * `cluster.context` - The name of the current kubeconfig context
+//! * `cluster.node_ip` - The internal IP of the first node
+//! * `cluster.node_ips` - The internal IPs of all nodes, separated by commas
+//! * `cluster.storage_class` - The name of the default StorageClass
+//! * `cluster.metallb_pool` - The first address range of the first MetalLB IPAddressPool
+//!
+
+use anyhow::{anyhow, Context, Result};
+use k8s_openapi::api::core::v1::Node;
+use k8s_openapi::api::storage::v1::StorageClass;
+use kube::api::{Api, ApiResource, DynamicObject, GroupVersionKind, ListParams};
+use kube::config::Kubeconfig;
+use kube::Client;
+use log::debug;
+use regex::{Captures, Regex};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+use tokio::sync::OnceCell;
+
+/// The directory where rendered files are written.
+const RENDERED_DIR: &str = ".loopy/cache/rendered";
+
+/// The variables from the `vars` section of the configuration.
+static VARS: OnceLock<BTreeMap<String, String>> = OnceLock::new();
+
+/// The cluster facts, discovered the first time a file refers to them.
+static CLUSTER_FACTS: OnceCell<BTreeMap<String, String>> = OnceCell::const_new();
+
+/// Matches a template reference, for example `{{ vars.domain }}`.
+fn reference_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| Regex::new(r"\{\{\s*(vars|env|cluster)\.([A-Za-z0-9_]+)\s*\}\}").unwrap())
+}
+
+/// Set the template variables.
+///
+/// # Arguments
+///
+/// * `vars` - The variables from the `vars` section of the configuration
+///
+pub fn set_vars(vars: BTreeMap<String, String>) {
+    if VARS.set(vars).is_err() {
+        debug!("Template variables were already set");
+    }
+}
+
+/// Render a file.
+///
+/// The rendered file is written to a temporary file next to it and moved into
+/// place, so a component never reads a file that another one is still writing.
+///
+/// # Arguments
+///
+/// * `path` - The path of the file to render
+///
+/// # Returns
+///
+/// The path of the rendered file, or the original path if it has no template references.
+///
+pub async fn render_file(path: &Path) -> Result<PathBuf> {
+    if !path.is_file() {
+        return Ok(path.to_path_buf());
+    }
+
+    let err_msg = format!("Failed to read {}", path.display());
+    let content = fs::read_to_string(path).context(err_msg)?;
+
+    // Encrypted files are rendered in memory after they are decrypted.
+    if !reference_regex().is_match(&content) || crate::secrets::is_encrypted(path) {
+        return Ok(path.to_path_buf());
+    }
+
+    let err_msg = format!("Failed to render {}", path.display());
+    let rendered = render(&content).await.context(err_msg)?;
+
+    // Keep the relative path, so rendered files don't collide.
+    let relative: PathBuf = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name),
+            Component::ParentDir => Some("__parent__".as_ref()),
+            _ => None,
+        })
+        .collect();
+    let rendered_path = Path::new(RENDERED_DIR).join(relative);
+    let dir = rendered_path.parent().unwrap_or(Path::new(RENDERED_DIR));
+    let err_msg = format!("Failed to create directory {}", dir.display());
+    fs::create_dir_all(dir).context(err_msg)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let err_msg = format!("Failed to restrict access to {}", RENDERED_DIR);
+        fs::set_permissions(RENDERED_DIR, fs::Permissions::from_mode(0o700)).context(err_msg)?;
+    }
+
+    // The temporary file is only readable by the current user.
+    let err_msg = format!("Failed to write {}", rendered_path.display());
+    let mut file = tempfile::NamedTempFile::new_in(dir).context(err_msg.clone())?;
+    file.write_all(rendered.as_bytes())
+        .context(err_msg.clone())?;
+    file.persist(&rendered_path).context(err_msg)?;
+
+    debug!("Rendered {} to {}", path.display(), rendered_path.display());
+
+    Ok(rendered_path)
+}
+
+/// Render the template references in a string.
+pub async fn render(content: &str) -> Result<String> {
+    let needs_cluster = reference_regex()
+        .captures_iter(content)
+        .any(|captures| &captures[1] == "cluster");
+    let cluster = if needs_cluster {
+        Some(CLUSTER_FACTS.get_or_try_init(cluster_facts).await?)
+    } else {
+        None
+    };
+
+    let mut missing = Vec::new();
+    let rendered = reference_regex().replace_all(content, |captures: &Captures| {
+        let (scope, name) = (&captures[1], &captures[2]);
+        let value = match scope {
+            "vars" => VARS.get().and_then(|vars| vars.get(name)).cloned(),
+            "env" => std::env::var(name).ok(),
+            _ => cluster.and_then(|facts| facts.get(name)).cloned(),
+        };
+        value.unwrap_or_else(|| {
+            missing.push(format!("{}.{}", scope, name));
+            captures[0].to_string()
+        })
+    });
+
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "Undefined template variables: {}",
+            missing.join(", ")
+        ));
+    }
+
+    Ok(rendered.into_owned())
+}
+
+/// Discover the cluster facts.
+async fn cluster_facts() -> Result<BTreeMap<String, String>> {
+    let mut facts = BTreeMap::new();
+
+    if let Some(context) = Kubeconfig::read()
+        .ok()
+        .and_then(|kubeconfig| kubeconfig.current_context)
+    {
+        facts.insert("context".to_string(), context);
+    }
+
+    let client = Client::try_default().await?;
+
+    let nodes: Api<Node> = Api::all(client.clone());
+    let node_ips: Vec<String> = nodes
+        .list(&ListParams::default())
+        .await
+        .context("Failed to list nodes")?
+        .items
+        .into_iter()
+        .filter_map(|node| node.status?.addresses)
+        .flat_map(|addresses| addresses.into_iter())
+        .filter(|address| address.type_ == "InternalIP")
+        .map(|address| address.address)
+        .collect();
+    if let Some(node_ip) = node_ips.first() {
+        facts.insert("node_ip".to_string(), node_ip.clone());
+    }
+    facts.insert("node_ips".to_string(), node_ips.join(","));
+
+    let storage_classes: Api<StorageClass> = Api::all(client.clone());
+    let default_class = storage_classes
+        .list(&ListParams::default())
+        .await
+        .context("Failed to list StorageClasses")?
+        .items
+        .into_iter()
+        .find(|class| {
+            class
+                .metadata
+                .annotations
+                .as_ref()
+                .and_then(|annotations| {
+                    annotations.get("storageclass.kubernetes.io/is-default-class")
+                })
+                .is_some_and(|value| value == "true")
+        });
+    if let Some(name) = default_class.and_then(|class| class.metadata.name) {
+        facts.insert("storage_class".to_string(), name);
+    }
+
+    // MetalLB may not be installed, so a missing CRD isn't an error.
+    let gvk = GroupVersionKind::gvk("metallb.io", "v1beta1", "IPAddressPool");
+    let pools: Api<DynamicObject> = Api::all_with(client, &ApiResource::from_gvk(&gvk));
+    match pools.list(&ListParams::default()).await {
+        Ok(pools) => {
+            let address = pools
+                .items
+                .first()
+                .and_then(|pool| pool.data["spec"]["addresses"][0].as_str())
+                .map(|address| address.to_string());
+            if let Some(address) = address {
+                facts.insert("metallb_pool".to_string(), address);
+            }
+        }
+        Err(e) => debug!("No MetalLB address pools were found: {}", e),
+    }
+
+    debug!("Discovered cluster facts: {:?}", facts);
+
+    Ok(facts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Set the variables and cluster facts the tests render, once for all tests.
+    fn setup() {
+        set_vars(BTreeMap::from([(
+            "domain".to_string(),
+            "example.com".to_string(),
+        )]));
+        let facts = BTreeMap::from([("storage_class".to_string(), "standard".to_string())]);
+        let _ = CLUSTER_FACTS.set(facts);
+    }
+
+    #[tokio::test]
+    async fn render_references() {
+        setup();
+        std::env::set_var("LOOPY_TEMPLATE_TEST", "secret");
+
+        let rendered = render(
+            "host: {{ vars.domain }}\nkey: {{env.LOOPY_TEMPLATE_TEST}}\nclass: {{  cluster.storage_class }}\n",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            rendered,
+            "host: example.com\nkey: secret\nclass: standard\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn leave_helm_templates_untouched() {
+        setup();
+
+        let content = "name: {{ .Values.name }}\nimage: {{ include \"app.image\" . }}\n";
+        assert_eq!(render(content).await.unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn undefined_references_are_an_error() {
+        setup();
+
+        let e = render("a: {{ vars.missing }}\nb: {{ cluster.missing }}\n")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Undefined template variables: vars.missing, cluster.missing"
+        );
+    }
+}
@@ -3,12 +3,25 @@
 //! This module contains utility functions used throughout the program.
 //!
 
+use crate::cache;
+use crate::changes;
 use crate::config::*;
-use crate::helm::{helm_process_charts, helm_process_repos, helm_repo};
+use crate::graph::{self, Graph, OwnedComponent};
+use crate::helm::{
+    helm_process_chart, helm_process_repos, helm_registry_login, helm_repo, helm_test,
+    helm_uninstall_chart,
+};
+use crate::http::http_test;
 use crate::kubectl::{
-    kubectl_apply_manifest, kubectl_delete_manifest, kubectl_namespace, kubectl_process_manifests,
-    ApplyFn,
+    kubectl_apply_manifest, kubectl_delete_manifest, kubectl_delete_objects, kubectl_namespace,
+    kubectl_process_manifest, kubectl_run_test_pod, ApplyFn,
 };
+use crate::logger;
+use crate::runs;
+use crate::secrets::process_secret;
+use crate::signal;
+use crate::state;
+use crate::store;
 use crate::PACKAGE_NAME;
 use anyhow::{anyhow, Context, Result};
 use crossterm::{
@@ -19,21 +32,27 @@ use crossterm::{
 use figlet_rs::FIGfont;
 use flate2::read::GzDecoder;
 use futures_util::StreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use infer::Infer;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use reqwest::Client;
+use sha2::{Digest, Sha256};
 use std::env;
 use std::fs::{self, create_dir_all, File};
 use std::io::{self, stdout, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::OnceLock;
+use std::time::Duration;
 use tar::Archive;
 use tempfile::TempDir;
-use tokio::macros::support::Future;
+use tokio::io::{AsyncBufReadExt, BufReader as AsyncBufReader};
 use which::which;
 use zip::ZipArchive;
 
+/// Progress spinners of all running streaming commands.
+static PROGRESS: OnceLock<MultiProgress> = OnceLock::new();
+
 //const GZIP_MAGIC: [u8; 8] = [0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00];
 //const BZIP2_MAGIC: [u8; 3] = [0x42, 0x5a, 0x68];
 
@@ -108,6 +127,14 @@ pub fn create_dir(dir: &PathBuf) -> Result<()> {
     }
 }
 
+/// Hash content.
+///
+/// Returns the SHA-256 of the content as a hex string.
+///
+pub fn hash_content(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
 /// Pause
 ///
 /// Waits for a user to continue.
@@ -401,13 +428,10 @@ fn extract_archive(path: &Path, dest: &Path) -> Result<PathBuf> {
         }
 
         _ => {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "Extracting the archive format {} is not yet implemented",
-                    mime_type
-                ),
-            )
+            return Err(io::Error::other(format!(
+                "Extracting the archive format {} is not yet implemented",
+                mime_type
+            ))
             .into())
         }
     };
@@ -713,12 +737,67 @@ pub async fn download_tool(
 
 /// Run Command.
 ///
-/// Runs a command with the provided arguments
+/// Runs a command with the provided arguments on the blocking thread pool,
+/// so it doesn't hold up the other components while it runs.
+/// The command is recorded in the transcript of the current run.
+///
+/// # Arguments
+///
+/// * `cmd_name` - The name of the command to run
+/// * `args` - A slice of string references representing the arguments to pass to the command
+///
+/// # Returns
+///
+/// A `Result` containing;
+///    - the standard output of the command if successful.
+///    - the standard error of the command if it failed.
+///    - the exit status.
+///
+pub async fn run_command(
+    cmd_name: &str,
+    args: &[&str],
+) -> Result<(String, String, std::process::ExitStatus)> {
+    run_command_with_input(cmd_name, args, None).await
+}
+
+/// Run Command With Input.
+///
+/// Runs a command with the provided arguments, writing the input to its standard input.
+/// The command is recorded in the transcript of the current run, but the input is not,
+/// so it can be used to pass passwords and other secrets.
+///
+/// # Arguments
+///
+/// * `cmd_name` - The name of the command to run
+/// * `args` - A slice of string references representing the arguments to pass to the command
+/// * `input` - The optional standard input of the command
+///
+/// # Returns
+///
+/// A `Result` containing;
+///    - the standard output of the command if successful.
+///    - the standard error of the command if it failed.
+///    - the exit status.
+///
+pub async fn run_command_with_input(
+    cmd_name: &str,
+    args: &[&str],
+    input: Option<&[u8]>,
+) -> Result<(String, String, std::process::ExitStatus)> {
+    run_command_blocking(cmd_name, args, input, false).await
+}
+
+/// Run Command Redacted.
+///
+/// Runs a command whose output is secret, for example a decryption command.
+/// The command is recorded in the transcript of the current run, but its
+/// input and output are not.
 ///
 /// # Arguments
 ///
 /// * `cmd_name` - The name of the command to run
 /// * `args` - A slice of string references representing the arguments to pass to the command
+/// * `input` - The optional standard input of the command
 ///
 /// # Returns
 ///
@@ -727,9 +806,46 @@ pub async fn download_tool(
 ///    - the standard error of the command if it failed.
 ///    - the exit status.
 ///
-pub fn run_command(
+pub async fn run_command_redacted(
+    cmd_name: &str,
+    args: &[&str],
+    input: Option<&[u8]>,
+) -> Result<(String, String, std::process::ExitStatus)> {
+    run_command_blocking(cmd_name, args, input, true).await
+}
+
+/// Run Command Blocking.
+///
+/// Runs run_command_inner on the blocking thread pool.
+///
+async fn run_command_blocking(
     cmd_name: &str,
     args: &[&str],
+    input: Option<&[u8]>,
+    redact_output: bool,
+) -> Result<(String, String, std::process::ExitStatus)> {
+    let cmd_name = cmd_name.to_string();
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    let input = input.map(|input| input.to_vec());
+
+    let err_msg = format!("Failed to run command '{}'", cmd_name);
+    tokio::task::spawn_blocking(move || {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        run_command_inner(&cmd_name, &args, input.as_deref(), redact_output)
+    })
+    .await
+    .context(err_msg)?
+}
+
+/// Run Command Inner.
+///
+/// A helper function for run_command, run_command_with_input and run_command_redacted.
+///
+fn run_command_inner(
+    cmd_name: &str,
+    args: &[&str],
+    input: Option<&[u8]>,
+    redact_output: bool,
 ) -> Result<(String, String, std::process::ExitStatus)> {
     // Check if the command exists in the path.
     if check_command_in_path(cmd_name).is_err() {
@@ -743,19 +859,262 @@ pub fn run_command(
         cmd.args(args);
     }
 
+    // Start a new process group, so a Ctrl-C in the terminal doesn't stop the
+    // command before loopy decides to.
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+
+    let started = chrono::Local::now();
     let err_msg = format!("Failed to execute command '{}'", cmd_name);
-    let output = cmd.output().context(err_msg)?;
+    let output = match input {
+        None => cmd.output().context(err_msg)?,
+        Some(input) => {
+            let mut child = cmd
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .context(err_msg)?;
+
+            // Write the input from another thread so a full output pipe can't block it.
+            let mut stdin = child
+                .stdin
+                .take()
+                .ok_or_else(|| anyhow!("Failed to open stdin of '{}'", cmd_name))?;
+            let input = input.to_vec();
+            let writer = std::thread::spawn(move || stdin.write_all(&input));
+
+            let output = child.wait_with_output()?;
+            if let Ok(Err(e)) = writer.join() {
+                debug!("Failed to write the input of '{}': {}", cmd_name, e);
+            }
+            output
+        }
+    };
+
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+
+    // Record the command in the transcript of the current run.
+    let argv: Vec<&str> = std::iter::once(cmd_name)
+        .chain(args.iter().copied())
+        .collect();
+    if redact_output {
+        let redacted = "[redacted]";
+        runs::record(
+            &argv,
+            &[],
+            started,
+            output.status.code(),
+            redacted,
+            redacted,
+        );
+    } else {
+        runs::record(&argv, &[], started, output.status.code(), &stdout, &stderr);
+    }
 
-    Ok((
-        String::from_utf8_lossy(&output.stdout).to_string(),
-        String::from_utf8_lossy(&output.stderr).to_string(),
-        output.status,
-    ))
+    Ok((stdout, stderr, output.status))
+}
+
+/// Run Command Streaming.
+///
+/// Runs a command with the provided arguments, streaming its output.
+/// Each line of stdout and stderr is written to the logger as it arrives and the
+/// latest line is shown next to a live progress spinner. The command runs in its
+/// own process group, which is killed if the user presses Ctrl-C.
+/// The command is recorded in the transcript of the current run.
+///
+/// # Arguments
+///
+/// * `cmd_name` - The name of the command to run
+/// * `args` - A slice of string references representing the arguments to pass to the command
+/// * `label` - A label for the progress spinner, for example the component name
+///
+/// # Returns
+///
+/// A `Result` containing;
+///    - the captured standard output of the command.
+///    - the captured standard error of the command.
+///    - the exit status.
+///
+pub async fn run_command_streaming(
+    cmd_name: &str,
+    args: &[&str],
+    label: &str,
+) -> Result<(String, String, ExitStatus)> {
+    run_command_streaming_with_input(cmd_name, args, label, None).await
+}
+
+/// Run Command Streaming With Input.
+///
+/// Runs a command like run_command_streaming, writing the input to its standard input.
+/// The input is not recorded in the transcript, so it can be used to pass secrets.
+///
+/// # Arguments
+///
+/// * `cmd_name` - The name of the command to run
+/// * `args` - A slice of string references representing the arguments to pass to the command
+/// * `label` - A label for the progress spinner, for example the component name
+/// * `input` - The optional standard input of the command
+///
+/// # Returns
+///
+/// A `Result` containing;
+///    - the captured standard output of the command.
+///    - the captured standard error of the command.
+///    - the exit status.
+///
+pub async fn run_command_streaming_with_input(
+    cmd_name: &str,
+    args: &[&str],
+    label: &str,
+    input: Option<Vec<u8>>,
+) -> Result<(String, String, ExitStatus)> {
+    // Check if the command exists in the path.
+    if check_command_in_path(cmd_name).is_err() {
+        return Err(anyhow::anyhow!("Command '{}' not found in PATH", cmd_name));
+    }
+
+    let mut std_cmd = Command::new(cmd_name);
+    std_cmd
+        .args(args)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    // Start a new process group so the whole process tree can be killed.
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut std_cmd, 0);
+
+    let mut cmd = tokio::process::Command::from(std_cmd);
+    cmd.kill_on_drop(true);
+
+    let started = chrono::Local::now();
+    let err_msg = format!("Failed to execute command '{}'", cmd_name);
+    let mut child = cmd.spawn().context(err_msg)?;
+
+    // Write the input in the background and close stdin once it's written.
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        tokio::spawn(async move {
+            if let Err(e) = tokio::io::AsyncWriteExt::write_all(&mut stdin, &input).await {
+                debug!("Failed to write the command input: {}", e);
+            }
+        });
+    }
+
+    let mut stdout_lines = AsyncBufReader::new(
+        child
+            .stdout
+            .take()
+            .ok_or_else(|| anyhow!("Failed to capture stdout of '{}'", cmd_name))?,
+    )
+    .lines();
+    let mut stderr_lines = AsyncBufReader::new(
+        child
+            .stderr
+            .take()
+            .ok_or_else(|| anyhow!("Failed to capture stderr of '{}'", cmd_name))?,
+    )
+    .lines();
+
+    let spinner = PROGRESS
+        .get_or_init(MultiProgress::new)
+        .add(ProgressBar::new_spinner());
+    spinner.set_style(
+        ProgressStyle::default_spinner()
+            .template("{spinner:.green} {prefix} [{elapsed}] {wide_msg}")
+            .unwrap(),
+    );
+    spinner.set_prefix(label.to_string());
+    spinner.enable_steady_tick(Duration::from_millis(120));
+
+    let argv: Vec<&str> = std::iter::once(cmd_name)
+        .chain(args.iter().copied())
+        .collect();
+    let mut stdout = String::new();
+    let mut stderr = String::new();
+    let mut stdout_open = true;
+    let mut stderr_open = true;
+
+    // A second Ctrl-C aborts the command, the first lets it finish.
+    let aborted = signal::aborted();
+    tokio::pin!(aborted);
+
+    while stdout_open || stderr_open {
+        tokio::select! {
+            line = stdout_lines.next_line(), if stdout_open => match line? {
+                Some(line) => {
+                    info!("[{}] {}", label, line);
+                    spinner.set_message(line.clone());
+                    stdout.push_str(&line);
+                    stdout.push('\n');
+                }
+                None => stdout_open = false,
+            },
+            line = stderr_lines.next_line(), if stderr_open => match line? {
+                Some(line) => {
+                    info!("[{}] {}", label, line);
+                    spinner.set_message(line.clone());
+                    stderr.push_str(&line);
+                    stderr.push('\n');
+                }
+                None => stderr_open = false,
+            },
+            _ = &mut aborted => {
+                spinner.abandon_with_message("cancelled");
+                error!("Cancelled command '{}', stopping its process tree", cmd_name);
+                kill_process_tree(&mut child).await;
+                runs::record(&argv, &[], started, None, &stdout, &stderr);
+                return Err(anyhow!("Command '{}' was cancelled", cmd_name));
+            }
+        }
+    }
+
+    let status = child.wait().await?;
+    spinner.finish_and_clear();
+
+    // Record the command in the transcript of the current run.
+    runs::record(&argv, &[], started, status.code(), &stdout, &stderr);
+
+    Ok((stdout, stderr, status))
+}
+
+/// Kill Process Tree.
+///
+/// Stops a child process and every process in its process group.
+/// The group is asked to terminate first and killed if it doesn't exit in time.
+///
+async fn kill_process_tree(child: &mut tokio::process::Child) {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        // The child leads its own process group, so signal the whole group.
+        // SAFETY: killpg only sends a signal and does not touch any memory.
+        unsafe {
+            libc::killpg(pid as libc::pid_t, libc::SIGTERM);
+        }
+        if tokio::time::timeout(Duration::from_secs(10), child.wait())
+            .await
+            .is_ok()
+        {
+            return;
+        }
+        unsafe {
+            libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+        }
+    }
+
+    if let Err(e) = child.kill().await {
+        debug!("Failed to kill child process: {}", e);
+    }
 }
 
 /// Run tests.
 ///
-/// Runs the provided tests in parallel.
+/// Runs the provided tests in order, recording the outcome of each in the state store.
 ///
 /// # Arguments
 ///
@@ -768,49 +1127,109 @@ pub fn run_command(
 ///  - An `anyhow::Error` if any of the tests failed.
 ///
 pub async fn run_tests(tests: &[Test]) -> Result<()> {
-    for test in tests {
-        println!("Running test: '{}'", test.command);
-        let (stdout, stderr, status) = run_command(&test.command, &[])?;
-
-        // Does the stdout match the expected result?
-        if let Some(expected_stdout) = &test.stdout {
-            if stdout.trim() != *expected_stdout {
-                return Err(anyhow::anyhow!(
-                    "Test failed. Expected stdout: '{}', Actual stdout: '{}'",
-                    expected_stdout,
-                    stdout.trim()
-                ));
-            }
+    for (index, test) in tests.iter().enumerate() {
+        // Labels can repeat, so the position identifies the test when resuming.
+        let id = format!("{} #{}", test.label(), index + 1);
+        if state::is_completed("test", &id) {
+            continue;
+        }
+        let result = logger::component("test", test.label(), "run", run_test(test)).await;
+        store::record_test(&state::phase(), &id, result.is_ok());
+        let saved = store::save().await;
+        result?;
+        saved?;
+        println!("Test passed");
+        state::complete("test", &id)?;
+        signal::check()?;
+    }
+
+    Ok(())
+}
+
+/// Run test.
+///
+/// Runs a single test of any kind.
+///
+/// # Arguments
+///
+/// * `test` - The test to run
+///
+async fn run_test(test: &Test) -> Result<()> {
+    if let Some(http) = &test.http {
+        println!("Running HTTP test: '{}'", test.label());
+        http_test(http).await?;
+    } else if let Some(pod) = &test.pod {
+        println!("Running Pod test: '{}'", test.label());
+        let exit_code = kubectl_run_test_pod(pod).await?;
+
+        // Does the container exit code match the expected status code?
+        let expected_status_code = test.status.unwrap_or(0);
+        if exit_code != expected_status_code {
+            return Err(anyhow::anyhow!(
+                "Test failed. Expected status code: '{}', Actual status code: '{}'",
+                expected_status_code,
+                exit_code
+            ));
         }
+    } else if let Some(helm) = &test.helm {
+        println!("Running Helm test: '{}'", test.label());
+        helm_test(helm).await?;
+    } else if let Some(command) = &test.command {
+        println!("Running test: '{}'", test.label());
+        run_command_test(command, test).await?;
+    }
 
-        // Does the stderr match the expected result?
-        if let Some(expected_stderr) = &test.stderr {
-            if stderr.trim() != *expected_stderr {
-                return Err(anyhow::anyhow!(
-                    "Test failed. Expected stderr: '{}', Actual stderr: '{}'",
-                    expected_stderr,
-                    stderr.trim()
-                ));
-            }
+    Ok(())
+}
+
+/// Run command test.
+///
+/// Runs a command test and compares the output to the expected result.
+///
+/// # Arguments
+///
+/// * `command` - The command to run
+/// * `test` - The test definition with the expected results
+///
+async fn run_command_test(command: &str, test: &Test) -> Result<()> {
+    let (stdout, stderr, status) = run_command(command, &[]).await?;
+
+    // Does the stdout match the expected result?
+    if let Some(expected_stdout) = &test.stdout {
+        if stdout.trim() != *expected_stdout {
+            return Err(anyhow::anyhow!(
+                "Test failed. Expected stdout: '{}', Actual stdout: '{}'",
+                expected_stdout,
+                stdout.trim()
+            ));
         }
+    }
 
-        // Does the status code matches the expected status code?
-        if let Some(expected_status_code) = test.status {
-            if status.code().unwrap() != expected_status_code {
-                return Err(anyhow::anyhow!(
-                    "Test failed. Expected status code: '{}', Actual status code: '{}'",
-                    expected_status_code,
-                    status.code().unwrap()
-                ));
-            }
-        } else if !status.success() {
+    // Does the stderr match the expected result?
+    if let Some(expected_stderr) = &test.stderr {
+        if stderr.trim() != *expected_stderr {
             return Err(anyhow::anyhow!(
-                "Test failed. Expected status code: '0', Actual status code: '{}'",
-                status.code().unwrap()
+                "Test failed. Expected stderr: '{}', Actual stderr: '{}'",
+                expected_stderr,
+                stderr.trim()
             ));
         }
+    }
 
-        println!("Test passed");
+    // Does the status code matches the expected status code?
+    if let Some(expected_status_code) = test.status {
+        if status.code().unwrap() != expected_status_code {
+            return Err(anyhow::anyhow!(
+                "Test failed. Expected status code: '{}', Actual status code: '{}'",
+                expected_status_code,
+                status.code().unwrap()
+            ));
+        }
+    } else if !status.success() {
+        return Err(anyhow::anyhow!(
+            "Test failed. Expected status code: '0', Actual status code: '{}'",
+            status.code().unwrap()
+        ));
     }
 
     Ok(())
@@ -822,6 +1241,7 @@ pub async fn run_tests(tests: &[Test]) -> Result<()> {
 ///
 /// * `action` - The action to perform (install or uninstall)
 /// * `config_loaded` - The loaded configuration
+/// * `resume` - Skip the steps that completed in the last interrupted run
 ///
 /// # Returns
 ///
@@ -837,48 +1257,75 @@ pub async fn run_tests(tests: &[Test]) -> Result<()> {
 /// use crate::helm::process_install_uninstall;
 ///
 /// let config_loaded: Config = Config::new()?;
-/// process_install_uninstall("install", &config_loaded)?;
+/// process_install_uninstall("install", &config_loaded, false)?;
 /// ```
 ///
-pub async fn process_install_uninstall<'a>(action: &str, config: &'a Config) -> Result<()> {
-    /*
-    ------------------------------------
-    Dependencies
-    ------------------------------------
-    */
+pub async fn process_install_uninstall(action: &str, config: &Config, resume: bool) -> Result<()> {
+    state::start(action, resume)?;
 
-    // Process Helm repositories
-    helm_process_repos(&config.dependencies.helm.repositories, action).await?;
+    // What loopy installed in the cluster before. Without the store, every
+    // component is treated as owned, as in a cluster installed by an older version.
+    let installed = match store::current() {
+        Some(installed) => installed,
+        None => {
+            println!("The loopy state store could not be read, continuing without it");
+            store::Store::default()
+        }
+    };
+    if installed.is_recorded() {
+        println!(
+            "The cluster was last changed by {} {} on {}",
+            PACKAGE_NAME,
+            installed.version,
+            installed
+                .updated
+                .map(|updated| updated.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_default()
+        );
+        if action == "install" && store::config_changed() == Some(false) {
+            println!("The configuration is unchanged since the last install");
+        }
+    }
+
+    state::begin_phase("orphans");
+    logger::phase("orphans", process_orphans(action, config)).await?;
 
-    // Update Helm repositories only during installation
+    let (dependencies, application) = graph::phase_graphs(config)?;
+    let parallelism = config.parallelism.unwrap_or(graph::DEFAULT_PARALLELISM);
+
+    // Log in to the OCI registries that charts are pulled from.
     if action == "install" {
-        println!("Updating Helm repositories...");
-        helm_repo("update", None, None).await?;
-        println!("Successfully updated Helm repositories");
+        if let Some(registries) = &config.registries {
+            helm_registry_login(registries).await?;
+        }
     }
 
     // Define apply_fn for processing manifests
-    let apply_fn: ApplyFn<'a> = match action {
-        "install" => |manifest| -> Box<
-            dyn Future<Output = Result<(), anyhow::Error>> + Send + Unpin + 'a,
-        > { Box::new(Box::pin(kubectl_apply_manifest(manifest))) },
-        _ => {
-            |manifest| -> Box<dyn Future<Output = Result<(), anyhow::Error>> + Send + Unpin + 'a> {
-                Box::new(Box::pin(kubectl_delete_manifest(manifest)))
-            }
-        }
+    let apply_fn: ApplyFn = match action {
+        "install" => |manifest| Box::new(Box::pin(kubectl_apply_manifest(manifest))),
+        _ => |manifest| Box::new(Box::pin(kubectl_delete_manifest(manifest))),
     };
 
-    // Process Manifests
-    kubectl_process_manifests(&config.dependencies.manifests, action, apply_fn).await?;
-
-    // Process Helm charts
-    helm_process_charts(&config.dependencies.helm.charts, action).await?;
+    /*
+    ------------------------------------
+    Dependencies
+    ------------------------------------
+    */
 
-    // Run tests
-    if !config.dependencies.tests.is_empty() {
-        run_tests(&config.dependencies.tests).await?;
-    }
+    state::begin_phase("dependencies");
+    logger::phase(
+        "dependencies",
+        process_phase(
+            action,
+            &config.dependencies.helm.repositories,
+            &config.dependencies.secrets,
+            &dependencies,
+            &config.dependencies.tests,
+            apply_fn,
+            parallelism,
+        ),
+    )
+    .await?;
 
     // Pause to allow the user to review the dependencies and make any manual changes.
     match pause(
@@ -891,8 +1338,10 @@ pub async fn process_install_uninstall<'a>(action: &str, config: &'a Config) ->
             debug!("User pressed ENTER, continuing with installation.");
         }
         Err(_) => {
+            // Return, so the run is recorded and the progress is kept for --resume.
             println!("Exiting at user request.");
-            std::process::exit(0);
+            println!("Run again with --resume to continue where it stopped.");
+            return Ok(());
         }
     }
 
@@ -902,26 +1351,20 @@ pub async fn process_install_uninstall<'a>(action: &str, config: &'a Config) ->
     ------------------------------------
     */
 
-    // Process Helm repositories
-    helm_process_repos(&config.application.helm.repositories, action).await?;
-
-    // Update Helm repositories only during installation.
-    if action == "install" {
-        println!("Updating Helm repositories...");
-        helm_repo("update", None, None).await?;
-        println!("Successfully updated Helm repositories");
-    }
-
-    // Process Manifests
-    kubectl_process_manifests(&config.application.manifests, action, apply_fn).await?;
-
-    // Process Helm charts
-    helm_process_charts(&config.application.helm.charts, action).await?;
-
-    // Run tests
-    if !config.application.tests.is_empty() {
-        run_tests(&config.application.tests).await?;
-    }
+    state::begin_phase("application");
+    logger::phase(
+        "application",
+        process_phase(
+            action,
+            &config.application.helm.repositories,
+            &config.application.secrets,
+            &application,
+            &config.application.tests,
+            apply_fn,
+            parallelism,
+        ),
+    )
+    .await?;
 
     /*
     ------------------------------------
@@ -929,7 +1372,8 @@ pub async fn process_install_uninstall<'a>(action: &str, config: &'a Config) ->
     ------------------------------------
     */
 
-    // Create or delete the namespace
+    // Create or delete the namespace, which holds the state store.
+    state::begin_phase("marker");
     let namespace_action = match action {
         "install" => "create",
         "uninstall" => "delete",
@@ -938,11 +1382,177 @@ pub async fn process_install_uninstall<'a>(action: &str, config: &'a Config) ->
     println!("{} namespace: {}", namespace_action, PACKAGE_NAME);
 
     let err_msg = format!("Failed to {} namespace {}", namespace_action, PACKAGE_NAME);
-    kubectl_namespace(namespace_action, PACKAGE_NAME)
+    logger::phase("marker", kubectl_namespace(namespace_action, PACKAGE_NAME))
         .await
         .context(err_msg)?;
 
+    // Record the completed install. An uninstall deleted the store with the namespace.
+    if action == "install" {
+        store::complete();
+        store::save()
+            .await
+            .context("Failed to write the loopy state store")?;
+    }
+
+    // The run completed, so there is nothing left to resume.
+    state::finish();
+    changes::summary();
+
     println!("The {} action completed successfully.", action);
 
     Ok(())
 }
+
+/// Process orphans.
+///
+/// Processes the components that loopy installed but are no longer in the
+/// configuration. An install only reports them, while an uninstall removes
+/// them, the last installed first.
+///
+/// # Arguments
+///
+/// * `action` - The action to perform, either "install" or "uninstall"
+/// * `config` - The loaded configuration
+///
+async fn process_orphans(action: &str, config: &Config) -> Result<()> {
+    let Some(installed) = store::current() else {
+        return Ok(());
+    };
+
+    for component in installed.orphans(config).iter().rev() {
+        if action == "install" {
+            warn!(
+                "The {} {} is no longer in the configuration",
+                component.kind, component.name
+            );
+            println!(
+                "The {} {} was installed by {} but is no longer in the configuration, uninstall to remove it",
+                component.kind, component.name, PACKAGE_NAME
+            );
+            continue;
+        }
+        if state::is_completed(&component.kind, &component.name) {
+            continue;
+        }
+
+        println!(
+            "uninstall {} {}, it was removed from the configuration",
+            component.kind, component.name
+        );
+        let err_msg = format!("Failed to uninstall {} {}", component.kind, component.name);
+        match component.kind.as_str() {
+            "chart" => {
+                let release = component.release.as_deref().unwrap_or(&component.name);
+                let namespace = component.namespace.as_deref().unwrap_or(release);
+                helm_uninstall_chart(release, namespace)
+                    .await
+                    .context(err_msg)?;
+            }
+            _ => kubectl_delete_objects(&component.objects)
+                .await
+                .context(err_msg)?,
+        }
+        store::remove(&component.kind, &component.name);
+        store::save().await?;
+        state::complete(&component.kind, &component.name)?;
+    }
+
+    Ok(())
+}
+
+/// Process phase.
+///
+/// Processes the Helm repositories, manifests, Helm charts and tests of a single phase.
+/// Manifests and charts are processed concurrently in the order of their dependencies.
+///
+/// # Arguments
+///
+/// * `action` - The action to perform (install or uninstall)
+/// * `repositories` - The Helm repositories of the phase
+/// * `secrets` - The Kubernetes Secrets of the phase
+/// * `graph` - The dependency graph of the manifests and charts of the phase
+/// * `tests` - The tests of the phase
+/// * `apply_fn` - The function that applies or deletes a manifest
+/// * `parallelism` - The maximum number of manifests and charts processed at the same time
+///
+async fn process_phase<'a>(
+    action: &str,
+    repositories: &'a [Repository],
+    secrets: &'a [Secret],
+    graph: &Graph<'a>,
+    tests: &'a [Test],
+    apply_fn: ApplyFn,
+    parallelism: usize,
+) -> Result<()> {
+    // Process Helm repositories
+    helm_process_repos(repositories, action).await?;
+
+    // Update Helm repositories only during installation, using the local index when offline
+    if action == "install" && !cache::is_offline() {
+        println!("Updating Helm repositories...");
+        helm_repo("update", None, None).await?;
+        println!("Successfully updated Helm repositories");
+    }
+
+    // Create Secrets before the manifests and charts that use them
+    if action == "install" {
+        process_phase_secrets(secrets, action).await?;
+    }
+
+    // Process Manifests and Helm charts, removing dependents first during uninstallation
+    if graph.is_empty() {
+        println!(
+            "No Kubernetes manifests or Helm charts to {} were found in the configuration file. Skipping...",
+            action
+        );
+    } else {
+        graph
+            .process(action == "uninstall", parallelism, |component| {
+                let action = action.to_string();
+                async move {
+                    match component {
+                        OwnedComponent::Manifest(manifest) => {
+                            kubectl_process_manifest(&manifest, &action, apply_fn).await
+                        }
+                        OwnedComponent::Chart(chart) => helm_process_chart(&chart, &action).await,
+                    }
+                }
+            })
+            .await?;
+    }
+
+    // Delete Secrets once nothing uses them
+    if action == "uninstall" {
+        process_phase_secrets(secrets, action).await?;
+    }
+
+    // Run tests
+    if !tests.is_empty() {
+        run_tests(tests).await?;
+    }
+
+    Ok(())
+}
+
+/// Process the Secrets of a phase.
+///
+/// A helper function for process_phase that records each Secret as a step.
+///
+async fn process_phase_secrets(secrets: &[Secret], action: &str) -> Result<()> {
+    for secret in secrets {
+        if state::is_completed("secret", &secret.name) {
+            continue;
+        }
+        logger::component(
+            "secret",
+            &secret.name,
+            action,
+            process_secret(secret, action),
+        )
+        .await?;
+        state::complete("secret", &secret.name)?;
+        signal::check()?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1202 @@
+//! Validate
+//!
+//! This module validates a loaded configuration and reports every problem at
+//! once, each with the file, line and column where the offending field is defined.
+//!
+//! Fields are identified by their path in the effective configuration, for
+//! example `dependencies.helm.charts[cert-manager].repo`. List entries are
+//! identified by their name, or by their index when they have no name.
+//!
+
+use crate::config::{Chart, Config, Manifests, PortForward, ResolvedConfig, Secret, Test};
+
+use anyhow::anyhow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+/// The line and column of every key and list entry of a file, by path.
+type Locations = HashMap<Vec<String>, (usize, usize)>;
+
+/// A problem found in the configuration.
+#[derive(Debug)]
+pub struct Problem {
+    /// Where the problem is, as "file:line:column", if it could be found.
+    pub location: Option<String>,
+    /// The path of the field in the configuration.
+    pub path: String,
+    /// What is wrong.
+    pub message: String,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}: {}", location, self.message),
+            None if self.path.is_empty() => write!(f, "{}", self.message),
+            None => write!(f, "{}: {}", self.path, self.message),
+        }
+    }
+}
+
+/// Finds where the fields of a configuration are defined.
+///
+/// Only block style YAML is located precisely. Fields inside flow style
+/// mappings and lists are located at their parent.
+#[derive(Debug, Default)]
+pub struct Locator {
+    /// The locations of every key and list entry of each file, in merge order.
+    files: Vec<(String, Locations)>,
+    /// The profile that was applied.
+    profile: Option<String>,
+}
+
+impl Locator {
+    /// Build a locator for the files of a resolved configuration.
+    pub fn new(resolved: &ResolvedConfig) -> Self {
+        let files = resolved
+            .sources
+            .iter()
+            .map(|(path, content)| (path.display().to_string(), scan_yaml(content)))
+            .collect();
+
+        Self {
+            files,
+            profile: resolved.profile.clone(),
+        }
+    }
+
+    /// Build a locator for plain YAML files, as their path and content in merge order.
+    pub fn from_files(files: &[(String, String)]) -> Self {
+        let files = files
+            .iter()
+            .map(|(path, content)| (path.clone(), scan_yaml(content)))
+            .collect();
+
+        Self {
+            files,
+            profile: None,
+        }
+    }
+
+    /// Find where a field is defined.
+    ///
+    /// Fields set by the profile are found first, then fields of the files
+    /// that were merged last. A field that isn't found is located at its
+    /// closest parent.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the field, for example `log.format`
+    ///
+    /// # Returns
+    ///
+    /// The location as "file:line:column".
+    ///
+    pub fn locate(&self, path: &str) -> Option<String> {
+        let mut segments = split_path(path);
+
+        while !segments.is_empty() {
+            let mut candidates = Vec::new();
+            if let Some(profile) = &self.profile {
+                let mut candidate = vec!["profiles".to_string(), profile.clone()];
+                candidate.extend(segments.iter().cloned());
+                candidates.push(candidate);
+            }
+            candidates.push(segments.clone());
+
+            for candidate in &candidates {
+                for (file, locations) in self.files.iter().rev() {
+                    if let Some((line, column)) = locations.get(candidate) {
+                        return Some(format!("{}:{}:{}", file, line, column));
+                    }
+                }
+            }
+
+            segments.pop();
+        }
+
+        None
+    }
+
+    /// Describe where a field is defined, or fall back to the configuration file.
+    pub fn describe(&self, path: &str, config_file: &str) -> String {
+        self.locate(path).unwrap_or_else(|| config_file.to_string())
+    }
+}
+
+/// Split a path into its segments, for example `a.b[c]` into `a`, `b` and `[c]`.
+fn split_path(path: &str) -> Vec<String> {
+    let mut segments = Vec::new();
+    let mut current = String::new();
+    let mut in_brackets = false;
+
+    for c in path.chars() {
+        match c {
+            '[' if !in_brackets => {
+                if !current.is_empty() {
+                    segments.push(std::mem::take(&mut current));
+                }
+                in_brackets = true;
+                current.push(c);
+            }
+            ']' if in_brackets => {
+                current.push(c);
+                segments.push(std::mem::take(&mut current));
+                in_brackets = false;
+            }
+            '.' if !in_brackets => {
+                if !current.is_empty() {
+                    segments.push(std::mem::take(&mut current));
+                }
+            }
+            _ => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        segments.push(current);
+    }
+
+    segments
+}
+
+/// A key or list entry that encloses the current line of a YAML file.
+struct Frame {
+    /// The column of the key or "-".
+    indent: usize,
+    /// The path segment.
+    segment: String,
+    /// Whether this is a list entry.
+    item: bool,
+}
+
+/// Find the line and column of every key and list entry in a YAML file.
+///
+/// List entries are recorded both by index and by their `name`, if they have one.
+fn scan_yaml(content: &str) -> Locations {
+    let mut stack: Vec<Frame> = Vec::new();
+    let mut counters: HashMap<Vec<String>, usize> = HashMap::new();
+    let mut names: HashMap<Vec<String>, String> = HashMap::new();
+    let mut entries: Vec<(Vec<String>, usize, usize)> = Vec::new();
+    let mut block_scalar: Option<usize> = None;
+
+    for (number, line) in content.lines().enumerate() {
+        let indent = line.len() - line.trim_start_matches(' ').len();
+        let trimmed = line.trim();
+
+        // Skip the lines of multi-line strings.
+        if let Some(block_indent) = block_scalar {
+            if trimmed.is_empty() || indent > block_indent {
+                continue;
+            }
+            block_scalar = None;
+        }
+
+        if trimmed.is_empty()
+            || trimmed.starts_with('#')
+            || trimmed.starts_with("---")
+            || trimmed.starts_with("...")
+        {
+            continue;
+        }
+
+        let path_of = |stack: &[Frame]| -> Vec<String> {
+            stack.iter().map(|frame| frame.segment.clone()).collect()
+        };
+
+        let mut column = indent;
+        let mut rest = trimmed;
+
+        // A list entry, possibly followed by the first key of a mapping.
+        while rest == "-" || rest.starts_with("- ") {
+            while stack.last().is_some_and(|frame| {
+                frame.indent > column || (frame.indent == column && frame.item)
+            }) {
+                stack.pop();
+            }
+
+            let mut path = path_of(&stack);
+            let counter = counters.entry(path.clone()).or_insert(0);
+            let segment = format!("[{}]", counter);
+            *counter += 1;
+            path.push(segment.clone());
+            entries.push((path, number + 1, column + 1));
+            stack.push(Frame {
+                indent: column,
+                segment,
+                item: true,
+            });
+
+            let after = rest[1..].trim_start();
+            column += rest.len() - after.len();
+            rest = after;
+        }
+
+        let Some((key, value)) = split_key(rest) else {
+            continue;
+        };
+
+        while stack.last().is_some_and(|frame| frame.indent >= column) {
+            stack.pop();
+        }
+
+        if key == "name" && stack.last().is_some_and(|frame| frame.item) && !value.is_empty() {
+            names.insert(path_of(&stack), unquote(value));
+        }
+
+        let mut path = path_of(&stack);
+        path.push(key.clone());
+        entries.push((path, number + 1, column + 1));
+        stack.push(Frame {
+            indent: column,
+            segment: key,
+            item: false,
+        });
+
+        if value.starts_with('|') || value.starts_with('>') {
+            block_scalar = Some(column);
+        }
+    }
+
+    let mut locations = HashMap::new();
+    for (path, line, column) in entries {
+        // Replace the index of each named list entry with its name.
+        let mut named = Vec::with_capacity(path.len());
+        for (index, segment) in path.iter().enumerate() {
+            match names.get(&path[..=index]) {
+                Some(name) => named.push(format!("[{}]", name)),
+                None => named.push(segment.clone()),
+            }
+        }
+
+        locations.entry(named).or_insert((line, column));
+        locations.entry(path).or_insert((line, column));
+    }
+
+    locations
+}
+
+/// Split a line into a mapping key and the rest of the line.
+fn split_key(line: &str) -> Option<(String, &str)> {
+    if line.starts_with('{') || line.starts_with('[') {
+        return None;
+    }
+
+    let end = if line.starts_with('"') || line.starts_with('\'') {
+        let quote = &line[..1];
+        line[1..].find(quote).map(|index| index + 2)?
+    } else {
+        line.find(": ")
+            .or_else(|| line.ends_with(':').then(|| line.len() - 1))?
+    };
+
+    let key = line[..end].trim_end();
+    let rest = line[end..].strip_prefix(':')?;
+    let value = match rest.find(" #") {
+        Some(comment) => &rest[..comment],
+        None => rest,
+    };
+
+    Some((unquote(key), value.trim()))
+}
+
+/// Remove the quotes around a scalar.
+fn unquote(value: &str) -> String {
+    value.trim_matches(|c| c == '"' || c == '\'').to_string()
+}
+
+/// Format the problems of a configuration as a single error.
+pub fn report(config_file: &str, problems: &[Problem]) -> anyhow::Error {
+    let lines: Vec<String> = problems
+        .iter()
+        .map(|problem| format!("  {}", problem))
+        .collect();
+
+    anyhow!(
+        "The configuration {} has {} problem{}:\n{}",
+        config_file,
+        problems.len(),
+        if problems.len() == 1 { "" } else { "s" },
+        lines.join("\n")
+    )
+}
+
+/// Collects the problems found in a configuration.
+struct Validator<'a> {
+    locator: &'a Locator,
+    problems: Vec<Problem>,
+}
+
+impl Validator<'_> {
+    /// Record a problem with a field.
+    fn error(&mut self, path: String, message: String) {
+        self.problems.push(Problem {
+            location: self.locator.locate(&path),
+            path,
+            message,
+        });
+    }
+
+    /// Record a problem with a field if a condition holds.
+    fn check(&mut self, failed: bool, path: &str, message: impl FnOnce() -> String) {
+        if failed {
+            self.error(path.to_string(), message());
+        }
+    }
+
+    /// Record a problem for each name that is used more than once.
+    fn unique<'b>(&mut self, kind: &str, entries: impl IntoIterator<Item = (String, &'b str)>) {
+        let mut seen = BTreeSet::new();
+        for (path, name) in entries {
+            if !name.trim().is_empty() && !seen.insert(name) {
+                self.error(
+                    path,
+                    format!("The {} name '{}' is used more than once.", kind, name),
+                );
+            }
+        }
+    }
+}
+
+/// Validate config.
+///
+/// Performs validation on the config file, collecting every problem instead
+/// of stopping at the first one.
+///
+/// # Arguments
+///
+/// * `config` - The loaded configuration
+/// * `locator` - Finds where the fields of the configuration are defined
+///
+/// # Returns
+///
+/// The problems that were found, empty if the configuration is valid.
+///
+pub fn validate_config(config: &Config, locator: &Locator) -> Vec<Problem> {
+    let mut v = Validator {
+        locator,
+        problems: Vec::new(),
+    };
+
+    // Validate log
+    if let Some(log) = &config.log {
+        // Ensure that the format field is a known log format.
+        if let Some(format) = &log.format {
+            if let Err(e) = format.parse::<crate::logger::LogFormat>() {
+                v.error("log.format".to_string(), e.to_string());
+            }
+        }
+
+        // Ensure that each module log level is a known log level.
+        for (module, level) in log.modules.iter().flatten() {
+            v.check(
+                level.parse::<log::LevelFilter>().is_err(),
+                &format!("log.modules.{}", module),
+                || {
+                    format!(
+                        "The log level '{}' of module {} is not a valid log level.",
+                        level, module
+                    )
+                },
+            );
+        }
+    }
+
+    // Validate dependencies.tools
+    for (index, tool) in config.dependencies.tools.iter().enumerate() {
+        let path = format!("dependencies.tools[{}]", index);
+
+        // Ensure that the name field of each tool is not empty.
+        v.check(tool.name.trim().is_empty(), &path, || {
+            "The 'name' field of all defined tools cannot be empty.".to_string()
+        });
+
+        // Ensure that the bin field of each tool is not empty.
+        v.check(tool.bin.trim().is_empty(), &format!("{}.bin", path), || {
+            format!("The 'bin' field of {} cannot be empty.", tool.name)
+        });
+    }
+    v.unique(
+        "tool",
+        config.dependencies.tools.iter().map(|tool| {
+            (
+                format!("dependencies.tools[{}]", tool.name),
+                tool.name.as_str(),
+            )
+        }),
+    );
+
+    // Repositories of the dependencies phase are also available to the application phase.
+    let mut repositories: Vec<&str> = Vec::new();
+
+    let phases = [
+        (
+            "dependencies",
+            &config.dependencies.helm,
+            &config.dependencies.manifests,
+            &config.dependencies.tests,
+            &config.dependencies.secrets,
+        ),
+        (
+            "application",
+            &config.application.helm,
+            &config.application.manifests,
+            &config.application.tests,
+            &config.application.secrets,
+        ),
+    ];
+    for (phase, helm, manifests, tests, secrets) in phases {
+        // Validate tests
+        for (index, test) in tests.iter().enumerate() {
+            validate_test(&mut v, &format!("{}.tests[{}]", phase, index), test);
+        }
+        v.unique(
+            "test",
+            tests.iter().enumerate().filter_map(|(index, test)| {
+                let name = test.name.as_deref()?;
+                Some((format!("{}.tests[{}]", phase, index), name))
+            }),
+        );
+
+        // Validate manifests
+        for manifest in manifests.iter() {
+            let path = format!("{}.manifests[{}]", phase, manifest.name);
+            validate_manifest(&mut v, &path, manifest);
+        }
+        v.unique(
+            "manifest",
+            manifests.iter().map(|manifest| {
+                (
+                    format!("{}.manifests[{}]", phase, manifest.name),
+                    manifest.name.as_str(),
+                )
+            }),
+        );
+
+        // Validate helm.repositories
+        for repo in &helm.repositories {
+            let path = format!("{}.helm.repositories[{}]", phase, repo.name);
+
+            // Ensure that the name field of each repository is not empty.
+            v.check(repo.name.trim().is_empty(), &path, || {
+                "The 'name' field of all defined repositories cannot be empty.".to_string()
+            });
+
+            // Ensure that the url field of each repository is not empty.
+            v.check(repo.url.trim().is_empty(), &format!("{}.url", path), || {
+                format!("The 'url' field of {} cannot be empty.", repo.name)
+            });
+
+            repositories.push(&repo.name);
+        }
+
+        // Validate helm.charts
+        for chart in &helm.charts {
+            let path = format!("{}.helm.charts[{}]", phase, chart.name);
+            validate_chart(&mut v, &path, chart);
+
+            // Ensure that the repository of each chart is defined.
+            if let Some(repo) = &chart.repo {
+                let is_oci = repo.starts_with("oci://");
+                v.check(
+                    !is_oci && !repo.trim().is_empty() && !repositories.contains(&repo.as_str()),
+                    &format!("{}.repo", path),
+                    || {
+                        format!(
+                            "Chart {} uses the repository '{}', which is not defined in the helm.repositories of this phase or an earlier one.",
+                            chart.name, repo
+                        )
+                    },
+                );
+            }
+
+            for port_forward in chart.port_forwards.iter().flatten() {
+                let path = format!("{}.port_forwards[{}]", path, port_forward.name);
+                validate_port_forward(&mut v, &path, port_forward);
+            }
+        }
+        v.unique(
+            "chart",
+            helm.charts.iter().map(|chart| {
+                (
+                    format!("{}.helm.charts[{}]", phase, chart.name),
+                    chart.name.as_str(),
+                )
+            }),
+        );
+
+        // Validate secrets
+        for secret in secrets.iter() {
+            let path = format!("{}.secrets[{}]", phase, secret.name);
+            validate_secret(&mut v, &path, secret);
+        }
+    }
+
+    // Ensure that repository names are unique, as Helm shares them between phases.
+    v.unique(
+        "repository",
+        phases.iter().flat_map(|(phase, helm, _, _, _)| {
+            helm.repositories.iter().map(move |repo| {
+                (
+                    format!("{}.helm.repositories[{}]", phase, repo.name),
+                    repo.name.as_str(),
+                )
+            })
+        }),
+    );
+
+    // Validate port_forwards
+    for port_forward in config.port_forwards.iter().flatten() {
+        let path = format!("port_forwards[{}]", port_forward.name);
+        validate_port_forward(&mut v, &path, port_forward);
+    }
+
+    // Ensure that port-forward names are unique, as they are started by name.
+    let chart_port_forwards = phases.iter().flat_map(|(phase, helm, _, _, _)| {
+        helm.charts.iter().flat_map(move |chart| {
+            chart
+                .port_forwards
+                .iter()
+                .flatten()
+                .map(move |port_forward| {
+                    (
+                        format!(
+                            "{}.helm.charts[{}].port_forwards[{}]",
+                            phase, chart.name, port_forward.name
+                        ),
+                        port_forward.name.as_str(),
+                    )
+                })
+        })
+    });
+    let port_forwards = config
+        .port_forwards
+        .iter()
+        .flatten()
+        .map(|port_forward| {
+            (
+                format!("port_forwards[{}]", port_forward.name),
+                port_forward.name.as_str(),
+            )
+        })
+        .chain(chart_port_forwards);
+    v.unique("port-forward", port_forwards);
+
+    // Validate parallelism
+    v.check(config.parallelism == Some(0), "parallelism", || {
+        "The 'parallelism' field must be at least 1.".to_string()
+    });
+
+    // Ensure that no two charts install the same release into the same namespace.
+    let mut releases = BTreeSet::new();
+    for (phase, helm, _, _, _) in phases {
+        for chart in &helm.charts {
+            v.check(
+                !releases.insert((chart.namespace(), chart.release())),
+                &format!("{}.helm.charts[{}]", phase, chart.name),
+                || {
+                    format!(
+                        "Release {} in namespace {} is defined more than once, set a distinct 'release' for chart {}.",
+                        chart.release(),
+                        chart.namespace(),
+                        chart.name
+                    )
+                },
+            );
+        }
+    }
+
+    // Ensure that no two secrets have the same name in the same namespace.
+    let mut secret_names = BTreeMap::new();
+    for (phase, _, _, _, secrets) in phases {
+        for secret in secrets.iter() {
+            let namespace = secret.namespace.as_deref().unwrap_or("default");
+            let previous = secret_names.insert((namespace, secret.name.as_str()), phase);
+            v.check(
+                previous.is_some(),
+                &format!("{}.secrets[{}]", phase, secret.name),
+                || {
+                    format!(
+                        "Secret {} in namespace {} is defined more than once.",
+                        secret.name, namespace
+                    )
+                },
+            );
+        }
+    }
+
+    // Validate vars
+    for (name, value) in config.vars.iter().flatten() {
+        // Ensure that each variable is a string, number or boolean.
+        v.check(
+            !matches!(
+                value,
+                serde_yaml::Value::String(_)
+                    | serde_yaml::Value::Number(_)
+                    | serde_yaml::Value::Bool(_)
+            ),
+            &format!("vars.{}", name),
+            || {
+                format!(
+                    "The variable '{}' must be a string, number or boolean.",
+                    name
+                )
+            },
+        );
+    }
+
+    // Validate registries
+    for (index, registry) in config.registries.iter().flatten().enumerate() {
+        let path = format!("registries[{}]", index);
+
+        // Ensure that the host field of each registry is not empty.
+        v.check(
+            registry.host.trim().is_empty(),
+            &format!("{}.host", path),
+            || "The 'host' field of all defined registries cannot be empty.".to_string(),
+        );
+
+        // Ensure that a password is provided for the user name.
+        v.check(
+            registry.username.is_some() && registry.password_env.is_none(),
+            &format!("{}.username", path),
+            || {
+                format!(
+                    "Registry {} must define 'password_env' when 'username' is set.",
+                    registry.host
+                )
+            },
+        );
+    }
+
+    // Ensure that every depends_on refers to a known component and there are no cycles.
+    if let Err(e) = crate::graph::phase_graphs(config) {
+        v.error(String::new(), e.to_string());
+    }
+
+    v.problems
+}
+
+/// Validate test.
+///
+/// Performs validation on a single test definition.
+///
+fn validate_test(v: &mut Validator, path: &str, test: &Test) {
+    let kinds = [
+        test.command.is_some(),
+        test.http.is_some(),
+        test.pod.is_some(),
+        test.helm.is_some(),
+    ];
+    v.check(
+        kinds.iter().filter(|kind| **kind).count() != 1,
+        path,
+        || {
+            "Each defined test must have exactly one of 'command', 'http', 'pod' or 'helm'."
+                .to_string()
+        },
+    );
+
+    if let Some(command) = &test.command {
+        // Ensure that the command field of each test is not empty.
+        v.check(
+            command.trim().is_empty(),
+            &format!("{}.command", path),
+            || "The 'command' field of all defined tests cannot be empty.".to_string(),
+        );
+    }
+
+    if let Some(http) = &test.http {
+        // Ensure that the url field of each HTTP test is not empty.
+        v.check(
+            http.url.trim().is_empty(),
+            &format!("{}.http.url", path),
+            || "The 'url' field of all defined HTTP tests cannot be empty.".to_string(),
+        );
+
+        // Ensure that the body field is a valid regular expression.
+        if let Some(body) = &http.body {
+            v.check(
+                regex::Regex::new(body).is_err(),
+                &format!("{}.http.body", path),
+                || {
+                    format!(
+                        "The 'body' field of HTTP test {} is not a valid regular expression.",
+                        http.url
+                    )
+                },
+            );
+        }
+    }
+
+    if let Some(pod) = &test.pod {
+        // Ensure that exactly one of the manifest or image fields is defined.
+        v.check(
+            pod.manifest.is_some() == pod.image.is_some(),
+            &format!("{}.pod", path),
+            || "Each defined Pod test must have exactly one of 'manifest' or 'image'.".to_string(),
+        );
+
+        // Ensure that the manifest file exists.
+        if let Some(manifest) = &pod.manifest {
+            let manifest_path = Path::new("config/manifests").join(manifest);
+            v.check(
+                !manifest_path.is_file(),
+                &format!("{}.pod.manifest", path),
+                || {
+                    format!(
+                        "The Pod test manifest {} does not exist.",
+                        manifest_path.display()
+                    )
+                },
+            );
+        }
+    }
+
+    if let Some(helm) = &test.helm {
+        // Ensure that the release field of each Helm test is not empty.
+        v.check(
+            helm.release.trim().is_empty(),
+            &format!("{}.helm.release", path),
+            || "The 'release' field of all defined Helm tests cannot be empty.".to_string(),
+        );
+    }
+}
+
+/// Validate manifest.
+///
+/// Performs validation on the sources of a single manifest definition.
+///
+fn validate_manifest(v: &mut Validator, path: &str, manifest: &Manifests) {
+    // Ensure that the name field of each manifest is not empty.
+    v.check(manifest.name.trim().is_empty(), path, || {
+        "The 'name' field of all defined manifests cannot be empty.".to_string()
+    });
+
+    // Ensure that at least one source of each manifest is defined.
+    v.check(
+        manifest.urls().is_empty()
+            && manifest.git.is_none()
+            && manifest.dir.is_none()
+            && manifest.kustomize.is_none(),
+        path,
+        || {
+            format!(
+                "The 'url', 'urls', 'git', 'dir' and 'kustomize' fields of {} cannot all be empty, at least one must be defined.",
+                manifest.name
+            )
+        },
+    );
+
+    // Ensure that no URL is empty.
+    v.check(
+        manifest.urls().iter().any(|url| url.trim().is_empty()),
+        path,
+        || format!("The URLs of manifest {} cannot be empty.", manifest.name),
+    );
+
+    // Ensure that the git repository URL is not empty.
+    if let Some(git) = &manifest.git {
+        v.check(
+            git.url.trim().is_empty(),
+            &format!("{}.git.url", path),
+            || {
+                format!(
+                    "The 'git.url' field of manifest {} cannot be empty.",
+                    manifest.name
+                )
+            },
+        );
+    }
+
+    // Ensure that the manifest directory exists.
+    if let Some(dir) = &manifest.dir {
+        let dir_path = Path::new("config/manifests").join(dir);
+        v.check(!dir_path.is_dir(), &format!("{}.dir", path), || {
+            format!(
+                "The manifest directory {} of {} does not exist.",
+                dir_path.display(),
+                manifest.name
+            )
+        });
+    }
+
+    // Ensure that a local kustomization exists.
+    if let Some(kustomize) = &manifest.kustomize {
+        let is_remote = kustomize.contains("://") || kustomize.starts_with("github.com/");
+        let dir_path = Path::new("config/manifests").join(kustomize);
+        v.check(
+            !is_remote && !dir_path.is_dir(),
+            &format!("{}.kustomize", path),
+            || {
+                format!(
+                    "The kustomization {} of {} does not exist.",
+                    dir_path.display(),
+                    manifest.name
+                )
+            },
+        );
+    }
+}
+
+/// Validate chart.
+///
+/// Performs validation on the optional fields of a single chart definition.
+///
+fn validate_chart(v: &mut Validator, path: &str, chart: &Chart) {
+    // Ensure that the name field of each chart is not empty.
+    v.check(chart.name.trim().is_empty(), path, || {
+        "The 'name' field of all defined charts cannot be empty.".to_string()
+    });
+
+    // Ensure that exactly one chart source is defined.
+    let sources = [
+        chart.repo.is_some(),
+        chart.path.is_some(),
+        chart.git.is_some(),
+    ];
+    v.check(
+        sources.iter().filter(|&&source| source).count() != 1,
+        path,
+        || {
+            format!(
+                "Chart {} must define exactly one of 'repo', 'path' or 'git'.",
+                chart.name
+            )
+        },
+    );
+
+    // Ensure that the git url is not empty.
+    if let Some(git) = &chart.git {
+        v.check(
+            git.url.trim().is_empty(),
+            &format!("{}.git.url", path),
+            || {
+                format!(
+                    "The git 'url' field of chart {} cannot be empty.",
+                    chart.name
+                )
+            },
+        );
+    }
+
+    // A version can only be selected from a repository.
+    v.check(
+        chart.version.is_some() && chart.repo.is_none(),
+        &format!("{}.version", path),
+        || {
+            format!(
+                "The 'version' field of chart {} can only be used with 'repo'.",
+                chart.name
+            )
+        },
+    );
+
+    let optional_fields = [
+        ("repo", &chart.repo),
+        ("path", &chart.path),
+        ("chart", &chart.chart),
+        ("release", &chart.release),
+        ("version", &chart.version),
+        ("timeout", &chart.timeout),
+    ];
+    for (field, value) in optional_fields {
+        // Ensure that optional fields are not empty when they are set.
+        v.check(
+            value.as_ref().is_some_and(|value| value.trim().is_empty()),
+            &format!("{}.{}", path, field),
+            || {
+                format!(
+                    "The '{}' field of chart {} cannot be empty.",
+                    field, chart.name
+                )
+            },
+        );
+    }
+
+    // Ensure that the values files are not empty and exist.
+    for file in chart.values_files() {
+        let values_path = Path::new("config/helm").join(&chart.name).join(file);
+        v.check(file.trim().is_empty(), &format!("{}.values", path), || {
+            format!(
+                "The 'values' field of chart {} cannot contain an empty file name.",
+                chart.name
+            )
+        });
+        v.check(
+            !file.trim().is_empty() && !values_path.is_file(),
+            &format!("{}.values", path),
+            || {
+                format!(
+                    "The values file {} of chart {} does not exist.",
+                    values_path.display(),
+                    chart.name
+                )
+            },
+        );
+    }
+
+    // Ensure that the keys of the set and set_string fields are not empty.
+    let mut keys = chart
+        .set
+        .iter()
+        .chain(chart.set_string.iter())
+        .flat_map(|set| set.keys());
+    v.check(keys.any(|key| key.trim().is_empty()), path, || {
+        format!(
+            "The 'set' and 'set_string' fields of chart {} cannot contain an empty key.",
+            chart.name
+        )
+    });
+}
+
+/// Validate port-forward.
+///
+/// Performs validation on a single port-forward definition.
+///
+fn validate_port_forward(v: &mut Validator, path: &str, port_forward: &PortForward) {
+    // Ensure that the name field of each port-forward is not empty.
+    v.check(port_forward.name.trim().is_empty(), path, || {
+        "The 'name' field of all defined port-forwards cannot be empty.".to_string()
+    });
+
+    // Ensure that exactly one of the service or pod fields is defined.
+    v.check(
+        port_forward.service.is_some() == port_forward.pod.is_some(),
+        path,
+        || {
+            format!(
+                "Port-forward {} must define exactly one of 'service' or 'pod'.",
+                port_forward.name
+            )
+        },
+    );
+}
+
+/// Validate secret.
+///
+/// Performs validation on a single secret definition.
+///
+fn validate_secret(v: &mut Validator, path: &str, secret: &Secret) {
+    // Ensure that the name field of each secret is not empty.
+    v.check(secret.name.trim().is_empty(), path, || {
+        "The 'name' field of all defined secrets cannot be empty.".to_string()
+    });
+
+    for (key, source) in &secret.data {
+        let key_path = format!("{}.data.{}", path, key);
+
+        // Ensure that each key has exactly one source.
+        v.check(
+            source.env.is_some() == source.file.is_some(),
+            &key_path,
+            || {
+                format!(
+                    "Key {} of Secret {} must define exactly one of 'env' or 'file'.",
+                    key, secret.name
+                )
+            },
+        );
+
+        // Ensure that a file source exists.
+        if let Some(file) = &source.file {
+            v.check(
+                !Path::new(file).is_file(),
+                &format!("{}.file", key_path),
+                || {
+                    format!(
+                        "The file {} for key {} of Secret {} does not exist.",
+                        file, key, secret.name
+                    )
+                },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locate(content: &str, path: &str) -> Option<String> {
+        Locator::from_files(&[("loopy.yaml".to_string(), content.to_string())]).locate(path)
+    }
+
+    #[test]
+    fn scan_block_mappings() {
+        let content = "log:\n  level: debug\n  # comment\n  file: loopy.log # trailing\n";
+        assert_eq!(locate(content, "log").as_deref(), Some("loopy.yaml:1:1"));
+        assert_eq!(
+            locate(content, "log.level").as_deref(),
+            Some("loopy.yaml:2:3")
+        );
+        assert_eq!(
+            locate(content, "log.file").as_deref(),
+            Some("loopy.yaml:4:3")
+        );
+        assert_eq!(
+            locate(content, "log.missing").as_deref(),
+            Some("loopy.yaml:1:1")
+        );
+        assert_eq!(locate(content, "other"), None);
+    }
+
+    #[test]
+    fn scan_quoted_keys() {
+        let content = "\"log\":\n  'level': debug\n";
+        assert_eq!(
+            locate(content, "log.level").as_deref(),
+            Some("loopy.yaml:2:3")
+        );
+    }
+
+    #[test]
+    fn scan_flow_collections_at_their_parent() {
+        let content = "log: {level: debug}\nvalues: [a.yaml, b.yaml]\nset:\n  key: {a: 1}\n";
+        assert_eq!(
+            locate(content, "log.level").as_deref(),
+            Some("loopy.yaml:1:1")
+        );
+        assert_eq!(
+            locate(content, "values[1]").as_deref(),
+            Some("loopy.yaml:2:1")
+        );
+        assert_eq!(
+            locate(content, "set.key.a").as_deref(),
+            Some("loopy.yaml:4:3")
+        );
+    }
+
+    #[test]
+    fn scan_skips_block_scalars() {
+        let content = "\
+script: |
+  name: not-a-key
+  - not-an-entry
+
+  still: text
+after: 1
+folded: >-
+  text: here
+last: 2
+";
+        assert_eq!(locate(content, "after").as_deref(), Some("loopy.yaml:6:1"));
+        assert_eq!(locate(content, "last").as_deref(), Some("loopy.yaml:9:1"));
+        assert_eq!(
+            locate(content, "script.name").as_deref(),
+            Some("loopy.yaml:1:1")
+        );
+        assert_eq!(locate(content, "still"), None);
+        assert_eq!(locate(content, "text"), None);
+    }
+
+    #[test]
+    fn scan_named_list_entries() {
+        let content = "\
+dependencies:
+  helm:
+    charts:
+      - name: cert-manager
+        repo: jetstack
+      - repo: bitnami
+        name: \"postgresql\"
+        values:
+          - values.yaml
+          - values-kind.yaml
+";
+        let chart = "dependencies.helm.charts[cert-manager]";
+        assert_eq!(locate(content, chart).as_deref(), Some("loopy.yaml:4:7"));
+        assert_eq!(
+            locate(content, &format!("{}.repo", chart)).as_deref(),
+            Some("loopy.yaml:5:9")
+        );
+        let chart = "dependencies.helm.charts[postgresql]";
+        assert_eq!(
+            locate(content, &format!("{}.repo", chart)).as_deref(),
+            Some("loopy.yaml:6:9")
+        );
+        assert_eq!(
+            locate(content, &format!("{}.values[1]", chart)).as_deref(),
+            Some("loopy.yaml:10:11")
+        );
+        // Entries can also be found by index.
+        assert_eq!(
+            locate(content, "dependencies.helm.charts[1].name").as_deref(),
+            Some("loopy.yaml:7:9")
+        );
+    }
+
+    #[test]
+    fn scan_lists_at_the_indent_of_their_key() {
+        let content = "\
+charts:
+- name: a
+  repo: r
+- name: b
+tools:
+- name: helm
+";
+        assert_eq!(
+            locate(content, "charts[a].repo").as_deref(),
+            Some("loopy.yaml:3:3")
+        );
+        assert_eq!(
+            locate(content, "charts[b]").as_deref(),
+            Some("loopy.yaml:4:1")
+        );
+        assert_eq!(
+            locate(content, "tools[helm]").as_deref(),
+            Some("loopy.yaml:6:1")
+        );
+    }
+
+    #[test]
+    fn scan_nested_lists() {
+        let content = "matrix:\n  - - a\n    - b\n  - - c\n";
+        assert_eq!(
+            locate(content, "matrix[0][1]").as_deref(),
+            Some("loopy.yaml:3:5")
+        );
+        assert_eq!(
+            locate(content, "matrix[1][0]").as_deref(),
+            Some("loopy.yaml:4:5")
+        );
+    }
+
+    #[test]
+    fn locate_prefers_the_profile_and_later_files() {
+        let base = "log:\n  level: info\n  file: a.log\n".to_string();
+        let main = "\
+log:
+  level: warn
+profiles:
+  ci:
+    log:
+      level: debug
+"
+        .to_string();
+        let mut locator = Locator::from_files(&[
+            ("base.yaml".to_string(), base),
+            ("loopy.yaml".to_string(), main),
+        ]);
+        assert_eq!(
+            locator.locate("log.level").as_deref(),
+            Some("loopy.yaml:2:3")
+        );
+        assert_eq!(locator.locate("log.file").as_deref(), Some("base.yaml:3:3"));
+
+        locator.profile = Some("ci".to_string());
+        assert_eq!(
+            locator.locate("log.level").as_deref(),
+            Some("loopy.yaml:6:7")
+        );
+        assert_eq!(locator.locate("log.file").as_deref(), Some("base.yaml:3:3"));
+    }
+
+    #[test]
+    fn split_paths() {
+        assert_eq!(
+            split_path("a.b[c.d].e[0]"),
+            vec!["a", "b", "[c.d]", "e", "[0]"]
+        );
+    }
+}
@@ -0,0 +1,690 @@
+//! Values
+//!
+//! This module keeps the stored default values of each chart, in
+//! `config/helm/<chart>/values.yaml`, in line with the chart version that is
+//! pinned in the configuration.
+//!
+//! The stored defaults are compared with `helm show values` of the pinned
+//! version, reporting the keys that were added, removed or renamed upstream.
+//! The override files of the chart are checked for keys that no longer exist
+//! in the upstream defaults, as Helm silently ignores them. When the chart is
+//! installed with the stored defaults, because it has no `values` entry or lists
+//! `values.yaml`, they are checked as an override file and never replaced.
+//!
+//! Before a chart is installed, its values are checked against the
+//! `values.schema.json` of the chart, or against its default values when it
+//! has no schema.
+//!
+
+use crate::cache;
+use crate::config::{Chart, Config};
+use crate::helm::{helm_chart_files, helm_merge_values, helm_repo, helm_upstream_values};
+use crate::secrets;
+use crate::template;
+use crate::validate::Locator;
+
+use anyhow::{anyhow, Context, Result};
+use jsonschema::JSONSchema;
+use log::{debug, info, warn};
+use serde_yaml::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// The file the default values of a chart are stored in.
+const DEFAULTS_FILE: &str = "values.yaml";
+
+/// The differences between the stored and upstream default values of a chart.
+#[derive(Debug, Default)]
+struct ValuesReport {
+    /// Keys that only exist upstream.
+    added: Vec<String>,
+    /// Keys that only exist in the stored defaults.
+    removed: Vec<String>,
+    /// Keys that moved, as the stored key and the upstream key.
+    renamed: Vec<(String, String)>,
+    /// Keys in override files that don't exist upstream, as the file and the key.
+    stale: Vec<(String, String)>,
+}
+
+impl ValuesReport {
+    /// Whether the stored defaults differ from upstream.
+    fn changed(&self) -> bool {
+        !self.added.is_empty() || !self.removed.is_empty() || !self.renamed.is_empty()
+    }
+}
+
+/// Values diff.
+///
+/// Shows how the stored default values of the given charts differ from the
+/// upstream defaults of their pinned versions, and the keys in their override
+/// files that no longer exist upstream.
+///
+/// # Arguments
+///
+/// * `config` - The loaded configuration
+/// * `names` - The names of the charts to compare, or all charts if empty
+///
+/// # Returns
+///
+/// True if the stored defaults are up to date and no override keys are stale.
+///
+pub async fn values_diff(config: &Config, names: &[String]) -> Result<bool> {
+    let mut up_to_date = true;
+
+    for chart in values_charts(config, names).await? {
+        let (report, _) = values_compare(chart).await?;
+        values_print(chart, &report);
+        up_to_date &= !report.changed() && report.stale.is_empty();
+    }
+
+    Ok(up_to_date)
+}
+
+/// Values refresh.
+///
+/// Replaces the stored default values of the given charts with the upstream
+/// defaults of their pinned versions, after showing what changed.
+///
+/// # Arguments
+///
+/// * `config` - The loaded configuration
+/// * `names` - The names of the charts to refresh, or all charts if empty
+///
+pub async fn values_refresh(config: &Config, names: &[String]) -> Result<()> {
+    for chart in values_charts(config, names).await? {
+        let (report, upstream) = values_compare(chart).await?;
+        values_print(chart, &report);
+
+        let defaults_file = Path::new("config/helm")
+            .join(&chart.name)
+            .join(DEFAULTS_FILE);
+        if defaults_installed(chart) && defaults_file.exists() {
+            println!(
+                "  Not updating {}, it holds the values the chart is installed with. \
+                 Move the overrides to a file listed under values: to refresh it.",
+                defaults_file.display()
+            );
+            continue;
+        }
+        if report.changed() || !defaults_file.exists() {
+            let err_msg = format!("Failed to create directory for {}", defaults_file.display());
+            fs::create_dir_all(defaults_file.parent().unwrap_or(Path::new(".")))
+                .context(err_msg)?;
+            let err_msg = format!("Failed to write {}", defaults_file.display());
+            fs::write(&defaults_file, upstream).context(err_msg)?;
+            println!("  Updated {}", defaults_file.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Values validate.
+///
+/// Checks the values of a chart before it is installed. When the chart has a
+/// `values.schema.json`, the merged values must match it and every mismatch
+/// is reported with the file, line and YAML path that set it. Otherwise, keys
+/// in the override files that don't exist in the default values of the chart
+/// are reported as warnings.
+///
+/// # Arguments
+///
+/// * `chart` - The chart to check the values of
+/// * `chart_ref` - The reference helm uses to find the chart
+/// * `layers` - The path and content of each values file, in the order they are layered
+///
+pub async fn values_validate(
+    chart: &Chart,
+    chart_ref: &str,
+    layers: &[(String, String)],
+) -> Result<()> {
+    let (defaults, schema) = match helm_chart_files(chart, chart_ref).await {
+        Ok(files) => files,
+        Err(e) => {
+            warn!("Skipping the values check of chart {}: {}", chart.name, e);
+            return Ok(());
+        }
+    };
+    let defaults = parse_values(&defaults, &chart.name)?;
+    let locator = Locator::from_files(layers);
+    let stored_defaults = Path::new("config/helm")
+        .join(&chart.name)
+        .join(DEFAULTS_FILE);
+
+    let mut merged = defaults.clone();
+    let mut overrides = Vec::new();
+    for (file, content) in layers {
+        let layer = parse_values(content, file)?;
+        if Path::new(file) != stored_defaults {
+            overrides.push((file, layer.clone()));
+        }
+        helm_merge_values(&mut merged, layer);
+    }
+
+    let Some(schema) = schema else {
+        for (file, layer) in &overrides {
+            let mut missing = Vec::new();
+            missing_keys(layer, &defaults, "", &mut missing);
+            for key in missing {
+                let location = locator.locate(&key).unwrap_or_else(|| file.to_string());
+                warn!(
+                    "{}: {} does not exist in the default values of chart {}",
+                    location, key, chart.name
+                );
+            }
+        }
+        return Ok(());
+    };
+
+    let err_msg = format!("Failed to parse the values schema of chart {}", chart.name);
+    let schema: serde_json::Value = serde_json::from_str(&schema).context(err_msg)?;
+    let compiled = match JSONSchema::compile(&schema) {
+        Ok(compiled) => compiled,
+        Err(e) => {
+            warn!(
+                "Skipping the invalid values schema of chart {}: {}",
+                chart.name, e
+            );
+            return Ok(());
+        }
+    };
+    let err_msg = format!("Failed to convert the values of chart {}", chart.name);
+    let problems = schema_problems(&compiled, &merged, &locator).context(err_msg)?;
+    if !problems.is_empty() {
+        return Err(anyhow!(
+            "The values of chart {} don't match its schema:\n{}",
+            chart.name,
+            problems.join("\n")
+        ));
+    }
+
+    info!("The values of chart {} match its schema", chart.name);
+
+    Ok(())
+}
+
+/// Check merged values against a values schema.
+///
+/// # Arguments
+///
+/// * `compiled` - The compiled values schema
+/// * `merged` - The merged values
+/// * `locator` - Finds the file, line and column that set each value
+///
+/// # Returns
+///
+/// Each mismatch, with its location and YAML path when they are known.
+///
+fn schema_problems(
+    compiled: &JSONSchema,
+    merged: &Value,
+    locator: &Locator,
+) -> Result<Vec<String>> {
+    let instance = serde_json::to_value(merged)?;
+
+    let problems = match compiled.validate(&instance) {
+        Ok(()) => Vec::new(),
+        Err(errors) => errors
+            .map(|error| {
+                let path = yaml_path(&instance, error.instance_path.clone().into_vec());
+                match (locator.locate(&path), path.is_empty()) {
+                    (Some(location), _) => format!("  {}: {}: {}", location, path, error),
+                    (None, true) => format!("  {}", error),
+                    (None, false) => format!("  {}: {}", path, error),
+                }
+            })
+            .collect(),
+    };
+
+    Ok(problems)
+}
+
+/// Find the charts to compare and update the Helm repositories they come from.
+async fn values_charts<'a>(config: &'a Config, names: &[String]) -> Result<Vec<&'a Chart>> {
+    let charts: Vec<&Chart> = config
+        .dependencies
+        .helm
+        .charts
+        .iter()
+        .chain(config.application.helm.charts.iter())
+        .collect();
+
+    for name in names {
+        if !charts.iter().any(|chart| &chart.name == name) {
+            let available: Vec<&str> = charts.iter().map(|chart| chart.name.as_str()).collect();
+            return Err(anyhow!(
+                "Chart {} is not defined in the configuration, available charts: {}",
+                name,
+                available.join(", ")
+            ));
+        }
+    }
+    let charts: Vec<&Chart> = charts
+        .into_iter()
+        .filter(|chart| names.is_empty() || names.contains(&chart.name))
+        .collect();
+
+    // Make sure the repository index knows about the pinned versions.
+    let from_repository = charts.iter().any(|chart| {
+        chart
+            .repo
+            .as_deref()
+            .is_some_and(|repo| !repo.starts_with("oci://"))
+    });
+    if from_repository && !cache::is_offline() {
+        let repos = config
+            .dependencies
+            .helm
+            .repositories
+            .iter()
+            .chain(config.application.helm.repositories.iter());
+        for repo in repos {
+            helm_repo("install", Some(&repo.name), Some(&repo.url)).await?;
+        }
+        helm_repo("update", None, None).await?;
+    }
+
+    Ok(charts)
+}
+
+/// Compare the stored default values of a chart with upstream.
+///
+/// # Returns
+///
+/// The report and the upstream default values.
+///
+async fn values_compare(chart: &Chart) -> Result<(ValuesReport, String)> {
+    let chart_dir = Path::new("config/helm").join(&chart.name);
+
+    let upstream = helm_upstream_values(chart)
+        .await
+        .with_context(|| format!("Failed to get the default values of chart {}", chart.name))?;
+    let upstream_values = parse_values(&upstream, &chart.name)?;
+
+    let defaults_file = chart_dir.join(DEFAULTS_FILE);
+    let stored_values = if defaults_file.exists() {
+        let err_msg = format!("Failed to read {}", defaults_file.display());
+        let content = fs::read_to_string(&defaults_file).context(err_msg)?;
+        parse_values(&content, &defaults_file.display().to_string())?
+    } else {
+        debug!("{} does not exist yet", defaults_file.display());
+        Value::Null
+    };
+
+    let mut report = values_report(&stored_values, &upstream_values);
+
+    // Check the override files against the upstream defaults, including the
+    // stored defaults when the chart is installed with them.
+    let mut files = chart.values_files();
+    if files.is_empty() {
+        files.push(DEFAULTS_FILE);
+    }
+    for file in files {
+        if file == DEFAULTS_FILE && !defaults_installed(chart) {
+            continue;
+        }
+        let path = chart_dir.join(file);
+        if !path.exists() {
+            warn!("Values file {} does not exist, skipping", path.display());
+            continue;
+        }
+        let content = if secrets::is_encrypted(&path) {
+            secrets::decrypt_file(&path).await?
+        } else {
+            let rendered = template::render_file(&path).await?;
+            let err_msg = format!("Failed to read {}", rendered.display());
+            fs::read_to_string(&rendered).context(err_msg)?
+        };
+        let overrides = parse_values(&content, &path.display().to_string())?;
+
+        let mut missing = Vec::new();
+        missing_keys(&overrides, &upstream_values, "", &mut missing);
+        for key in missing {
+            report.stale.push((file.to_string(), key));
+        }
+    }
+
+    Ok((report, upstream))
+}
+
+/// Compare stored default values with upstream.
+///
+/// # Arguments
+///
+/// * `stored_values` - The stored default values
+/// * `upstream_values` - The upstream default values
+///
+/// # Returns
+///
+/// The added, removed and renamed keys, without stale override keys.
+///
+fn values_report(stored_values: &Value, upstream_values: &Value) -> ValuesReport {
+    let mut stored = BTreeMap::new();
+    flatten_values(stored_values, "", &mut stored);
+    let mut current = BTreeMap::new();
+    flatten_values(upstream_values, "", &mut current);
+
+    let mut report = ValuesReport {
+        added: current
+            .keys()
+            .filter(|key| !stored.contains_key(*key))
+            .cloned()
+            .collect(),
+        ..ValuesReport::default()
+    };
+
+    // A removed key is renamed when exactly one added key has the same name under
+    // another parent, or the same value under the same parent.
+    for old in stored.keys().filter(|key| !current.contains_key(*key)) {
+        let (old_parent, old_name) = split_key(old);
+        let moved: Vec<&String> = report
+            .added
+            .iter()
+            .filter(|new| split_key(new).1 == old_name)
+            .collect();
+        let candidates = if moved.len() == 1 {
+            moved
+        } else {
+            report
+                .added
+                .iter()
+                .filter(|new| split_key(new).0 == old_parent && current[*new] == stored[old])
+                .collect()
+        };
+        match candidates.as_slice() {
+            [new] => {
+                let new = new.to_string();
+                report.added.retain(|key| *key != new);
+                report.renamed.push((old.clone(), new));
+            }
+            _ => report.removed.push(old.clone()),
+        }
+    }
+
+    report
+}
+
+/// Whether the stored defaults of a chart are installed as its values.
+///
+/// Without a `values` entry, the chart is installed with the stored defaults, so
+/// they may hold overrides and must not be replaced.
+///
+fn defaults_installed(chart: &Chart) -> bool {
+    let files = chart.values_files();
+    files.is_empty() || files.contains(&DEFAULTS_FILE)
+}
+
+/// Print the report of a chart.
+fn values_print(chart: &Chart, report: &ValuesReport) {
+    match &chart.version {
+        Some(version) => println!("Chart {} ({} {}):", chart.name, chart.chart(), version),
+        None => println!("Chart {} ({}):", chart.name, chart.chart()),
+    }
+
+    if !report.changed() && report.stale.is_empty() {
+        println!("  The stored defaults are up to date.");
+        return;
+    }
+    for key in &report.added {
+        println!("  + {}", key);
+    }
+    for key in &report.removed {
+        println!("  - {}", key);
+    }
+    for (old, new) in &report.renamed {
+        println!("  ~ {} -> {}", old, new);
+    }
+    for (file, key) in &report.stale {
+        println!("  ! {}: {} no longer exists upstream", file, key);
+    }
+}
+
+/// Parse values, treating an empty document as no values.
+fn parse_values(content: &str, source: &str) -> Result<Value> {
+    let err_msg = format!("Failed to parse the values of {}", source);
+    let value: Option<Value> = serde_yaml::from_str(content).context(err_msg)?;
+    Ok(value.unwrap_or(Value::Null))
+}
+
+/// Flatten values to their dotted key paths.
+///
+/// Nested maps are followed, everything else, including lists and empty maps,
+/// is a leaf.
+///
+fn flatten_values(value: &Value, prefix: &str, keys: &mut BTreeMap<String, Value>) {
+    match value {
+        Value::Mapping(map) if !map.is_empty() => {
+            for (key, value) in map {
+                flatten_values(value, &join_key(prefix, key), keys);
+            }
+        }
+        _ if !prefix.is_empty() => {
+            keys.insert(prefix.to_string(), value.clone());
+        }
+        _ => {}
+    }
+}
+
+/// Collect the keys of the overrides that don't exist in the defaults.
+///
+/// Keys below an empty map, null or any other value in the defaults are free
+/// form, for example annotations, and are not reported.
+///
+fn missing_keys(overrides: &Value, defaults: &Value, prefix: &str, missing: &mut Vec<String>) {
+    let (Value::Mapping(overrides), Value::Mapping(defaults)) = (overrides, defaults) else {
+        return;
+    };
+    if defaults.is_empty() {
+        return;
+    }
+    for (key, value) in overrides {
+        let path = join_key(prefix, key);
+        match defaults.get(key) {
+            Some(default) => missing_keys(value, default, &path, missing),
+            None => missing.push(path),
+        }
+    }
+}
+
+/// Join a map key to a dotted key path.
+fn join_key(prefix: &str, key: &Value) -> String {
+    let key = match key {
+        Value::String(key) => key.clone(),
+        other => serde_yaml::to_string(other)
+            .map(|key| key.trim().to_string())
+            .unwrap_or_default(),
+    };
+    if prefix.is_empty() {
+        key
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+/// Convert the segments of a JSON pointer to a YAML path, for example `a.b[0]`.
+fn yaml_path(instance: &serde_json::Value, segments: Vec<String>) -> String {
+    let mut path = String::new();
+    let mut current = Some(instance);
+
+    for segment in segments {
+        match current {
+            Some(serde_json::Value::Array(items)) => {
+                path.push_str(&format!("[{}]", segment));
+                current = segment
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|index| items.get(index));
+            }
+            _ => {
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(&segment);
+                current = current.and_then(|value| value.get(&segment));
+            }
+        }
+    }
+
+    path
+}
+
+/// Split a dotted key path into its parent and name.
+fn split_key(key: &str) -> (&str, &str) {
+    key.rsplit_once('.').unwrap_or(("", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(content: &str) -> Value {
+        parse_values(content, "test").unwrap()
+    }
+
+    fn report(stored: &str, upstream: &str) -> ValuesReport {
+        values_report(&yaml(stored), &yaml(upstream))
+    }
+
+    fn missing(overrides: &str, defaults: &str) -> Vec<String> {
+        let mut missing = Vec::new();
+        missing_keys(&yaml(overrides), &yaml(defaults), "", &mut missing);
+        missing
+    }
+
+    #[test]
+    fn flatten_nested_maps_to_leaves() {
+        let mut keys = BTreeMap::new();
+        flatten_values(
+            &yaml("a: {b: 1, c: [1, 2]}\nd: {}\ne: null\n"),
+            "",
+            &mut keys,
+        );
+
+        let keys: Vec<&str> = keys.keys().map(String::as_str).collect();
+        assert_eq!(keys, vec!["a.b", "a.c", "d", "e"]);
+    }
+
+    #[test]
+    fn split_keys_at_the_last_dot() {
+        assert_eq!(split_key("a.b.c"), ("a.b", "c"));
+        assert_eq!(split_key("a"), ("", "a"));
+    }
+
+    #[test]
+    fn report_added_and_removed_keys() {
+        let report = report("a: 1\nb: {c: 2}\n", "a: 1\nd: 3\n");
+
+        assert_eq!(report.added, vec!["d"]);
+        assert_eq!(report.removed, vec!["b.c"]);
+        assert!(report.renamed.is_empty());
+    }
+
+    #[test]
+    fn report_keys_moved_to_another_parent_as_renamed() {
+        let report = report("image: {tag: 1.0}\n", "controller: {image: {tag: 2.0}}\n");
+
+        assert!(report.added.is_empty());
+        assert!(report.removed.is_empty());
+        assert_eq!(
+            report.renamed,
+            vec![("image.tag".to_string(), "controller.image.tag".to_string())]
+        );
+    }
+
+    #[test]
+    fn report_keys_with_the_same_value_under_the_same_parent_as_renamed() {
+        let report = report(
+            "service: {port: 80}\n",
+            "service: {httpPort: 80, tls: true}\n",
+        );
+
+        assert_eq!(report.added, vec!["service.tls"]);
+        assert!(report.removed.is_empty());
+        assert_eq!(
+            report.renamed,
+            vec![("service.port".to_string(), "service.httpPort".to_string())]
+        );
+    }
+
+    #[test]
+    fn report_ambiguous_renames_as_removed() {
+        let report = report("a: {name: x}\n", "b: {name: x}\nc: {name: x}\n");
+
+        assert_eq!(report.added, vec!["b.name", "c.name"]);
+        assert_eq!(report.removed, vec!["a.name"]);
+        assert!(report.renamed.is_empty());
+    }
+
+    #[test]
+    fn stale_override_keys_are_missing() {
+        assert_eq!(
+            missing("a: {b: 1, old: 2}\ngone: 3\n", "a: {b: 0}\nc: 1\n"),
+            vec!["a.old", "gone"]
+        );
+    }
+
+    #[test]
+    fn free_form_maps_are_not_missing() {
+        let defaults = "podAnnotations: {}\nextraEnv: null\nlabels: ~\n";
+        let overrides = "podAnnotations: {a: b}\nextraEnv: {X: 1}\nlabels: {app: x}\n";
+
+        assert!(missing(overrides, defaults).is_empty());
+    }
+    fn problems(schema: &str, values: &str) -> Vec<String> {
+        let schema: serde_json::Value = serde_json::from_str(schema).unwrap();
+        let compiled = JSONSchema::compile(&schema).unwrap();
+        let layers = [("values-kind.yaml".to_string(), values.to_string())];
+        schema_problems(&compiled, &yaml(values), &Locator::from_files(&layers)).unwrap()
+    }
+
+    fn path(instance: &str, pointer: &[&str]) -> String {
+        let instance: serde_json::Value = serde_json::from_str(instance).unwrap();
+        yaml_path(&instance, pointer.iter().map(|s| s.to_string()).collect())
+    }
+
+    #[test]
+    fn yaml_paths_of_json_pointers() {
+        let instance = r#"{"a": {"b": [{"c": 1}, {"c": 2}]}, "0": {"1": true}}"#;
+
+        assert_eq!(path(instance, &["a", "b"]), "a.b");
+        assert_eq!(path(instance, &["a", "b", "1", "c"]), "a.b[1].c");
+        assert_eq!(path(instance, &["0", "1"]), "0.1");
+        assert_eq!(path(instance, &[]), "");
+    }
+
+    #[test]
+    fn schema_problems_of_nested_maps() {
+        let schema =
+            r#"{"properties": {"service": {"properties": {"port": {"type": "integer"}}}}}"#;
+        let problems = problems(schema, "service:\n  port: http\n");
+
+        assert_eq!(problems.len(), 1);
+        assert!(
+            problems[0].starts_with("  values-kind.yaml:2:3: service.port: "),
+            "{}",
+            problems[0]
+        );
+    }
+
+    #[test]
+    fn schema_problems_of_list_items() {
+        let schema = r#"{"properties": {"hosts": {"items": {"type": "string"}}}}"#;
+        let problems = problems(schema, "hosts:\n  - a.example\n  - 2\n");
+
+        assert_eq!(problems.len(), 1);
+        assert!(
+            problems[0].starts_with("  values-kind.yaml:3:3: hosts[1]: "),
+            "{}",
+            problems[0]
+        );
+    }
+
+    #[test]
+    fn schema_problems_at_the_root() {
+        let schema = r#"{"required": ["replicas"]}"#;
+        let problems = problems(schema, "image: nginx\n");
+
+        assert_eq!(problems, vec!["  \"replicas\" is a required property"]);
+    }
+}